@@ -6,6 +6,7 @@ use crate::cpu::{
 };
 
 use crate::memory::mmu::Mmu;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 use Flag::*;
 use Register::*;
@@ -42,6 +43,7 @@ pub enum Flag {
     NZero,
 }
 
+#[derive(Default)]
 pub struct Cpu {
     pub a: u8,
     pub f: u8,
@@ -55,6 +57,10 @@ pub struct Cpu {
     pub pc: u16,
     pub ime: bool,
     pub halt: bool,
+    /// Set by an illegal opcode: the CPU hangs until the console is turned off
+    pub locked: bool,
+    /// STOP mode: the clocks and the LCD are stopped until a button is pressed
+    pub stopped: bool,
 }
 
 impl Cpu {
@@ -72,6 +78,8 @@ impl Cpu {
             sp: 0,
             ime: false,
             halt: false,
+            locked: false,
+            stopped: false,
         }
     }
 
@@ -162,8 +170,8 @@ impl Cpu {
             Subtract => self.f & 0x40 == 0x40,
             HalfCarry => self.f & 0x20 == 0x20,
             Carry => self.f & 0x10 == 0x10,
-            NCarry => self.get_flag(Carry) == false,
-            NZero => self.get_flag(Zero) == false,
+            NCarry => !self.get_flag(Carry),
+            NZero => !self.get_flag(Zero),
         }
     }
 
@@ -212,7 +220,7 @@ impl Cpu {
             0x00 => 4,
             0x01 => ld_rr_nn(BC, self, memory),
             0x02 => ld_bc_a(self, memory),
-            0x03 => inc_rr(BC, self),
+            0x03 => inc_rr(BC, self, memory),
             0x04 => inc_r(B, self),
             0x05 => dec_r(B, self),
             0x06 => ld_r_n(B, self, memory),
@@ -220,16 +228,16 @@ impl Cpu {
             0x08 => ld_nn_sp(self, memory),
             0x09 => add_hl_rr(BC, self),
             0x0a => ld_a_bc(self, memory),
-            0x0b => dec_rr(BC, self),
+            0x0b => dec_rr(BC, self, memory),
             0x0c => inc_r(C, self),
             0x0d => dec_r(C, self),
             0x0e => ld_r_n(C, self, memory),
             0x0f => rrca(self),
 
-            0x10 => stop(),
+            0x10 => stop(self, memory),
             0x11 => ld_rr_nn(DE, self, memory),
             0x12 => ld_de_a(self, memory),
-            0x13 => inc_rr(DE, self),
+            0x13 => inc_rr(DE, self, memory),
             0x14 => inc_r(D, self),
             0x15 => dec_r(D, self),
             0x16 => ld_r_n(D, self, memory),
@@ -237,7 +245,7 @@ impl Cpu {
             0x18 => jr_n(self, memory),
             0x19 => add_hl_rr(DE, self),
             0x1a => ld_a_de(self, memory),
-            0x1b => dec_rr(DE, self),
+            0x1b => dec_rr(DE, self, memory),
             0x1c => inc_r(E, self),
             0x1d => dec_r(E, self),
             0x1e => ld_r_n(E, self, memory),
@@ -246,7 +254,7 @@ impl Cpu {
             0x20 => jr_cc_n(NZero, self, memory),
             0x21 => ld_rr_nn(HL, self, memory),
             0x22 => ld_hli_a(self, memory),
-            0x23 => inc_rr(HL, self),
+            0x23 => inc_rr(HL, self, memory),
             0x24 => inc_r(H, self),
             0x25 => dec_r(H, self),
             0x26 => ld_r_n(H, self, memory),
@@ -254,7 +262,7 @@ impl Cpu {
             0x28 => jr_cc_n(Zero, self, memory),
             0x29 => add_hl_rr(HL, self),
             0x2a => ld_a_hli(self, memory),
-            0x2b => dec_rr(HL, self),
+            0x2b => dec_rr(HL, self, memory),
             0x2c => inc_r(L, self),
             0x2d => dec_r(L, self),
             0x2e => ld_r_n(L, self, memory),
@@ -263,7 +271,7 @@ impl Cpu {
             0x30 => jr_cc_n(NCarry, self, memory),
             0x31 => ld_rr_nn(SP, self, memory),
             0x32 => ld_hld_a(self, memory),
-            0x33 => inc_rr(SP, self),
+            0x33 => inc_rr(SP, self, memory),
             0x34 => inc_hl(self, memory),
             0x35 => dec_hl(self, memory),
             0x36 => ld_hl_n(self, memory),
@@ -271,7 +279,7 @@ impl Cpu {
             0x38 => jr_cc_n(Carry, self, memory),
             0x39 => add_hl_rr(SP, self),
             0x3a => ld_a_hld(self, memory),
-            0x3b => dec_rr(SP, self),
+            0x3b => dec_rr(SP, self, memory),
             0x3c => inc_r(A, self),
             0x3d => dec_r(A, self),
             0x3e => ld_r_n(A, self, memory),
@@ -433,7 +441,7 @@ impl Cpu {
             0xd0 => ret_cc(NCarry, self, memory),
             0xd1 => pop_rr(DE, self, memory),
             0xd2 => jp_cc_nn(NCarry, self, memory),
-            0xd3 => panic!("Not valid opcode"),
+            0xd3 => lock(self),
             0xd4 => call_cc_nn(NCarry, self, memory),
             0xd5 => push_rr(DE, self, memory),
             0xd6 => sub_n(self, memory),
@@ -441,26 +449,26 @@ impl Cpu {
             0xd8 => ret_cc(Carry, self, memory),
             0xd9 => reti(self, memory),
             0xda => jp_cc_nn(Carry, self, memory),
-            0xdb => panic!("Not valid opcode"),
+            0xdb => lock(self),
             0xdc => call_cc_nn(Carry, self, memory),
-            0xdd => panic!("Not valid opcode"),
+            0xdd => lock(self),
             0xde => sbc_n(self, memory),
             0xdf => rst(0x18, self, memory),
 
             0xe0 => ldh_n_a(self, memory),
             0xe1 => pop_rr(HL, self, memory),
             0xe2 => ld_c_a(self, memory),
-            0xe3 => panic!("Not valid opcode"),
-            0xe4 => panic!("Not valid opcode"),
+            0xe3 => lock(self),
+            0xe4 => lock(self),
             0xe5 => push_rr(HL, self, memory),
             0xe6 => and_n(self, memory),
             0xe7 => rst(0x20, self, memory),
             0xe8 => add_sp_n(self, memory),
             0xe9 => jp_hl(self),
             0xea => ld_nn_a(self, memory),
-            0xeb => panic!("Not valid opcode"),
-            0xec => panic!("Not valid opcode"),
-            0xed => panic!("Not valid opcode"),
+            0xeb => lock(self),
+            0xec => lock(self),
+            0xed => lock(self),
             0xee => xor_n(self, memory),
             0xef => rst(0x28, self, memory),
 
@@ -468,7 +476,7 @@ impl Cpu {
             0xf1 => pop_rr(AF, self, memory),
             0xf2 => ld_a_c(self, memory),
             0xf3 => di(self, memory),
-            0xf4 => panic!("Not valid opcode"),
+            0xf4 => lock(self),
             0xf5 => push_rr(AF, self, memory),
             0xf6 => or_n(self, memory),
             0xf7 => rst(0x30, self, memory),
@@ -476,8 +484,8 @@ impl Cpu {
             0xf9 => ld_sp_hl(self),
             0xfa => ld_a_nn(self, memory),
             0xfb => ei(self, memory),
-            0xfc => panic!("Not valid opcode"),
-            0xfd => panic!("Not valid opcode"),
+            0xfc => lock(self),
+            0xfd => lock(self),
             0xfe => cp_n(self, memory),
             0xff => rst(0x38, self, memory),
         }
@@ -760,3 +768,41 @@ impl Cpu {
         }
     }
 }
+
+impl SaveState for Cpu {
+    fn save(&self, state: &mut StateWriter) {
+        for r in [
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ] {
+            state.u8(r);
+        }
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.bool(self.ime);
+        state.bool(self.halt);
+        state.bool(self.locked);
+        state.bool(self.stopped);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for r in [
+            &mut self.a,
+            &mut self.f,
+            &mut self.b,
+            &mut self.c,
+            &mut self.d,
+            &mut self.e,
+            &mut self.h,
+            &mut self.l,
+        ] {
+            *r = state.u8()?;
+        }
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        self.ime = state.bool()?;
+        self.halt = state.bool()?;
+        self.locked = state.bool()?;
+        self.stopped = state.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::memory::mmu::Mmu;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+/// Decodes the instruction at `addr`. Returns its mnemonic and its length in bytes.
+/// Opcodes are split as `xx yyy zzz`, see https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+pub fn disassemble(mmu: &Mmu, addr: u16) -> (String, u16) {
+    let opcode = mmu.peek_byte(addr);
+    let n = mmu.peek_byte(addr.wrapping_add(1));
+    let nn = u16::from_le_bytes([n, mmu.peek_byte(addr.wrapping_add(2))]);
+    let relative = addr.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    let x = (opcode >> 6) as usize;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = (opcode & 7) as usize;
+    let p = y >> 1;
+    let q = y & 1;
+
+    let (mnemonic, length) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP".to_string(), 1),
+            1 => (format!("LD (${:04X}),SP", nn), 3),
+            2 => ("STOP".to_string(), 2),
+            3 => (format!("JR ${:04X}", relative), 2),
+            _ => (format!("JR {},${:04X}", CC[y - 4], relative), 2),
+        },
+        (0, 1) if q == 0 => (format!("LD {},${:04X}", RP[p], nn), 3),
+        (0, 1) => (format!("ADD HL,{}", RP[p]), 1),
+        (0, 2) => {
+            let indirect = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 {
+                (format!("LD {},A", indirect), 1)
+            } else {
+                (format!("LD A,{}", indirect), 1)
+            }
+        }
+        (0, 3) if q == 0 => (format!("INC {}", RP[p]), 1),
+        (0, 3) => (format!("DEC {}", RP[p]), 1),
+        (0, 4) => (format!("INC {}", R[y]), 1),
+        (0, 5) => (format!("DEC {}", R[y]), 1),
+        (0, 6) => (format!("LD {},${:02X}", R[y], n), 2),
+        (0, _) => (
+            ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y].to_string(),
+            1,
+        ),
+        (1, 6) if y == 6 => ("HALT".to_string(), 1),
+        (1, _) => (format!("LD {},{}", R[y], R[z]), 1),
+        (2, _) => (format!("{}{}", ALU[y], R[z]), 1),
+        (3, 0) => match y {
+            0..=3 => (format!("RET {}", CC[y]), 1),
+            4 => (format!("LDH (${:02X}),A", n), 2),
+            5 => (format!("ADD SP,${:02X}", n), 2),
+            6 => (format!("LDH A,(${:02X})", n), 2),
+            _ => (format!("LD HL,SP+${:02X}", n), 2),
+        },
+        (3, 1) if q == 0 => (format!("POP {}", RP2[p]), 1),
+        (3, 1) => (["RET", "RETI", "JP HL", "LD SP,HL"][p].to_string(), 1),
+        (3, 2) => match y {
+            0..=3 => (format!("JP {},${:04X}", CC[y], nn), 3),
+            4 => ("LD (C),A".to_string(), 1),
+            5 => (format!("LD (${:04X}),A", nn), 3),
+            6 => ("LD A,(C)".to_string(), 1),
+            _ => (format!("LD A,(${:04X})", nn), 3),
+        },
+        (3, 3) => match y {
+            0 => (format!("JP ${:04X}", nn), 3),
+            1 => (disassemble_cb(n), 2),
+            6 => ("DI".to_string(), 1),
+            7 => ("EI".to_string(), 1),
+            _ => (format!("DB ${:02X}", opcode), 1),
+        },
+        (3, 4) if y < 4 => (format!("CALL {},${:04X}", CC[y], nn), 3),
+        (3, 5) if q == 0 => (format!("PUSH {}", RP2[p]), 1),
+        (3, 5) if p == 0 => (format!("CALL ${:04X}", nn), 3),
+        (3, 6) => (format!("{}${:02X}", ALU[y], n), 2),
+        (3, 7) => (format!("RST ${:02X}", y * 8), 1),
+        _ => (format!("DB ${:02X}", opcode), 1),
+    };
+
+    (mnemonic, length)
+}
+
+fn disassemble_cb(opcode: u8) -> String {
+    let x = (opcode >> 6) as usize;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = (opcode & 7) as usize;
+
+    match x {
+        0 => format!("{} {}", ROT[y], R[z]),
+        1 => format!("BIT {},{}", y, R[z]),
+        2 => format!("RES {},{}", y, R[z]),
+        _ => format!("SET {},{}", y, R[z]),
+    }
+}
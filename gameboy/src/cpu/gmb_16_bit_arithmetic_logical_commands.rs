@@ -19,8 +19,9 @@ use crate::memory::mmu::Mmu;
 /// cpu.cycle(&mut memory);
 /// assert_eq!(cpu.get_de(), 0x2360);
 /// ```
-pub fn inc_rr(rr: RegisterPair, cpu: &mut Cpu) -> u8 {
+pub fn inc_rr(rr: RegisterPair, cpu: &mut Cpu, memory: &mut Mmu) -> u8 {
     let value = cpu.get_rr(rr.clone());
+    memory.corrupt_oam(value);
     cpu.set_rr(rr, value.wrapping_add(1));
 
     8
@@ -39,8 +40,9 @@ pub fn inc_rr(rr: RegisterPair, cpu: &mut Cpu) -> u8 {
 /// cpu.cycle(&mut memory);
 /// assert_eq!(cpu.get_de(), 0x235E);
 /// ```
-pub fn dec_rr(rr: RegisterPair, cpu: &mut Cpu) -> u8 {
+pub fn dec_rr(rr: RegisterPair, cpu: &mut Cpu, memory: &mut Mmu) -> u8 {
     let value = cpu.get_rr(rr.clone());
+    memory.corrupt_oam(value);
     cpu.set_rr(rr, value.wrapping_sub(1));
 
     8
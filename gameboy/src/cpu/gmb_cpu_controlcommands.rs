@@ -1,5 +1,6 @@
 use crate::cpu::cpu::{Cpu, Flag::*, RegisterPair};
 use crate::cpu::gmb_16_bit_loadcommands::push_rr;
+use crate::io::timer::DIV_ADDRESS;
 use crate::memory::mmu::Mmu;
 
 /// Sets the interrupt master enable flag and enables maskable interrupts.
@@ -21,12 +22,17 @@ pub fn ei(cpu: &mut Cpu, memory: &mut Mmu) -> u8 {
 }
 
 /// Execution of a STOP instruction stops both the system clock and oscillator circuit. STOP mode is entered, and the LCD controller also stops.
-pub fn stop() -> u8 {
-    panic!("Stop");
-    // TODO:
-    // The following conditions should be met before a STOP instruction is executed and STOP mode is entered
-    // All interrupt-enable (IE) flags are reset.
-    // Input to PI 0 — PI 3 is LOW for all.
+/// On CGB, when a speed switch has been requested through KEY1, STOP switches the CPU speed instead.
+pub fn stop(cpu: &mut Cpu, memory: &mut Mmu) -> u8 {
+    // STOP is followed by a padding byte
+    cpu.read_n(memory);
+
+    if !memory.switch_speed() {
+        // The divider is reset as the system clock stops. STOP mode is left when a button is pressed, see `GameBoy::tick_cpu`
+        memory.write_byte(DIV_ADDRESS, 0);
+        cpu.stopped = true;
+    }
+    4
 }
 
 /// When performing addition and subtraction, binary coded decimal representation is used to set the contents of register A to a binary coded decimal number (BCD).
@@ -121,6 +127,13 @@ pub fn halt(cpu: &mut Cpu) -> u8 {
     //TODO: HALT mode is canceled by an interrupt or reset signal.
 }
 
+/// Illegal opcodes hang the CPU: it stops fetching instructions and ignores interrupts, while the rest of the
+/// hardware keeps running.
+pub fn lock(cpu: &mut Cpu) -> u8 {
+    cpu.locked = true;
+    4
+}
+
 /// Resets the interrupt master enable flag and prohibits maskable interrupts.
 pub fn di(cpu: &mut Cpu, memory: &mut Mmu) -> u8 {
     cpu.ime = false;
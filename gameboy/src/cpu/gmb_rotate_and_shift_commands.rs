@@ -124,7 +124,7 @@ pub fn rra(cpu: &mut Cpu) -> u8 {
 /// ```
 pub fn rlca(cpu: &mut Cpu) -> u8 {
     let value = cpu.a;
-    cpu.a = (value << 1) | (value >> 7);
+    cpu.a = value.rotate_left(1);
 
     cpu.set_flag(Zero, false);
     cpu.set_flag(Subtract, false);
@@ -1,4 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod cpu;
+pub mod disassembler;
 mod gmb_16_bit_arithmetic_logical_commands;
 pub mod gmb_16_bit_loadcommands;
 mod gmb_8_bit_arithmetic_logical_commands;
@@ -7,3 +9,4 @@ mod gmb_cpu_controlcommands;
 mod gmb_jumpcommands;
 mod gmb_rotate_and_shift_commands;
 mod gmb_singlebit_operation_commands;
+pub mod trace;
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::cpu::cpu::Cpu;
+use crate::memory::mmu::Mmu;
+
+/// Size of the write buffer. Big enough to keep the emulation loop away from the disk most of the time.
+const BUFFER_CAPACITY: usize = 1 << 20;
+
+/// When the tracer starts writing lines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceStart {
+    /// Log from the very next instruction.
+    Immediately,
+    /// Log once the CPU reaches the given PC (e.g. 0x0100 to skip the boot rom).
+    AtPc(u16),
+    /// Log once the CPU has run the given number of T-cycles, counted from the rom load or the last reset (a save state
+    /// brings back its own count). They run twice as fast in CGB double speed.
+    AfterCycle(u64),
+}
+
+/// Writes one line per executed instruction, in the format expected by gameboy-doctor:
+/// `A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD`
+pub struct Tracer {
+    writer: BufWriter<File>,
+    start: TraceStart,
+    started: bool,
+}
+
+impl Tracer {
+    pub fn new(path: PathBuf, start: TraceStart) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+
+        Ok(Self {
+            writer: BufWriter::with_capacity(BUFFER_CAPACITY, file),
+            start,
+            started: start == TraceStart::Immediately,
+        })
+    }
+
+    /// Logs the state of the CPU before the instruction at PC is executed.
+    pub fn log(&mut self, cpu: &Cpu, mmu: &Mmu, cpu_cycles: u64) -> std::io::Result<()> {
+        if !self.started {
+            self.started = match self.start {
+                TraceStart::Immediately => true,
+                TraceStart::AtPc(pc) => cpu.pc == pc,
+                TraceStart::AfterCycle(cycle) => cpu_cycles >= cycle,
+            };
+
+            if !self.started {
+                return Ok(());
+            }
+        }
+
+        writeln!(self.writer, "{}", format_line(cpu, mmu))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Returns the gameboy-doctor line describing the current state of the CPU.
+pub fn format_line(cpu: &Cpu, mmu: &Mmu) -> String {
+    let pc = cpu.pc;
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        cpu.a,
+        cpu.f,
+        cpu.b,
+        cpu.c,
+        cpu.d,
+        cpu.e,
+        cpu.h,
+        cpu.l,
+        cpu.sp,
+        pc,
+        mmu.peek_byte(pc),
+        mmu.peek_byte(pc.wrapping_add(1)),
+        mmu.peek_byte(pc.wrapping_add(2)),
+        mmu.peek_byte(pc.wrapping_add(3)),
+    )
+}
@@ -0,0 +1,65 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// CGB palette memory, accessed through BCPS/BCPD (background) or OCPS/OCPD (objects).
+/// Holds 8 palettes of 4 colors, each color being 15-bit little endian: `0bbbbbgg gggrrrrr`.
+pub struct ColorRam {
+    data: [u8; 64],
+    /// Byte accessed through the data register
+    index: u8,
+    /// Increment the index after each write to the data register
+    auto_increment: bool,
+}
+
+impl Default for ColorRam {
+    fn default() -> Self {
+        Self {
+            // The boot rom leaves the palettes white
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+}
+
+impl ColorRam {
+    pub fn read_spec(&self) -> u8 {
+        0x40 | (self.auto_increment as u8) << 7 | self.index
+    }
+
+    pub fn write_spec(&mut self, val: u8) {
+        self.index = val & 0x3F;
+        self.auto_increment = val & 0x80 == 0x80;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    /// Returns the 15-bit value of `color` (0-3) in `palette` (0-7).
+    pub fn get_color(&self, palette: u8, color: u8) -> u16 {
+        let index = (palette as usize * 4 + color as usize) * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]])
+    }
+}
+
+impl SaveState for ColorRam {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.index);
+        state.bool(self.auto_increment);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.data)?;
+        self.index = state.u8()? & 0x3F;
+        self.auto_increment = state.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::gpu::palette::{rgb, Palette};
+use crate::memory::mmu::Mmu;
+
+/// A palette of the CGB boot rom, which the player can pick with a button combination while the logo is shown.
+pub struct ManualPalette {
+    pub buttons: &'static str,
+    pub palette: Palette,
+}
+
+/// The 12 palettes selectable with the button combinations of the CGB boot rom.
+pub const MANUAL_PALETTES: [ManualPalette; 12] = [
+    manual("Up", BROWN, BROWN, BROWN),
+    manual("Up + A", RED, RED, RED),
+    manual("Up + B", DARK_BROWN, DARK_BROWN, DARK_BROWN),
+    manual("Left", BLUE, RED, RED),
+    manual("Left + A", DARK_BLUE, RED, BROWN),
+    manual("Left + B", GREY, GREY, GREY),
+    manual("Down", PASTEL, PASTEL, PASTEL),
+    manual("Down + A", ORANGE, ORANGE, ORANGE),
+    manual("Down + B", YELLOW, BLUE, GREEN),
+    manual("Right", GREEN_RED, GREEN_RED, GREEN_RED),
+    manual("Right + A", INVERTED, INVERTED, INVERTED),
+    manual("Right + B", GREEN_BLUE, RED, RED),
+];
+
+/// Palette used for the games which are not in the boot rom table, the one of "Right".
+pub const DEFAULT_PALETTE: usize = 9;
+
+const BROWN: [u32; 4] = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const RED: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const DARK_BROWN: [u32; 4] = [0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108];
+const BLUE: [u32; 4] = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+const DARK_BLUE: [u32; 4] = [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000];
+const GREY: [u32; 4] = [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000];
+const PASTEL: [u32; 4] = [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000];
+const ORANGE: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000];
+const YELLOW: [u32; 4] = [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000];
+const GREEN: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const GREEN_RED: [u32; 4] = [0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000];
+const INVERTED: [u32; 4] = [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF];
+const GREEN_BLUE: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+
+/// Colors of the title palettes which are not also manual palettes.
+const MARIO_BG: [u32; 4] = [0xFFFFFF, 0xADAD84, 0x42737B, 0x000000];
+const MARIO_OBJ: [u32; 4] = [0xFFFFFF, 0xFF7300, 0x944200, 0x000000];
+const MARIO_OBJ1: [u32; 4] = [0xFFFFFF, 0x5ABDFF, 0xFF0000, 0x0000FF];
+const MARIOLAND2_BG: [u32; 4] = [0xFFFFCE, 0x63EFEF, 0x9C8431, 0x5A5A5A];
+const ZELDA_OBJ0: [u32; 4] = [0xFFFFFF, 0x00FF00, 0x318400, 0x004A00];
+
+/// Palettes the boot rom assigns to some of the Nintendo games: `(title checksum, 4th letter of the title, palette)`.
+/// The boot rom only compares the 4th letter for the checksums which several titles share, the other entries match any
+/// letter.
+const TITLE_PALETTES: [(u8, Option<u8>, Palette); 7] = [
+    // POKEMON RED
+    (0x14, None, title(RED, GREEN, RED)),
+    // POKEMON GREEN
+    (0xAA, None, title(GREEN, RED, GREEN)),
+    // POKEMON BLUE, which shares its checksum with another title
+    (0x61, Some(b'E'), title(BLUE, RED, BLUE)),
+    // SUPER MARIOLAND, METROID2 has the same checksum
+    (0x46, Some(b'E'), title(MARIO_BG, MARIO_OBJ, MARIO_OBJ1)),
+    // MARIOLAND2
+    (0xC9, None, title(MARIOLAND2_BG, MARIO_OBJ, BLUE)),
+    // TETRIS
+    (0xDB, None, title(ORANGE, ORANGE, ORANGE)),
+    // ZELDA
+    (0x70, None, title(RED, ZELDA_OBJ0, BLUE)),
+];
+
+/// Returns the palette the CGB boot rom would pick for the DMG game loaded in `mmu`: the one listed for its title if
+/// Nintendo published it, `DEFAULT_PALETTE` otherwise.
+pub fn automatic_palette(mmu: &Mmu) -> Palette {
+    // Only games published by Nintendo are looked up
+    let old_licensee = mmu.peek_byte(0x014B);
+    let new_licensee = [mmu.peek_byte(0x0144), mmu.peek_byte(0x0145)];
+    if old_licensee != 0x01 && !(old_licensee == 0x33 && new_licensee == *b"01") {
+        return MANUAL_PALETTES[DEFAULT_PALETTE].palette;
+    }
+
+    let checksum = (0x0134..=0x0143).fold(0_u8, |sum, addr| sum.wrapping_add(mmu.peek_byte(addr)));
+    let fourth_letter = mmu.peek_byte(0x0137);
+
+    TITLE_PALETTES
+        .iter()
+        .find(|(title_checksum, letter, _)| {
+            *title_checksum == checksum && letter.is_none_or(|letter| letter == fourth_letter)
+        })
+        .map_or(
+            MANUAL_PALETTES[DEFAULT_PALETTE].palette,
+            |(_, _, palette)| *palette,
+        )
+}
+
+const fn manual(
+    buttons: &'static str,
+    bg: [u32; 4],
+    obp0: [u32; 4],
+    obp1: [u32; 4],
+) -> ManualPalette {
+    ManualPalette {
+        buttons,
+        palette: title(bg, obp0, obp1),
+    }
+}
+
+const fn title(bg: [u32; 4], obp0: [u32; 4], obp1: [u32; 4]) -> Palette {
+    Palette {
+        bg: colors(bg),
+        obp0: colors(obp0),
+        obp1: colors(obp1),
+    }
+}
+
+const fn colors(values: [u32; 4]) -> [[u8; 4]; 4] {
+    [
+        rgb(values[0]),
+        rgb(values[1]),
+        rgb(values[2]),
+        rgb(values[3]),
+    ]
+}
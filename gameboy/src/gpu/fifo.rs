@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+
+use crate::gpu::lcdc;
+use crate::memory::mmu::Mmu;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// A pixel waiting in the background FIFO.
+#[derive(Clone, Copy, Default)]
+pub struct BgPixel {
+    pub color: u8,
+    /// CGB palette, from the tile attributes
+    pub palette: u8,
+    /// CGB only: colors 1-3 are drawn over the objects
+    pub priority: bool,
+}
+
+/// A pixel waiting in the object FIFO.
+#[derive(Clone, Copy, Default)]
+pub struct ObjPixel {
+    pub color: u8,
+    pub obp1: bool,
+    /// CGB palette, from the object flags
+    pub palette: u8,
+    /// BG and window colors 1-3 are drawn over the object
+    pub bg_priority: bool,
+    /// Position of the object in OAM, which decides the priority between objects on CGB
+    pub oam_index: u8,
+}
+
+/// An object selected during the OAM scan.
+#[derive(Clone, Copy, Default)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+    pub oam_index: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+    TileId,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/// Background/window fetcher. Each of the first three steps takes 2 dots, then the fetcher waits until the
+/// BG FIFO is empty to push the 8 pixels of the tile.
+pub struct Fetcher {
+    step: FetchStep,
+    step_dots: u8,
+    /// Tile column being fetched, relative to the start of the line (or of the window).
+    x: u8,
+    tile_id: u8,
+    /// CGB tile attributes, read from VRAM bank 1 at the address of the tile id
+    attributes: u8,
+    data_low: u8,
+    data_high: u8,
+    window: bool,
+}
+
+impl Fetcher {
+    pub fn new() -> Self {
+        Self {
+            step: FetchStep::TileId,
+            step_dots: 0,
+            x: 0,
+            tile_id: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            window: false,
+        }
+    }
+
+    /// Restarts the fetcher on the first tile of the window.
+    pub fn start_window(&mut self) {
+        *self = Self {
+            window: true,
+            ..Self::new()
+        };
+    }
+
+    /// Restarts the fetcher on the background, at tile column `x` of the line.
+    pub fn start_background(&mut self, x: u8) {
+        *self = Self { x, ..Self::new() };
+    }
+
+    /// Fetches again the tile which has just been pushed.
+    pub fn restart_tile(&mut self) {
+        self.x = self.x.wrapping_sub(1);
+        self.step = FetchStep::TileId;
+        self.step_dots = 0;
+    }
+
+    /// Advances the fetcher by one dot. `line` is the line of the background (LY + SCY) or of the window.
+    pub fn step(&mut self, mmu: &Mmu, line: u8, fifo: &mut VecDeque<BgPixel>) {
+        if self.step != FetchStep::Push {
+            self.step_dots += 1;
+            if self.step_dots < 2 {
+                return;
+            }
+            self.step_dots = 0;
+
+            match self.step {
+                FetchStep::TileId => {
+                    let addr = self.tile_map_address(mmu, line);
+                    self.tile_id = mmu.read_vram(0, addr);
+                    self.attributes = if mmu.is_cgb() {
+                        mmu.read_vram(1, addr)
+                    } else {
+                        0
+                    };
+                    self.step = FetchStep::DataLow;
+                }
+                FetchStep::DataLow => {
+                    self.data_low = mmu.read_vram(self.bank(), self.tile_data_address(mmu, line));
+                    self.step = FetchStep::DataHigh;
+                }
+                FetchStep::DataHigh => {
+                    self.data_high =
+                        mmu.read_vram(self.bank(), self.tile_data_address(mmu, line) + 1);
+                    self.step = FetchStep::Push;
+                }
+                FetchStep::Push => unreachable!(),
+            }
+
+            // The tile can be pushed on the dot its high byte is read
+            if self.step != FetchStep::Push {
+                return;
+            }
+        }
+
+        if fifo.is_empty() {
+            let x_flipped = self.attributes & 0x20 == 0x20;
+            for pixel in 0..8 {
+                let bit = if x_flipped { pixel } else { 7 - pixel };
+                fifo.push_back(BgPixel {
+                    color: ((self.data_high >> bit) & 1) << 1 | ((self.data_low >> bit) & 1),
+                    palette: self.attributes & 0x7,
+                    priority: self.attributes & 0x80 == 0x80,
+                });
+            }
+            self.x = self.x.wrapping_add(1);
+            self.step = FetchStep::TileId;
+        }
+    }
+
+    fn tile_map_address(&self, mmu: &Mmu, line: u8) -> u16 {
+        let (map_bit, column) = if self.window {
+            (6, self.x)
+        } else {
+            (3, (mmu.peek_byte(0xFF43) / 8).wrapping_add(self.x))
+        };
+        let tile_map = if lcdc::get_lcdc_n(map_bit, mmu) {
+            0x9C00
+        } else {
+            0x9800
+        };
+
+        tile_map + (line as u16 / 8) * 32 + (column & 31) as u16
+    }
+
+    fn tile_data_address(&self, mmu: &Mmu, line: u8) -> u16 {
+        let tile = if lcdc::get_lcdc_n(4, mmu) {
+            0x8000 + self.tile_id as u16 * 16
+        } else {
+            (0x9000 + self.tile_id as i8 as i32 * 16) as u16
+        };
+
+        let mut row = line as u16 % 8;
+        if self.attributes & 0x40 == 0x40 {
+            row = 7 - row;
+        }
+
+        tile + row * 2
+    }
+
+    /// VRAM bank of the tile data, from the CGB tile attributes.
+    fn bank(&self) -> usize {
+        ((self.attributes >> 3) & 1) as usize
+    }
+}
+
+/// Returns the objects visible on line `ly`, at most 10, ordered by drawing priority.
+pub fn oam_scan(mmu: &Mmu, ly: u8) -> Vec<Sprite> {
+    let height = sprite_height(mmu);
+    let mut sprites: Vec<Sprite> = (0..40)
+        .map(|index| {
+            let addr = 0xFE00 + index * 4;
+            Sprite {
+                y: mmu.peek_byte(addr),
+                x: mmu.peek_byte(addr + 1),
+                tile: mmu.peek_byte(addr + 2),
+                flags: mmu.peek_byte(addr + 3),
+                oam_index: index as u8,
+            }
+        })
+        .filter(|sprite| {
+            let top = sprite.y as i16 - 16;
+            (top..top + height as i16).contains(&(ly as i16))
+        })
+        .take(10)
+        .collect();
+
+    // Objects are fetched from left to right, then in OAM order (sort is stable)
+    sprites.sort_by_key(|sprite| sprite.x);
+    sprites
+}
+
+/// Fetches the line of `sprite` visible on `ly` and merges it into the object FIFO.
+/// On DMG, the object with the smallest X is drawn on top: pixels already in the FIFO have priority, only transparent
+/// ones are replaced. On CGB, the first object in OAM is drawn on top.
+pub fn fetch_sprite(mmu: &Mmu, sprite: &Sprite, ly: u8, fifo: &mut VecDeque<ObjPixel>) {
+    let height = sprite_height(mmu);
+    // Masked to the current height, which may have changed since the OAM scan
+    let mut line = ly.wrapping_sub(sprite.y.wrapping_sub(16)) & (height - 1);
+    if sprite.flags & 0x40 == 0x40 {
+        line = (height - 1).wrapping_sub(line);
+    }
+    let tile = if height == 16 {
+        sprite.tile & 0xFE
+    } else {
+        sprite.tile
+    };
+
+    let cgb = mmu.is_cgb();
+    let bank = if cgb {
+        ((sprite.flags >> 3) & 1) as usize
+    } else {
+        0
+    };
+    let addr = 0x8000 + tile as u16 * 16 + line as u16 * 2;
+    let data_low = mmu.read_vram(bank, addr);
+    let data_high = mmu.read_vram(bank, addr + 1);
+    let x_flipped = sprite.flags & 0x20 == 0x20;
+
+    // Objects partially off the left edge lose their first pixels
+    let hidden = 8_u8.saturating_sub(sprite.x);
+
+    for (i, pixel) in (hidden..8).enumerate() {
+        let bit = if x_flipped { pixel } else { 7 - pixel };
+        let obj_pixel = ObjPixel {
+            color: ((data_high >> bit) & 1) << 1 | ((data_low >> bit) & 1),
+            obp1: sprite.flags & 0x10 == 0x10,
+            palette: if cgb { sprite.flags & 0x7 } else { 0 },
+            bg_priority: sprite.flags & 0x80 == 0x80,
+            oam_index: sprite.oam_index,
+        };
+
+        match fifo.get_mut(i) {
+            Some(existing) if existing.color == 0 => *existing = obj_pixel,
+            Some(existing)
+                if cgb && obj_pixel.color != 0 && obj_pixel.oam_index < existing.oam_index =>
+            {
+                *existing = obj_pixel
+            }
+            Some(_) => (),
+            None => fifo.push_back(obj_pixel),
+        }
+    }
+}
+
+fn sprite_height(mmu: &Mmu) -> u8 {
+    if lcdc::get_lcdc_n(2, mmu) {
+        16
+    } else {
+        8
+    }
+}
+
+impl SaveState for BgPixel {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.color);
+        state.u8(self.palette);
+        state.bool(self.priority);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.color = state.u8()? & 0x03;
+        self.palette = state.u8()? & 0x07;
+        self.priority = state.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for ObjPixel {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.color);
+        state.bool(self.obp1);
+        state.u8(self.palette);
+        state.bool(self.bg_priority);
+        state.u8(self.oam_index);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.color = state.u8()? & 0x03;
+        self.obp1 = state.bool()?;
+        self.palette = state.u8()? & 0x07;
+        self.bg_priority = state.bool()?;
+        self.oam_index = state.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sprite {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&[self.y, self.x, self.tile, self.flags, self.oam_index]);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 5];
+        state.bytes_into(&mut bytes)?;
+        [self.y, self.x, self.tile, self.flags, self.oam_index] = bytes;
+        Ok(())
+    }
+}
+
+impl SaveState for Fetcher {
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(match self.step {
+            FetchStep::TileId => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        });
+        state.bytes(&[
+            self.step_dots,
+            self.x,
+            self.tile_id,
+            self.attributes,
+            self.data_low,
+            self.data_high,
+        ]);
+        state.bool(self.window);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.step = match state.u8()? {
+            0 => FetchStep::TileId,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        let mut bytes = [0; 6];
+        state.bytes_into(&mut bytes)?;
+        [
+            self.step_dots,
+            self.x,
+            self.tile_id,
+            self.attributes,
+            self.data_low,
+            self.data_high,
+        ] = bytes;
+        self.window = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    const LCDC_OBJ_16: u8 = 0x04;
+
+    fn setup(cgb: bool, lcdc: u8) -> Mmu {
+        let mut mmu = if cgb {
+            Mmu::blank_cgb()
+        } else {
+            Mmu::blank(Model::Dmg)
+        };
+        mmu.set_access_restrictions(false);
+        mmu.write_byte(0xFF40, lcdc);
+        mmu
+    }
+
+    /// Writes the low and high bytes of `row` of tile `tile` of VRAM bank 0.
+    fn write_row(mmu: &mut Mmu, tile: u8, row: u16, low: u8, high: u8) {
+        let addr = 0x8000 + tile as u16 * 16 + row * 2;
+        mmu.write_byte(addr, low);
+        mmu.write_byte(addr + 1, high);
+    }
+
+    fn write_oam(mmu: &mut Mmu, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        for (offset, val) in [y, x, tile, flags].into_iter().enumerate() {
+            mmu.write_byte(0xFE00 + index * 4 + offset as u16, val);
+        }
+    }
+
+    fn sprite(y: u8, x: u8, tile: u8, flags: u8, oam_index: u8) -> Sprite {
+        Sprite {
+            y,
+            x,
+            tile,
+            flags,
+            oam_index,
+        }
+    }
+
+    /// Fetches `sprite` on line `ly` into an empty FIFO and returns the colors pushed.
+    fn fetch(mmu: &Mmu, sprite: &Sprite, ly: u8) -> Vec<u8> {
+        let mut fifo = VecDeque::new();
+        fetch_sprite(mmu, sprite, ly, &mut fifo);
+        fifo.iter().map(|pixel| pixel.color).collect()
+    }
+
+    #[test]
+    fn sprite_is_flipped_by_its_flags() {
+        let mut mmu = setup(false, 0);
+        // Color 1 on the first pixel of the top row, color 2 on the last pixel of the bottom row
+        write_row(&mut mmu, 1, 0, 0x80, 0x00);
+        write_row(&mut mmu, 1, 7, 0x00, 0x01);
+
+        assert_eq!(
+            fetch(&mmu, &sprite(16, 8, 1, 0x00, 0), 0),
+            [1, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            fetch(&mmu, &sprite(16, 8, 1, 0x20, 0), 0),
+            [0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            fetch(&mmu, &sprite(16, 8, 1, 0x40, 0), 0),
+            [0, 0, 0, 0, 0, 0, 0, 2]
+        );
+        assert_eq!(
+            fetch(&mmu, &sprite(16, 8, 1, 0x60, 0), 0),
+            [2, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn tall_sprite_uses_both_tiles_and_ignores_the_low_bit_of_the_tile() {
+        let mut mmu = setup(false, LCDC_OBJ_16);
+        write_row(&mut mmu, 2, 3, 0xFF, 0x00);
+        write_row(&mut mmu, 3, 4, 0x00, 0xFF);
+
+        // Line 3 is in tile 2, line 12 flipped to line 3 from the bottom is in tile 3
+        assert_eq!(fetch(&mmu, &sprite(16, 8, 3, 0x00, 0), 3), [1; 8]);
+        assert_eq!(fetch(&mmu, &sprite(16, 8, 3, 0x40, 0), 3), [2; 8]);
+    }
+
+    #[test]
+    fn sprite_height_change_after_the_scan_is_masked() {
+        let mut mmu = setup(false, LCDC_OBJ_16);
+        write_oam(&mut mmu, 0, 16, 8, 3, 0x40);
+        write_row(&mut mmu, 3, 5, 0x00, 0xFF);
+        let sprites = oam_scan(&mmu, 10);
+        assert_eq!(sprites.len(), 1);
+
+        // Back to 8x8: line 10 of the object is line 2 of tile 3, flipped to line 5
+        mmu.write_byte(0xFF40, 0);
+        assert_eq!(fetch(&mmu, &sprites[0], 10), [2; 8]);
+    }
+
+    #[test]
+    fn sprite_off_the_left_edge_loses_its_first_pixels() {
+        let mut mmu = setup(false, 0);
+        write_row(&mut mmu, 1, 0, 0b1000_0111, 0x00);
+
+        assert_eq!(fetch(&mmu, &sprite(16, 3, 1, 0x00, 0), 0), [1, 1, 1]);
+        assert_eq!(fetch(&mmu, &sprite(16, 1, 1, 0x00, 0), 0), [1]);
+        assert_eq!(fetch(&mmu, &sprite(16, 0, 1, 0x00, 0), 0), []);
+    }
+
+    #[test]
+    fn oam_scan_keeps_ten_sprites_ordered_by_x_then_oam() {
+        let mut mmu = setup(false, 0);
+        // Not on line 0
+        write_oam(&mut mmu, 0, 40, 1, 0, 0);
+        for index in 1..13 {
+            let x = [50, 20, 20, 10][index as usize % 4];
+            write_oam(&mut mmu, index, 16, x, 0, 0);
+        }
+
+        let order: Vec<u8> = oam_scan(&mmu, 0)
+            .iter()
+            .map(|sprite| sprite.oam_index)
+            .collect();
+        assert_eq!(order, [3, 7, 1, 2, 5, 6, 9, 10, 4, 8]);
+    }
+
+    #[test]
+    fn sprites_merge_by_x_on_dmg_and_by_oam_index_on_cgb() {
+        for cgb in [false, true] {
+            let mut mmu = setup(cgb, 0);
+            write_row(&mut mmu, 1, 0, 0x0F, 0x00);
+            write_row(&mut mmu, 2, 0, 0x00, 0xFF);
+
+            // Fetched first, as the one with the smallest X, but later in OAM
+            let mut fifo = VecDeque::new();
+            fetch_sprite(&mmu, &sprite(16, 8, 1, 0x00, 5), 0, &mut fifo);
+            fetch_sprite(&mmu, &sprite(16, 8, 2, 0x00, 2), 0, &mut fifo);
+            let colors: Vec<u8> = fifo.iter().map(|pixel| pixel.color).collect();
+
+            // The transparent pixels are always filled by the second object
+            let expected = if cgb {
+                [2; 8]
+            } else {
+                [2, 2, 2, 2, 1, 1, 1, 1]
+            };
+            assert_eq!(colors, expected, "cgb: {}", cgb);
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod color_ram;
+pub mod colorization;
+pub mod fifo;
 pub mod lcdc;
+pub mod palette;
 pub mod ppu;
@@ -0,0 +1,105 @@
+/// RGBA color written to the frame.
+pub type Color = [u8; 4];
+
+/// Colors used to display the 4 DMG shades, from lightest to darkest.
+/// Background/window, OBP0 and OBP1 pixels can have their own colors.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Palette {
+    pub bg: [Color; 4],
+    pub obp0: [Color; 4],
+    pub obp1: [Color; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Preset::Grey.palette()
+    }
+}
+
+impl Palette {
+    /// Uses the same colors for the background and both object palettes.
+    pub const fn uniform(colors: [Color; 4]) -> Self {
+        Self {
+            bg: colors,
+            obp0: colors,
+            obp1: colors,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Preset {
+    Grey,
+    /// Green tint of the original DMG screen
+    Green,
+    Pocket,
+    /// Backlit screen of the Game Boy Light
+    Light,
+    HighContrast,
+    /// Viridis scale, which stays readable with the common forms of color blindness
+    ColorBlind,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 6] = [
+        Preset::Grey,
+        Preset::Green,
+        Preset::Pocket,
+        Preset::Light,
+        Preset::HighContrast,
+        Preset::ColorBlind,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Grey => "Grey",
+            Preset::Green => "DMG green",
+            Preset::Pocket => "Pocket",
+            Preset::Light => "Light",
+            Preset::HighContrast => "High contrast",
+            Preset::ColorBlind => "Color blind friendly",
+        }
+    }
+
+    pub fn palette(&self) -> Palette {
+        let colors = match self {
+            Preset::Grey => [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000],
+            Preset::Green => [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F],
+            Preset::Pocket => [0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F],
+            Preset::Light => [0x00B581, 0x009A71, 0x00694A, 0x004F3B],
+            Preset::HighContrast => [0xFFFFFF, 0xC0C0C0, 0x404040, 0x000000],
+            Preset::ColorBlind => [0xFDE725, 0x35B779, 0x31688E, 0x440154],
+        };
+
+        Palette::uniform(colors.map(rgb))
+    }
+}
+
+/// Converts a 15-bit CGB color to RGB888. With `correction`, the colors are mixed and darkened to approximate the
+/// washed out look of the CGB LCD, without it the 5-bit channels are only scaled up.
+pub fn cgb_color(value: u16, correction: bool) -> Color {
+    let r = (value & 0x1F) as u32;
+    let g = ((value >> 5) & 0x1F) as u32;
+    let b = ((value >> 10) & 0x1F) as u32;
+
+    if correction {
+        [
+            ((r * 26 + g * 4 + b * 2).min(960) >> 2) as u8,
+            ((g * 24 + b * 8).min(960) >> 2) as u8,
+            ((r * 6 + g * 4 + b * 22).min(960) >> 2) as u8,
+            0xFF,
+        ]
+    } else {
+        [
+            (r << 3 | r >> 2) as u8,
+            (g << 3 | g >> 2) as u8,
+            (b << 3 | b >> 2) as u8,
+            0xFF,
+        ]
+    }
+}
+
+/// Converts a 0xRRGGBB value to an opaque RGBA color.
+pub const fn rgb(value: u32) -> Color {
+    [(value >> 16) as u8, (value >> 8) as u8, value as u8, 0xFF]
+}
@@ -1,26 +1,87 @@
-use crate::gpu::lcdc;
+use std::collections::VecDeque;
+
+use crate::gpu::palette::{self, Palette};
+use crate::gpu::{fifo, lcdc};
 use crate::memory::mmu::Mmu;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+const DOTS_PER_LINE: u16 = 456;
+const SPRITE_FETCH_DOTS: u8 = 6;
 
 #[derive(PartialEq)]
 enum Mode {
     HBlank,
     VBlank,
-    OAM,
+    Oam,
     DrawingPixel,
 }
 
 pub struct Ppu {
+    /// Dots elapsed on the current line
     dots: u16,
     mode: Mode,
+    /// State of the internal STAT interrupt line
+    stat_line: bool,
+    /// LCDC.7 as seen on the previous dot
+    lcd_on: bool,
+    /// The LCD has just been enabled, the first line has no OAM scan visible in STAT
+    lcd_starting: bool,
+    /// The first frame after the LCD is enabled is not displayed
+    skip_frame: bool,
+    /// Number of pixels pushed to the LCD on the current line
+    lx: u8,
+    /// Pixels left to drop for the SCX fine scroll
+    discard: u8,
+    first_fetch: bool,
+    /// The fetcher is on the window for the rest of the line
+    window_active: bool,
+    /// The window has been drawn on the current line
+    window_drawn: bool,
+    /// Internal line counter of the window, only incremented on lines where the window was drawn
+    window_line: u8,
+    /// WY matched LY on a line of the current frame
+    window_y_triggered: bool,
+    /// WX was 166 at the end of the previous line, the window covers the whole current line
+    window_full_line: bool,
+    fetcher: fifo::Fetcher,
+    bg_fifo: VecDeque<fifo::BgPixel>,
+    obj_fifo: VecDeque<fifo::ObjPixel>,
+    /// Objects of the current line not fetched yet
+    sprites: Vec<fifo::Sprite>,
+    /// Object being fetched, with the number of dots left
+    sprite_fetch: Option<(fifo::Sprite, u8)>,
+    /// Background tile on which the last object of the line was fetched
+    last_sprite_tile: Option<u8>,
+    /// DMG shade (0-3) of each pixel of the last frame, read by the SGB
+    shades: Vec<u8>,
 }
 
 impl Ppu {
     pub fn new() -> Self {
         Self {
             dots: 0,
-            mode: Mode::HBlank,
+            mode: Mode::Oam,
+            stat_line: false,
+            lcd_on: true,
+            lcd_starting: false,
+            skip_frame: false,
+            lx: 0,
+            discard: 0,
+            first_fetch: true,
+            window_active: false,
+            window_drawn: false,
+            window_line: 0,
+            window_y_triggered: false,
+            window_full_line: false,
+            fetcher: fifo::Fetcher::new(),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            sprites: Vec::with_capacity(10),
+            sprite_fetch: None,
+            last_sprite_tile: None,
+            shades: vec![0; WIDTH * HEIGHT],
         }
     }
 
@@ -28,317 +89,474 @@ impl Ppu {
         let mode_flag_bit = match &mode {
             Mode::HBlank => 0,
             Mode::VBlank => 1,
-            Mode::OAM => 2,
+            Mode::Oam => 2,
             Mode::DrawingPixel => 3,
         };
-        let mode_flag = mmu.read_byte(0xFF41) & 0xFC; // Unset bit 0 and 1
-        mmu.write_byte(0xFF41, mode_flag | mode_flag_bit); // Set correct bit
+        mmu.set_stat_mode_flag(mode_flag_bit);
         self.mode = mode;
-
-        match self.mode {
-            Mode::DrawingPixel => return,
-            Mode::HBlank => {
-                if (mmu.read_byte(0xFF41) >> 3) & 1 == 1 {
-                    mmu.set_interrupt_flag(1);
-                }
-            }
-            Mode::VBlank => {
-                if (mmu.read_byte(0xFF41) >> 4) & 1 == 1 {
-                    mmu.set_interrupt_flag(1);
-                }
-            }
-            Mode::OAM => {
-                if (mmu.read_byte(0xFF41) >> 5) & 1 == 1 {
-                    mmu.set_interrupt_flag(1);
-                }
-            }
-        }
     }
 
-    pub fn update(&mut self, frame: &mut [u8], mmu: &mut Mmu) {
+    /// Runs a single dot. Returns true when the PPU enters VBlank, once the frame is complete.
+    pub fn update(
+        &mut self,
+        frame: &mut [u8],
+        mmu: &mut Mmu,
+        palette: &Palette,
+        color_correction: bool,
+    ) -> bool {
+        let stat_written = mmu.take_stat_write();
+
         // Update the PPU if the LCD is enabled
         if !lcdc::is_ldc_and_ppu_enable(mmu) {
+            if self.lcd_on {
+                self.turn_off(frame, mmu, palette);
+            }
             reset_ly(mmu); // Reset scanline
             self.dots = 0;
-            mmu.write_byte(0xFF41, mmu.read_byte(0xFF41) & 0xFC | 1); // Set to mode 1
-            return;
+            mmu.set_stat_mode_flag(0); // STAT reads mode 0 while the LCD is off
+            return false;
+        }
+
+        if !self.lcd_on {
+            self.turn_on(mmu);
         }
 
+        let mut frame_done = false;
         match self.mode {
             Mode::HBlank => {
-                if self.dots == 376 {
-                    // MODE 3 + MODE 0 - MODE 2 = 456 - 80
+                if self.dots == DOTS_PER_LINE {
                     self.dots = 0;
 
                     let ly = increment_ly(mmu);
                     if ly == 144 {
                         // At line 144, the PPU enters V-Blank and requests a V-Blank interrupt
+                        self.reset_window();
+                        self.skip_frame = false;
                         self.set_mode(Mode::VBlank, mmu);
                         mmu.set_interrupt_flag(0);
+                        frame_done = true;
                     } else {
-                        self.set_mode(Mode::OAM, mmu)
+                        self.set_mode(Mode::Oam, mmu)
                     }
                 }
             }
             Mode::VBlank => {
-                if self.dots == 456 {
-                    // 456 dots per line
+                if self.dots == 4 && mmu.get_ly() == 153 {
+                    // LY only reads 153 for the first M-cycle of line 153, then 0 until the end of the frame
+                    reset_ly(mmu);
+                }
+
+                if self.dots == DOTS_PER_LINE {
                     self.dots = 0;
 
-                    let ly = increment_ly(mmu);
-                    if ly == 154 {
-                        // At line 154, the PPU returns to OAM mode
-                        reset_ly(mmu);
-                        self.set_mode(Mode::OAM, mmu)
+                    if mmu.get_ly() == 0 {
+                        // After line 153, the PPU returns to OAM mode
+                        self.set_mode(Mode::Oam, mmu)
+                    } else {
+                        increment_ly(mmu);
                     }
                 }
             }
-            Mode::OAM => {
+            Mode::Oam => {
                 if self.dots == 80 {
                     // Searching OAM takes 80 dots
-                    self.dots = 0;
+                    self.sprites = fifo::oam_scan(mmu, mmu.get_ly());
+                    self.lcd_starting = false;
+                    self.start_drawing(mmu);
                     self.set_mode(Mode::DrawingPixel, mmu)
                 }
             }
             Mode::DrawingPixel => {
-                if self.dots == 172 {
-                    self.dots = 0;
-                    draw_scanline(frame, mmu);
-                    self.set_mode(Mode::HBlank, mmu)
+                // Mode 3 lasts until the 160 pixels of the line are pushed, HBlank takes the rest of the 456 dots
+                if self.lx == WIDTH as u8 {
+                    self.end_window_line(mmu);
+                    self.set_mode(Mode::HBlank, mmu);
+                    mmu.hblank_dma();
+                } else {
+                    self.draw_dot(frame, mmu, palette, color_correction);
                 }
             }
         }
 
-        check_coincidence_flag(mmu);
+        self.update_stat_line(mmu, stat_written);
+        mmu.set_oam_scan_row(
+            (self.mode == Mode::Oam && !self.lcd_starting).then_some(self.dots as usize / 4),
+        );
 
         self.dots += 1;
+        frame_done
     }
-}
-
-fn check_coincidence_flag(mmu: &mut Mmu) {
-    let ly = mmu.get_ly();
-    let lyc = mmu.read_byte(0xFF45);
-    let stat = mmu.read_byte(0xFF41);
 
-    if ly == lyc {
-        // If LY == LYC, set the coincidence flag in STAT
-        mmu.write_byte(0xFF41, stat | 0x4);
+    /// The STAT interrupt is requested on the rising edge of the OR of all the enabled sources.
+    /// A source staying active, or a second one becoming active, does not request it again.
+    fn update_stat_line(&mut self, mmu: &mut Mmu, stat_written: bool) {
+        let coincidence = mmu.get_ly() == mmu.read_byte(0xFF45);
+        mmu.set_coincidence_flag(coincidence);
+
+        let stat = mmu.read_byte(0xFF41);
+        let mut line = (stat & 0x40 == 0x40 && coincidence)
+            || (stat & 0x08 == 0x08 && self.mode == Mode::HBlank)
+            || (stat & 0x10 == 0x10 && self.mode == Mode::VBlank)
+            || (stat & 0x20 == 0x20 && self.mode == Mode::Oam && !self.lcd_starting)
+            // The mode 2 source is also raised at the start of line 144
+            || (stat & 0x20 == 0x20 && self.mode == Mode::VBlank && mmu.get_ly() == 144 && self.dots == 0);
+
+        // DMG bug: for one cycle, writing to STAT behaves as if every source was enabled
+        if stat_written && mmu.get_model().has_stat_write_bug() {
+            line |= coincidence || self.mode == Mode::HBlank || self.mode == Mode::VBlank;
+        }
 
-        // If the coincidence interrupt is enabled, request STAT interrupt
-        if stat & 0x40 == 0x40 {
+        if line && !self.stat_line {
             mmu.set_interrupt_flag(1);
         }
-    } else {
-        mmu.write_byte(0xFF41, stat & 0xFB);
+        self.stat_line = line;
     }
-}
-
-fn reset_ly(mmu: &mut Mmu) {
-    mmu.write_byte(0xFF44, 0);
-}
 
-fn increment_ly(mmu: &mut Mmu) -> u8 {
-    mmu.increment_ly();
-    mmu.get_ly()
-}
+    /// Returns true while the LCD shows nothing: LCD disabled, or first frame after it was enabled.
+    pub fn is_screen_off(&self) -> bool {
+        !self.lcd_on || self.skip_frame
+    }
 
-fn draw_scanline(frame: &mut [u8], mmu: &mut Mmu) {
-    if lcdc::get_lcdc_n(0, mmu) {
-        draw_bg(frame, mmu);
+    pub fn get_shades(&self) -> &[u8] {
+        &self.shades
     }
 
-    if lcdc::get_lcdc_n(5, mmu) {
-        draw_window(frame, mmu);
+    /// Blanks the screen when LCDC.7 is cleared. The LCD shows color 0 of the DMG palette, or white on CGB.
+    fn turn_off(&mut self, frame: &mut [u8], mmu: &Mmu, palette: &Palette) {
+        self.lcd_on = false;
+        self.mode = Mode::HBlank;
+        self.stat_line = false;
+        self.reset_window();
+        self.shades.fill(0);
+        let white = if mmu.is_cgb() {
+            [0xFF; 4]
+        } else {
+            palette.bg[0]
+        };
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&white);
+        }
     }
 
-    // if lcdc::get_lcdc_n(1, mmu) {
-    //     draw_sprites(frame, mmu);
-    // }
-}
+    /// Restarts the PPU on line 0 when LCDC.7 is set. The first line is 4 dots shorter and skips the OAM scan: STAT
+    /// reads mode 0 until mode 3 starts. Nothing is displayed until the next frame.
+    fn turn_on(&mut self, mmu: &mut Mmu) {
+        self.lcd_on = true;
+        self.lcd_starting = true;
+        self.skip_frame = true;
+        self.mode = Mode::Oam;
+        self.dots = 4;
+        mmu.set_stat_mode_flag(0);
+    }
 
-fn draw_bg(frame: &mut [u8], mmu: &mut Mmu) {
-    let scx = mmu.read_byte(0xFF43);
-    let scy = mmu.read_byte(0xFF42);
-
-    let tile_data = if lcdc::get_lcdc_n(4, mmu) {
-        0x8000
-    } else {
-        0x8800
-    };
-    let background = if lcdc::get_lcdc_n(3, mmu) {
-        0x9C00
-    } else {
-        0x9800
-    };
-
-    let y = mmu.get_ly() + scy;
-
-    let tile_row = (y / 8) as u16 * 32;
-    for x in 0..160 {
-        let x_pos = (x as u8).wrapping_add(scx);
-
-        if x_pos > 159 || mmu.get_ly() > 143 {
-            continue;
+    /// Resets the FIFOs and the fetcher at the start of mode 3.
+    fn start_drawing(&mut self, mmu: &Mmu) {
+        self.lx = 0;
+        self.fetcher = fifo::Fetcher::new();
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.first_fetch = true;
+        self.window_active = false;
+        self.window_drawn = false;
+        if mmu.get_ly() == mmu.read_byte(0xFF4A) {
+            self.window_y_triggered = true;
         }
+        self.sprite_fetch = None;
+        self.last_sprite_tile = None;
+        // SCX fine scroll: the first SCX % 8 pixels of the line are fetched and discarded
+        self.discard = mmu.read_byte(0xFF43) % 8;
+    }
 
-        let tile_col = (x_pos / 8) as u16;
-        let tile_addr = background + tile_row + tile_col;
-        let tile_num = if tile_data == 0x8000 {
-            mmu.read_byte(tile_addr) as u16
-        } else {
-            mmu.read_byte(tile_addr) as i8 as u16
-        };
+    /// Runs one dot of mode 3: fetches background, window and object pixels, and pushes at most one pixel to the LCD.
+    fn draw_dot(
+        &mut self,
+        frame: &mut [u8],
+        mmu: &mut Mmu,
+        palette: &Palette,
+        color_correction: bool,
+    ) {
+        let ly = mmu.get_ly();
+
+        if self.sprite_fetch.is_none() && self.discard == 0 && lcdc::get_lcdc_n(1, mmu) {
+            if let Some(index) = self
+                .sprites
+                .iter()
+                .position(|sprite| sprite.x <= self.lx + 8)
+            {
+                let sprite = self.sprites.remove(index);
+                self.sprite_fetch = Some((sprite, self.sprite_penalty(mmu)));
+            }
+        }
 
-        let tile_loc = if tile_data == 0x8000 {
-            tile_data + tile_num * 16
-        } else {
-            (tile_data as u32 + (tile_num as u32 + 128) * 16) as u16
-        };
+        // The LCD is stalled while an object is fetched. The background fetcher keeps going until it has a tile ready.
+        if let Some((sprite, dots)) = self.sprite_fetch {
+            self.step_fetcher(mmu, ly);
+            if dots > 1 {
+                self.sprite_fetch = Some((sprite, dots - 1));
+            } else {
+                fifo::fetch_sprite(mmu, &sprite, ly, &mut self.obj_fifo);
+                self.sprite_fetch = None;
+            }
+            return;
+        }
 
-        let line = ((y % 8) * 2) as u16;
-        let data1 = mmu.read_byte(tile_loc + line);
-        let data2 = mmu.read_byte(tile_loc + line + 1);
+        if self.window_active && !lcdc::get_lcdc_n(5, mmu) {
+            self.stop_window(mmu);
+        }
 
-        let pixel = x_pos % 8;
+        self.step_fetcher(mmu, ly);
 
-        let color = ((data1 >> (7 - pixel) & 1) << 1) | ((data2 >> (7 - pixel)) & 1);
+        if self.should_start_window(mmu) {
+            self.start_window(mmu);
+            return;
+        }
 
-        draw_color_at_pos(
-            COLORS[mmu.get_background_palette(color)],
-            x,
-            mmu.get_ly(),
-            frame,
-        );
+        self.push_pixel(frame, mmu, palette, color_correction, ly);
     }
-}
 
-fn draw_window(frame: &mut [u8], mmu: &mut Mmu) {
-    let wx = mmu.read_byte(0xFF4B).wrapping_sub(7);
-    let wy = mmu.read_byte(0xFF4A);
-    let scx = mmu.read_byte(0xFF43);
-
-    let ly = mmu.get_ly();
-    if ly < wy {
-        return;
-    }
-
-    let tile_data = if lcdc::get_lcdc_n(4, mmu) {
-        0x8000
-    } else {
-        0x8800
-    };
-    let background = if lcdc::get_lcdc_n(6, mmu) {
-        0x9C00
-    } else {
-        0x9800
-    };
-
-    let y = mmu.get_ly() - wy;
-
-    let tile_row = (y / 8) as u16 * 32;
-    for x in 0..160 {
-        if x < wx {
-            continue;
+    fn should_start_window(&self, mmu: &Mmu) -> bool {
+        if self.window_active || !self.window_y_triggered || !lcdc::get_lcdc_n(5, mmu) {
+            return false;
         }
 
-        let x_pos = if x >= wx { x - wx } else { x + scx };
-
-        if x_pos > 159 {
-            continue;
-        }
+        let wx = mmu.read_byte(0xFF4B);
+        // With WX < 7 the window starts on the first pixel, its first 7 - WX pixels being cut off.
+        // WX = 166 would match the last pixel, but it covers the next line instead, see `end_window_line`
+        (wx != 166 && wx == self.lx + 7) || (self.lx == 0 && (wx < 7 || self.window_full_line))
+    }
 
-        let tile_col = (x_pos / 8) as u16;
-        let tile_addr = background + tile_row + tile_col;
-        let tile_num = if tile_data == 0x8000 {
-            mmu.read_byte(tile_addr) as u16
+    /// Switches the fetcher to the window, the pixels of the background already fetched are dropped.
+    fn start_window(&mut self, mmu: &Mmu) {
+        self.window_active = true;
+        self.window_drawn = true;
+        self.bg_fifo.clear();
+        self.fetcher.start_window();
+        self.discard = if self.lx == 0 && !self.window_full_line {
+            7_u8.saturating_sub(mmu.read_byte(0xFF4B))
         } else {
-            mmu.read_byte(tile_addr) as i8 as u16
+            0
         };
+    }
 
-        let tile_loc = if tile_data == 0x8000 {
-            tile_data + tile_num * 16
-        } else {
-            (tile_data as u32 + (tile_num as u32 + 128) * 16) as u16
-        };
+    /// LCDC.5 was cleared while drawing the window: the fetcher goes back to the background where the LCD is.
+    fn stop_window(&mut self, mmu: &Mmu) {
+        let scx = mmu.read_byte(0xFF43);
+        self.window_active = false;
+        self.bg_fifo.clear();
+        self.fetcher.start_background((self.lx + scx % 8) / 8);
+        self.discard = self.lx.wrapping_add(scx) % 8;
+    }
 
-        let line = ((y % 8) * 2) as u16;
-        let data1 = mmu.read_byte(tile_loc + line);
-        let data2 = mmu.read_byte(tile_loc + line + 1);
+    /// Advances the window line counter if the window was drawn on this line.
+    fn end_window_line(&mut self, mmu: &Mmu) {
+        if self.window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
 
-        let pixel = x_pos % 8;
+        // WX = 166 never matches on this line, but the window then covers the whole next line
+        self.window_full_line =
+            self.window_y_triggered && lcdc::get_lcdc_n(5, mmu) && mmu.read_byte(0xFF4B) == 166;
+    }
 
-        let color = ((data1 >> (7 - pixel) & 1) << 1) | ((data2 >> (7 - pixel)) & 1);
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.window_full_line = false;
+    }
 
-        draw_color_at_pos(
-            COLORS[mmu.get_background_palette(color)],
-            x,
-            mmu.get_ly(),
-            frame,
-        );
+    /// Returns the number of dots the LCD is stalled for an object fetch: 6 dots for the fetch itself, plus the time
+    /// left for the background fetcher to finish the current tile, unless an object was already fetched on this tile.
+    fn sprite_penalty(&mut self, mmu: &Mmu) -> u8 {
+        let position = if self.window_active {
+            (self.lx + 7).wrapping_sub(mmu.read_byte(0xFF4B))
+        } else {
+            self.lx.wrapping_add(mmu.read_byte(0xFF43))
+        };
+
+        let tile = Some(position / 8);
+        if self.last_sprite_tile == tile {
+            return SPRITE_FETCH_DOTS;
+        }
+        self.last_sprite_tile = tile;
+
+        SPRITE_FETCH_DOTS + 5 - (position % 8).min(5)
     }
-}
 
-fn draw_sprites(frame: &mut [u8], mmu: &mut Mmu) {
-    let object_size = if lcdc::get_lcdc_n(2, mmu) { 8 } else { 16 };
-    let object_size = 8;
+    fn step_fetcher(&mut self, mmu: &Mmu, ly: u8) {
+        let line = if self.window_active {
+            self.window_line
+        } else {
+            ly.wrapping_add(mmu.read_byte(0xFF42))
+        };
 
-    mmu.get_oam_slice()
-        .chunks(4)
-        .filter(|&sprite| {
-            let y = match sprite[0].checked_sub(16) {
-                Some(y) => y,
-                None => return false,
-            };
-            y > mmu.get_ly() && y <= mmu.get_ly() + object_size
-        })
-        .for_each(|sprite| {
-            let sprite_y = sprite[0] - 16;
-            let sprite_x = sprite[1] - 8;
-            let bg_over_obj = (sprite[3] >> 7) & 1 == 0;
-            let obp0 = (sprite[3] >> 4) & 1 == 0;
-            let y_flipped = (sprite[3] >> 6) & 1 == 1;
-            let x_flipped = (sprite[3] >> 5) & 1 == 1;
-
-            let tile_index = match object_size {
-                8 => sprite[2] as u16,
-                16 => (sprite[2] & 254) as u16,
-                _ => unreachable!(),
-            };
-            let addr: u16 = 0x8000 + tile_index * 16;
+        self.fetcher.step(mmu, line, &mut self.bg_fifo);
 
-            let current_object_y = sprite_y - mmu.get_ly();
-            //let line = current_object_y as u16 * 2;
+        // The first tile of the line is fetched twice
+        if self.first_fetch && !self.bg_fifo.is_empty() {
+            self.first_fetch = false;
+            self.bg_fifo.clear();
+            self.fetcher.restart_tile();
+        }
+    }
 
-            let line = if y_flipped {
-                (object_size - current_object_y) as u16 * 2
-            } else {
-                current_object_y as u16 * 2
-            };
+    /// Pops one pixel of each FIFO, mixes them and draws the result at (LX, LY).
+    fn push_pixel(
+        &mut self,
+        frame: &mut [u8],
+        mmu: &Mmu,
+        palette: &Palette,
+        color_correction: bool,
+        ly: u8,
+    ) {
+        let bg = match self.bg_fifo.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
 
-            let data1 = mmu.read_byte(addr + line);
-            let data2 = mmu.read_byte(addr + line + 1);
-
-            for x in 0..8 {
-                let color = mmu.get_object_palette(
-                    ((data1 >> (7 - x) & 1) << 1) | ((data2 >> (7 - x)) & 1),
-                    obp0,
-                );
-
-                let index = (mmu.get_ly() as usize * WIDTH + x as usize) * 4;
-                let actual_color = [
-                    frame[index],
-                    frame[index + 1],
-                    frame[index + 2],
-                    frame[index + 3],
-                ];
-
-                if color != 0 {
-                    if bg_over_obj || (!bg_over_obj && actual_color == COLORS[0]) {
-                        draw_color_at_pos(COLORS[color], sprite_x + x, mmu.get_ly(), frame);
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+
+        let cgb = mmu.is_cgb();
+        // With LCDC.0 cleared, the background and the window are blank on DMG.
+        // On CGB they are still drawn, but lose their priority over the objects.
+        let bg_enabled = lcdc::get_lcdc_n(0, mmu);
+        let bg_color = if bg_enabled || cgb { bg.color } else { 0 };
+
+        let index = ly as usize * WIDTH + self.lx as usize;
+        let color = match self.obj_fifo.pop_front() {
+            Some(obj)
+                if obj.color != 0
+                    && (bg_color == 0
+                        || (cgb && !bg_enabled)
+                        || (!obj.bg_priority && !bg.priority)) =>
+            {
+                if cgb {
+                    palette::cgb_color(
+                        mmu.get_cgb_object_color(obj.palette, obj.color),
+                        color_correction,
+                    )
+                } else {
+                    let shade = mmu.get_object_palette(obj.color, !obj.obp1);
+                    self.shades[index] = shade as u8;
+                    if obj.obp1 {
+                        palette.obp1[shade]
+                    } else {
+                        palette.obp0[shade]
                     }
                 }
             }
+            _ if cgb => palette::cgb_color(
+                mmu.get_cgb_background_color(bg.palette, bg_color),
+                color_correction,
+            ),
+            _ => {
+                let shade = mmu.get_background_palette(bg_color);
+                self.shades[index] = shade as u8;
+                palette.bg[shade]
+            }
+        };
+
+        if !self.skip_frame {
+            draw_color_at_pos(color, self.lx, ly, frame);
+        }
+        self.lx += 1;
+    }
+}
+
+impl SaveState for Ppu {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.dots);
+        state.u8(match self.mode {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::Oam => 2,
+            Mode::DrawingPixel => 3,
         });
+        for flag in [
+            self.stat_line,
+            self.lcd_on,
+            self.lcd_starting,
+            self.skip_frame,
+            self.first_fetch,
+            self.window_active,
+            self.window_drawn,
+            self.window_y_triggered,
+            self.window_full_line,
+        ] {
+            state.bool(flag);
+        }
+        state.u8(self.lx);
+        state.u8(self.discard);
+        state.u8(self.window_line);
+        self.fetcher.save(state);
+        state.items(self.bg_fifo.iter());
+        state.items(self.obj_fifo.iter());
+        state.items(self.sprites.iter());
+        state.bool(self.sprite_fetch.is_some());
+        if let Some((sprite, dots)) = &self.sprite_fetch {
+            sprite.save(state);
+            state.u8(*dots);
+        }
+        state.bool(self.last_sprite_tile.is_some());
+        state.u8(self.last_sprite_tile.unwrap_or(0));
+        state.bytes(&self.shades);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.dots = state.u16()?;
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::Oam,
+            3 => Mode::DrawingPixel,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        for flag in [
+            &mut self.stat_line,
+            &mut self.lcd_on,
+            &mut self.lcd_starting,
+            &mut self.skip_frame,
+            &mut self.first_fetch,
+            &mut self.window_active,
+            &mut self.window_drawn,
+            &mut self.window_y_triggered,
+            &mut self.window_full_line,
+        ] {
+            *flag = state.bool()?;
+        }
+        self.lx = state.u8()?;
+        self.discard = state.u8()?;
+        self.window_line = state.u8()?;
+        self.fetcher.load(state)?;
+        self.bg_fifo = state.items()?.into();
+        self.obj_fifo = state.items()?.into();
+        self.sprites = state.items()?;
+        self.sprite_fetch = if state.bool()? {
+            let mut sprite = fifo::Sprite::default();
+            sprite.load(state)?;
+            Some((sprite, state.u8()?))
+        } else {
+            None
+        };
+        let sprite_tile = state.bool()?;
+        let tile = state.u8()?;
+        self.last_sprite_tile = sprite_tile.then_some(tile);
+        if self.lx as usize > WIDTH || self.dots > DOTS_PER_LINE {
+            return Err(StateError::InvalidFormat);
+        }
+        state.bytes_into(&mut self.shades)
+    }
+}
+
+fn reset_ly(mmu: &mut Mmu) {
+    mmu.write_byte(0xFF44, 0);
+}
+
+fn increment_ly(mmu: &mut Mmu) -> u8 {
+    mmu.increment_ly();
+    mmu.get_ly()
 }
 
 fn draw_color_at_pos(color: [u8; 4], x: u8, y: u8, frame: &mut [u8]) {
@@ -349,9 +567,246 @@ fn draw_color_at_pos(color: [u8; 4], x: u8, y: u8, frame: &mut [u8]) {
     frame[index + 3] = color[3]; // A
 }
 
-const COLORS: [[u8; 4]; 4] = [
-    [0xFF, 0xFF, 0xFF, 0xff],
-    [0x55, 0x55, 0x55, 0xff],
-    [0xAA, 0xAA, 0xAA, 0xff],
-    [0x00, 0x00, 0x00, 0xff],
-];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    const STAT_HBLANK: u8 = 0x08;
+    const STAT_OAM: u8 = 0x20;
+    const STAT_LYC: u8 = 0x40;
+
+    /// PPU at the start of line 0 with the LCD on, and the STAT sources of `stat` enabled.
+    fn setup(model: Model, stat: u8, lyc: u8) -> (Ppu, Mmu) {
+        let mut mmu = Mmu::blank(model);
+        mmu.set_access_restrictions(false);
+        mmu.write_byte(0xFF40, 0x91);
+        mmu.write_byte(0xFF45, lyc);
+        mmu.write_byte(0xFF41, stat);
+        mmu.take_stat_write();
+        (Ppu::new(), mmu)
+    }
+
+    /// Runs `dots` dots and returns the number of STAT interrupts requested.
+    fn run(ppu: &mut Ppu, mmu: &mut Mmu, dots: usize) -> usize {
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        let palette = Palette::default();
+        let mut requests = 0;
+        for _ in 0..dots {
+            ppu.update(&mut frame, mmu, &palette, false);
+            if mmu.read_byte(0xFF0F) & 0x02 != 0 {
+                requests += 1;
+                mmu.write_byte(0xFF0F, 0);
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn stat_sources_request_once_per_rising_edge() {
+        let lines = 4 * DOTS_PER_LINE as usize;
+        for stat in [STAT_HBLANK, STAT_OAM] {
+            let (mut ppu, mut mmu) = setup(Model::Dmg, stat, 0xFF);
+            assert_eq!(run(&mut ppu, &mut mmu, lines), 4, "STAT {:02X}", stat);
+        }
+
+        // HBlank hands over to the OAM scan of the next line without the line going low: only the OAM scan of line 0
+        // and the 4 HBlanks request it, instead of 8
+        let (mut ppu, mut mmu) = setup(Model::Dmg, STAT_HBLANK | STAT_OAM, 0xFF);
+        assert_eq!(run(&mut ppu, &mut mmu, lines), 5);
+    }
+
+    #[test]
+    fn lyc_source_blocks_the_following_hblank() {
+        // The line stays high from the HBlank of line 1 to the end of line 2, so the HBlank of line 2 is lost
+        let (mut ppu, mut mmu) = setup(Model::Dmg, STAT_HBLANK | STAT_LYC, 2);
+        assert_eq!(run(&mut ppu, &mut mmu, 4 * DOTS_PER_LINE as usize), 3);
+    }
+
+    #[test]
+    fn stat_write_requests_the_interrupt_on_dmg_only() {
+        for (model, requests) in [(Model::Dmg, 1), (Model::Cgb, 0)] {
+            let (mut ppu, mut mmu) = setup(model, 0, 0xFF);
+            // Into the HBlank of line 0
+            assert_eq!(run(&mut ppu, &mut mmu, 300), 0);
+            assert_eq!(mmu.read_byte(0xFF41) & 0x03, 0);
+
+            mmu.write_byte(0xFF41, 0);
+            assert_eq!(run(&mut ppu, &mut mmu, 1), requests, "{:?}", model);
+            // Only for the cycle of the write
+            assert_eq!(run(&mut ppu, &mut mmu, 10), 0);
+        }
+    }
+
+    /// LCDC with the LCD, the background, the window from the 0x9C00 map and the tiles at 0x8000.
+    const LCDC_WINDOW: u8 = 0xF1;
+
+    /// PPU turning the LCD on with the window at (WX, WY). The background is color 0, the window is made of
+    /// tile 1, whose rows are `rows` as (low, high) bytes.
+    fn setup_window(wy: u8, wx: u8, rows: [(u8, u8); 8]) -> (Ppu, Mmu) {
+        let mut mmu = Mmu::blank(Model::Dmg);
+        mmu.set_access_restrictions(false);
+        mmu.write_byte(0xFF47, 0xE4);
+        for (row, (low, high)) in rows.into_iter().enumerate() {
+            mmu.write_byte(0x8010 + row as u16 * 2, low);
+            mmu.write_byte(0x8011 + row as u16 * 2, high);
+        }
+        for addr in 0x9C00..0xA000 {
+            mmu.write_byte(addr, 1);
+        }
+        mmu.write_byte(0xFF4A, wy);
+        mmu.write_byte(0xFF4B, wx);
+        let ppu = lcd_off(&mut mmu);
+        mmu.write_byte(0xFF40, LCDC_WINDOW);
+        (ppu, mmu)
+    }
+
+    /// PPU which saw the LCD off, so that the next LCDC write turns it on.
+    fn lcd_off(mmu: &mut Mmu) -> Ppu {
+        let mut ppu = Ppu::new();
+        mmu.write_byte(0xFF40, 0x00);
+        run(&mut ppu, mmu, 1);
+        ppu
+    }
+
+    /// Number of dots to run after turning the LCD on for `dot` of line `ly` to be the next one, the first line being
+    /// 4 dots shorter.
+    fn dots_to(ly: usize, dot: usize) -> usize {
+        ly * DOTS_PER_LINE as usize + dot - 4
+    }
+
+    fn line_shades(ppu: &Ppu, ly: usize) -> &[u8] {
+        &ppu.get_shades()[ly * WIDTH..(ly + 1) * WIDTH]
+    }
+
+    /// Shades of a line with color 1 on the first pixel of each window tile, the window starting at `wx` - 7.
+    fn window_tile_starts(wx: u8) -> Vec<u8> {
+        (0..WIDTH as i32)
+            .map(|x| (x >= wx as i32 - 7 && (x + 7 - wx as i32) % 8 == 0) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn window_line_counter_only_counts_the_lines_it_is_drawn_on() {
+        // Row 0 of the window tile is color 3, the other rows color 1
+        let mut rows = [(0xFF, 0x00); 8];
+        rows[0] = (0xFF, 0xFF);
+        let (mut ppu, mut mmu) = setup_window(0, 7, rows);
+
+        // Window hidden on lines 2 to 5
+        run(&mut ppu, &mut mmu, dots_to(1, 300));
+        mmu.write_byte(0xFF40, LCDC_WINDOW & !0x20);
+        run(&mut ppu, &mut mmu, dots_to(5, 300) - dots_to(1, 300));
+        mmu.write_byte(0xFF40, LCDC_WINDOW);
+        run(&mut ppu, &mut mmu, dots_to(12, 300) - dots_to(5, 300));
+
+        for (ly, shade) in [(0, 3), (1, 1), (3, 0), (6, 1), (8, 1), (12, 3)] {
+            assert_eq!(line_shades(&ppu, ly), [shade; WIDTH], "line {}", ly);
+        }
+    }
+
+    #[test]
+    fn window_left_of_the_screen_is_cut() {
+        for wx in [0, 1, 3, 6, 7, 20] {
+            let (mut ppu, mut mmu) = setup_window(0, wx, [(0x80, 0x00); 8]);
+            run(&mut ppu, &mut mmu, dots_to(0, 300));
+            assert_eq!(line_shades(&ppu, 0), window_tile_starts(wx), "WX {}", wx);
+        }
+    }
+
+    #[test]
+    fn window_at_wx_166_covers_the_next_line() {
+        let (mut ppu, mut mmu) = setup_window(0, 166, [(0x80, 0x00); 8]);
+        run(&mut ppu, &mut mmu, dots_to(1, 300));
+        assert_eq!(line_shades(&ppu, 0), [0; WIDTH]);
+        assert_eq!(line_shades(&ppu, 1), window_tile_starts(7));
+    }
+
+    #[test]
+    fn wy_only_matches_at_the_start_of_mode_3() {
+        // Written during mode 3 of line 5: too late for this line, and the following ones don't match
+        let (mut ppu, mut mmu) = setup_window(0xFF, 7, [(0x80, 0x00); 8]);
+        run(&mut ppu, &mut mmu, dots_to(5, 100));
+        mmu.write_byte(0xFF4A, 5);
+        run(&mut ppu, &mut mmu, dots_to(143, 300) - dots_to(5, 100));
+        assert!(ppu.get_shades().iter().all(|shade| *shade == 0));
+
+        // Written during the HBlank of line 4
+        let (mut ppu, mut mmu) = setup_window(0xFF, 7, [(0x80, 0x00); 8]);
+        run(&mut ppu, &mut mmu, dots_to(4, 300));
+        mmu.write_byte(0xFF4A, 5);
+        run(&mut ppu, &mut mmu, dots_to(6, 300) - dots_to(4, 300));
+        assert_eq!(line_shades(&ppu, 4), [0; WIDTH]);
+        assert_eq!(line_shades(&ppu, 5), window_tile_starts(7));
+        assert_eq!(line_shades(&ppu, 6), window_tile_starts(7));
+    }
+
+    fn mode(mmu: &Mmu) -> u8 {
+        mmu.read_byte(0xFF41) & 0x3
+    }
+
+    #[test]
+    fn first_line_after_turning_the_lcd_on_is_shorter() {
+        let mut mmu = Mmu::blank(Model::Dmg);
+        let mut ppu = lcd_off(&mut mmu);
+        mmu.write_byte(0xFF40, 0x91);
+
+        // No OAM scan: STAT reads mode 0 until mode 3 starts at the usual dot
+        run(&mut ppu, &mut mmu, dots_to(0, 80));
+        assert_eq!(mode(&mmu), 0);
+        run(&mut ppu, &mut mmu, 1);
+        assert_eq!(mode(&mmu), 3);
+
+        run(&mut ppu, &mut mmu, dots_to(1, 0) - dots_to(0, 81));
+        assert_eq!(mmu.get_ly(), 0);
+        run(&mut ppu, &mut mmu, 1);
+        assert_eq!(mmu.get_ly(), 1);
+        assert_eq!(mode(&mmu), 2);
+    }
+
+    #[test]
+    fn screen_stays_off_for_the_first_frame() {
+        let mut mmu = Mmu::blank(Model::Dmg);
+        let mut ppu = lcd_off(&mut mmu);
+        mmu.write_byte(0xFF40, 0x91);
+        run(&mut ppu, &mut mmu, 1);
+        assert!(ppu.is_screen_off());
+        run(&mut ppu, &mut mmu, dots_to(144, 0) - 1);
+        assert!(ppu.is_screen_off());
+        run(&mut ppu, &mut mmu, 1);
+        assert_eq!(mmu.get_ly(), 144);
+        assert!(!ppu.is_screen_off());
+    }
+
+    #[test]
+    fn turning_the_lcd_off_blanks_the_screen() {
+        for mut mmu in [Mmu::blank(Model::Dmg), Mmu::blank_cgb()] {
+            let mut ppu = lcd_off(&mut mmu);
+            mmu.write_byte(0xFF40, 0x91);
+            let mut palette = Palette::default();
+            palette.bg[0] = [0xE0, 0xF8, 0xD0, 0xFF];
+            let mut frame = vec![0; WIDTH * HEIGHT * 4];
+            for _ in 0..dots_to(2, 100) {
+                ppu.update(&mut frame, &mut mmu, &palette, false);
+            }
+
+            mmu.write_byte(0xFF40, 0x11);
+            ppu.update(&mut frame, &mut mmu, &palette, false);
+            assert!(ppu.is_screen_off());
+            assert_eq!(mmu.get_ly(), 0);
+            assert_eq!(mode(&mmu), 0);
+            let white = if mmu.is_cgb() {
+                [0xFF; 4]
+            } else {
+                palette.bg[0]
+            };
+            assert!(frame.chunks_exact(4).all(|pixel| pixel == white));
+
+            // Turning it back on starts over from line 0, 4 dots in
+            mmu.write_byte(0xFF40, 0x91);
+            run(&mut ppu, &mut mmu, dots_to(1, 0) + 1);
+            assert_eq!(mmu.get_ly(), 1);
+            assert!(ppu.is_screen_off());
+        }
+    }
+}
@@ -31,7 +31,7 @@ pub fn exectute_interrupts(cpu: &mut Cpu, mmu: &mut Mmu) -> u8 {
         return 20;
     }
 
-    return 0;
+    0
 }
 
 fn reset_interrupt_flag(memory: &mut Mmu, flag: u8) {
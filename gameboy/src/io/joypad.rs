@@ -0,0 +1,133 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const JOYPAD_ADDRESS: u16 = 0xFF00;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Bit of the button in the state of a joypad: directions in the low nibble, then A, B, Select, Start.
+    fn mask(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// P1 register. Bits 4 (P14) and 5 (P15) select the directions or the buttons, the low nibble reads the selected keys,
+/// 0 meaning pressed. An SGB can plug up to 4 joypads, read one after the other.
+pub struct Joypad {
+    /// Pressed buttons of each joypad, see `Button::mask`
+    pressed: [u8; 4],
+    select: u8,
+    /// Number of joypads read, 1, 2 or 4
+    players: u8,
+    player: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self {
+            pressed: [0; 4],
+            select: 0x30,
+            players: 1,
+            player: 0,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn read(&self) -> u8 {
+        let pressed = self.pressed[self.player as usize];
+        let mut keys = 0;
+        if self.select & 0x10 == 0 {
+            keys |= pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            keys |= pressed >> 4;
+        }
+
+        // With multiple joypads and nothing selected, the low nibble reads the ID of the current joypad
+        if self.players > 1 && self.select == 0x30 {
+            return 0xC0 | self.select | (0x0F - self.player);
+        }
+
+        0xC0 | self.select | (!keys & 0x0F)
+    }
+
+    /// Selects the keys to read.
+    pub fn write(&mut self, val: u8) {
+        self.select = val & 0x30;
+    }
+
+    /// Sets the number of joypads and the one being read, both driven by the SGB.
+    pub fn set_player(&mut self, players: u8, player: u8) {
+        self.players = players;
+        self.player = player;
+    }
+
+    /// Updates the state of `button` on the joypad of `player` (0-3).
+    /// Returns true if the joypad interrupt must be requested: a selected key went from released to pressed.
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) -> bool {
+        let previous = self.pressed[player];
+        if pressed {
+            self.pressed[player] |= button.mask();
+        } else {
+            self.pressed[player] &= !button.mask();
+        }
+
+        let selected = if button.mask() & 0x0F != 0 {
+            self.select & 0x10 == 0
+        } else {
+            self.select & 0x20 == 0
+        };
+        pressed && previous & button.mask() == 0 && selected
+    }
+
+    /// Pressed buttons of `player`, one bit per button in the order of `Button::ALL`.
+    pub fn get_pressed(&self, player: usize) -> u8 {
+        self.pressed[player]
+    }
+
+    pub fn is_pressed(&self, player: usize, button: Button) -> bool {
+        self.pressed[player] & button.mask() != 0
+    }
+}
+
+impl SaveState for Joypad {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.pressed);
+        state.u8(self.select);
+        state.u8(self.players);
+        state.u8(self.player);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.pressed)?;
+        self.select = state.u8()? & 0x30;
+        self.players = state.u8()?;
+        self.player = state.u8()?;
+        if !matches!(self.players, 1 | 2 | 4) || self.player >= self.players {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,3 @@
 pub mod interrupts;
+pub mod joypad;
 pub mod timer;
@@ -1,83 +1,298 @@
-use crate::memory::mmu::Mmu;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
-const DIV_LOW_ADDRESS: u16 = 0xFF03;
-const DIV_HIGH_ADDRESS: u16 = 0xFF04;
-const TIMA_ADDRESS: u16 = 0xFF05;
-const TMA_ADDRESS: u16 = 0xFF06;
-const TAC_ADDRESS: u16 = 0xFF07;
+pub const DIV_ADDRESS: u16 = 0xFF04;
+pub const TIMA_ADDRESS: u16 = 0xFF05;
+pub const TMA_ADDRESS: u16 = 0xFF06;
+pub const TAC_ADDRESS: u16 = 0xFF07;
 
-pub fn update(mmu: &mut Mmu) {
-    increment_divder(mmu);
+/// Number of T-cycles between the TIMA overflow and the reload from TMA (one M-cycle).
+const RELOAD_DELAY: u8 = 4;
 
-    if !is_timer_enable(mmu) {
-        return;
+/// DIV/TIMA timer. Both are driven by a 16-bit system counter incremented every T-cycle, DIV being its upper byte.
+/// TIMA is incremented on the falling edge of `counter bit selected by TAC AND timer enable`, which is why writing
+/// to DIV or TAC can increment TIMA.
+#[derive(Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    /// T-cycles left before TIMA is reloaded from TMA. TIMA reads 0 in the meantime.
+    reload_delay: u8,
+    /// T-cycles left in the M-cycle during which TIMA has just been reloaded.
+    reloading: u8,
+}
+
+impl Timer {
+    /// Advances the timer by one T-cycle. Returns true if the timer interrupt must be requested.
+    pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        self.reloading = self.reloading.saturating_sub(1);
+
+        if self.reload_delay > 0 {
+            self.reload_delay -= 1;
+            if self.reload_delay == 0 {
+                self.tima = self.tma;
+                self.reloading = RELOAD_DELAY;
+                interrupt = true;
+            }
+        }
+
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(1);
+        self.detect_falling_edge(signal);
+
+        interrupt
     }
 
-    tick(mmu);
-}
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DIV_ADDRESS => (self.counter >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            TAC_ADDRESS => self.tac | 0xF8,
+            _ => unreachable!("{:04X} is not a timer register", addr),
+        }
+    }
 
-fn tick(mmu: &mut Mmu) {
-    let div_counter: u16 = u16::from_be_bytes([
-        mmu.read_byte(DIV_HIGH_ADDRESS),
-        mmu.read_byte(DIV_LOW_ADDRESS),
-    ]);
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            DIV_ADDRESS => {
+                // Any write resets the whole system counter
+                let signal = self.signal();
+                self.counter = 0;
+                self.detect_falling_edge(signal);
+            }
+            TIMA_ADDRESS => {
+                // Writing during the reload M-cycle is ignored, TMA wins
+                if self.reloading > 0 {
+                    return;
+                }
+                // Writing before the reload cancels it, and the interrupt with it
+                self.reload_delay = 0;
+                self.tima = val;
+            }
+            TMA_ADDRESS => {
+                self.tma = val;
+                // TIMA is still being loaded from TMA, so the new value goes through
+                if self.reloading > 0 {
+                    self.tima = val;
+                }
+            }
+            TAC_ADDRESS => {
+                let signal = self.signal();
+                self.tac = val & 0x7;
+                self.detect_falling_edge(signal);
+            }
+            _ => unreachable!("{:04X} is not a timer register", addr),
+        }
+    }
 
-    let tac_frequency = get_tac_frequency(mmu);
-    if div_counter & tac_frequency != 0 && div_counter - 1 & tac_frequency == 0 {
-        reset_divider(mmu);
-        increment_tima(mmu);
+    /// Returns the internal 16-bit system counter.
+    pub fn get_counter(&self) -> u16 {
+        self.counter
     }
-}
 
-/// Returns true if the timer is enabled, false otherwise.
-fn is_timer_enable(mmu: &mut Mmu) -> bool {
-    mmu.read_byte(TAC_ADDRESS) & 4 == 4
-}
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    /// Increments TIMA if the timer signal went from 1 to 0.
+    fn detect_falling_edge(&mut self, previous_signal: bool) {
+        if previous_signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    /// Increment the TIMA register. On overflow TIMA stays at 0 for one M-cycle, then is reloaded from TMA.
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
 
-/// Increment the divider register. The divider register is on 2 bytes. When the lower byte overflow, the higher byte is incremented.
-fn increment_divder(mmu: &mut Mmu) {
-    let div_low = mmu.read_byte(DIV_LOW_ADDRESS);
+        if overflow {
+            self.reload_delay = RELOAD_DELAY;
+        }
+    }
 
-    if div_low == 255 {
-        mmu.reset_div_low();
-        mmu.increment_div_high();
-        return;
+    /// Returns true if the timer is enabled and the counter bit selected by TAC is set.
+    fn signal(&self) -> bool {
+        self.tac & 4 == 4 && self.counter & self.get_tac_frequency_mask() != 0
     }
 
-    mmu.increment_div_low();
+    /// Returns the bit of the system counter selected by TAC.
+    /// Bits 1-0 - Input Clock Select
+    /// 00: CPU Clock / 1024 (DMG, SGB2, CGB Single Speed Mode:   4096 Hz, SGB1:   ~4194 Hz, CGB Double Speed Mode:   8192 Hz)
+    /// 01: CPU Clock / 16   (DMG, SGB2, CGB Single Speed Mode: 262144 Hz, SGB1: ~268400 Hz, CGB Double Speed Mode: 524288 Hz)
+    /// 10: CPU Clock / 64   (DMG, SGB2, CGB Single Speed Mode:  65536 Hz, SGB1:  ~67110 Hz, CGB Double Speed Mode: 131072 Hz)
+    /// 11: CPU Clock / 256  (DMG, SGB2, CGB Single Speed Mode:  16384 Hz, SGB1:  ~16780 Hz, CGB Double Speed Mode:  32768 Hz)
+    fn get_tac_frequency_mask(&self) -> u16 {
+        match self.tac & 0x3 {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            3 => 1 << 7,
+            _ => unreachable!(),
+        }
+    }
 }
 
-/// Reset the divider register. The divider register is on 2 bytes, so both bytes are set to 0.
-fn reset_divider(mmu: &mut Mmu) {
-    mmu.reset_div_low();
-    mmu.reset_div_high();
+impl SaveState for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.counter);
+        state.u8(self.tima);
+        state.u8(self.tma);
+        state.u8(self.tac);
+        state.u8(self.reload_delay);
+        state.u8(self.reloading);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.u16()?;
+        self.tima = state.u8()?;
+        self.tma = state.u8()?;
+        self.tac = state.u8()?;
+        self.reload_delay = state.u8()?;
+        self.reloading = state.u8()?;
+        Ok(())
+    }
 }
 
-/// Increment the TIMA register. If the TIMA register overflow, the TMA register is copied to the TIMA register and the interrupt flag is set.
-fn increment_tima(mmu: &mut Mmu) {
-    let tima = mmu.read_byte(TIMA_ADDRESS);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TIMA incremented every 16 T-cycles, on the falling edge of bit 3.
+    const TAC_16: u8 = 0x05;
 
-    if tima == 255 {
-        mmu.write_byte(TIMA_ADDRESS, mmu.read_byte(TMA_ADDRESS));
-        mmu.set_interrupt_flag(2);
-        return;
+    fn setup(tac: u8, counter: u16) -> Timer {
+        let mut timer = Timer::default();
+        timer.write(TAC_ADDRESS, tac);
+        timer.set_counter(counter);
+        timer
     }
 
-    mmu.write_byte(TIMA_ADDRESS, tima + 1);
-}
+    /// Runs `cycles` T-cycles and returns the number of interrupts requested.
+    fn run(timer: &mut Timer, cycles: usize) -> usize {
+        (0..cycles).filter(|_| timer.tick()).count()
+    }
+
+    /// Timer whose TIMA has just overflowed, with TMA = 0x42.
+    fn overflowed() -> Timer {
+        let mut timer = setup(TAC_16, 15);
+        timer.write(TIMA_ADDRESS, 0xFF);
+        timer.write(TMA_ADDRESS, 0x42);
+        assert_eq!(run(&mut timer, 1), 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        timer
+    }
+
+    #[test]
+    fn div_is_the_upper_byte_of_the_system_counter() {
+        let mut timer = setup(0, 0);
+        run(&mut timer, 0x1FF);
+        assert_eq!(timer.read(DIV_ADDRESS), 0x01);
+        assert_eq!(timer.get_counter(), 0x01FF);
+
+        timer.write(DIV_ADDRESS, 0x55);
+        assert_eq!(timer.read(DIV_ADDRESS), 0);
+        assert_eq!(timer.get_counter(), 0);
+    }
+
+    #[test]
+    fn tima_increments_on_the_falling_edge_of_the_selected_bit() {
+        let mut timer = setup(TAC_16, 0);
+        run(&mut timer, 15);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        run(&mut timer, 1);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+        run(&mut timer, 16 * 3);
+        assert_eq!(timer.read(TIMA_ADDRESS), 4);
+
+        // Bit 9 falls every 1024 T-cycles
+        let mut timer = setup(0x04, 0);
+        run(&mut timer, 1023);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        run(&mut timer, 1);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn disabled_timer_does_not_increment_tima() {
+        let mut timer = setup(0x01, 0);
+        run(&mut timer, 1024);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_m_cycle_later() {
+        let mut timer = overflowed();
+        assert_eq!(run(&mut timer, 3), 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        assert_eq!(run(&mut timer, 1), 1);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn tima_write_before_the_reload_cancels_it() {
+        let mut timer = overflowed();
+        run(&mut timer, 2);
+        timer.write(TIMA_ADDRESS, 0x10);
+        assert_eq!(run(&mut timer, 4), 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x10);
+    }
+
+    #[test]
+    fn tima_write_during_the_reload_is_ignored() {
+        let mut timer = overflowed();
+        run(&mut timer, 4);
+        timer.write(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x42);
+
+        // Accepted again once the reload M-cycle is over
+        run(&mut timer, 4);
+        timer.write(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x10);
+    }
+
+    #[test]
+    fn tma_write_during_the_reload_goes_to_tima() {
+        let mut timer = overflowed();
+        run(&mut timer, 4);
+        timer.write(TMA_ADDRESS, 0x77);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x77);
+
+        run(&mut timer, 4);
+        timer.write(TMA_ADDRESS, 0x88);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x77);
+        assert_eq!(timer.read(TMA_ADDRESS), 0x88);
+    }
+
+    #[test]
+    fn div_write_increments_tima_when_the_selected_bit_is_set() {
+        let mut timer = setup(TAC_16, 0x08);
+        timer.write(DIV_ADDRESS, 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+
+        let mut timer = setup(TAC_16, 0x07);
+        timer.write(DIV_ADDRESS, 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+    }
+
+    #[test]
+    fn tac_write_increments_tima_when_the_signal_falls() {
+        // Disabling the timer while the selected bit is set
+        let mut timer = setup(TAC_16, 0x08);
+        timer.write(TAC_ADDRESS, 0x01);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+
+        // Selecting a bit which is clear while the selected one is set
+        let mut timer = setup(TAC_16, 0x08);
+        timer.write(TAC_ADDRESS, 0x06);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
 
-/// Returns the frequency of the timer.
-/// Bits 1-0 - Input Clock Select
-/// 00: CPU Clock / 1024 (DMG, SGB2, CGB Single Speed Mode:   4096 Hz, SGB1:   ~4194 Hz, CGB Double Speed Mode:   8192 Hz)
-/// 01: CPU Clock / 16   (DMG, SGB2, CGB Single Speed Mode: 262144 Hz, SGB1: ~268400 Hz, CGB Double Speed Mode: 524288 Hz)
-/// 10: CPU Clock / 64   (DMG, SGB2, CGB Single Speed Mode:  65536 Hz, SGB1:  ~67110 Hz, CGB Double Speed Mode: 131072 Hz)
-/// 11: CPU Clock / 256  (DMG, SGB2, CGB Single Speed Mode:  16384 Hz, SGB1:  ~16780 Hz, CGB Double Speed Mode:  32768 Hz)
-fn get_tac_frequency(mmu: &mut Mmu) -> u16 {
-    match mmu.read_byte(TAC_ADDRESS) & 0x3 {
-        0 => 512,
-        1 => 8,
-        2 => 32,
-        3 => 128,
-        _ => unreachable!(),
+        // Selecting a bit which is also set
+        let mut timer = setup(TAC_16, 0x28);
+        timer.write(TAC_ADDRESS, 0x06);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
     }
 }
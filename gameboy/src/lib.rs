@@ -1,60 +1,819 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use io::joypad::Button;
+use movie::{Movie, MovieError, MovieFrame, MovieMode, MovieSession};
+use state::SaveState;
 
 pub mod cpu;
 mod gpu;
 pub mod io;
 pub mod memory;
+pub mod model;
+pub mod movie;
+pub mod rewind;
+pub mod sgb;
+pub mod state;
+
+pub use gpu::{colorization, palette};
+pub use model::Model;
 
 const CLOCK_SPEED: u32 = 4_194_304;
+/// 154 lines of 456 dots
+const DOTS_PER_FRAME: u64 = 70_224;
+/// Frames per second of the LCD, about 59.73.
+pub const REFRESH_RATE: f64 = CLOCK_SPEED as f64 / DOTS_PER_FRAME as f64;
+
+/// Why a run stopped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    /// The PPU entered VBlank, the frame is complete
+    FrameDone,
+    /// The CPU is about to execute the instruction at this address, which has a breakpoint
+    Breakpoint(u16),
+    /// The CPU executed an illegal opcode and hung. Running further only advances the rest of the hardware
+    Lockup,
+    /// The instruction has been executed
+    InstructionDone,
+    /// All the requested cycles have been run
+    CyclesDone,
+    /// The predicate of `run_until` returned true
+    PredicateMet,
+}
+
+/// What happened during a CPU cycle.
+#[derive(Default)]
+struct Tick {
+    /// The CPU finished an instruction or an interrupt dispatch
+    instruction_done: bool,
+    frame_done: bool,
+}
 
 pub struct GameBoy {
     pub cpu: cpu::cpu::Cpu,
+    model: Model,
     ppu: gpu::ppu::Ppu,
     pub mmu: memory::mmu::Mmu,
     cycles: u8,
+    /// Dots run since the rom was loaded
+    elapsed_cycles: u64,
+    /// CPU cycles run since the rom was loaded, two per dot in CGB double speed
+    cpu_cycles: u64,
+    /// In CGB double speed, the first CPU cycle of the current dot has run but not the second one
+    half_dot: bool,
+    /// Frames completed since the rom was loaded
+    frames: u64,
+    /// The last frame completed without reading the joypad
+    lag_frame: bool,
+    /// Frames completed without reading the joypad since the rom was loaded
+    lag_frames: u64,
+    pub rewind: rewind::Rewind,
+    /// The latest rewind snapshot is the state on screen, going back to it would not move
+    snapshot_on_screen: bool,
+    movie: Option<MovieSession>,
+    /// Addresses the runs stop at before executing the instruction there
+    pub breakpoints: BTreeSet<u16>,
+    tracer: Option<cpu::trace::Tracer>,
     pub debug_paused: bool,
-    pub speed: u32,
+    pub palette: palette::Palette,
+    /// Approximate the colors of the CGB LCD
+    pub color_correction: bool,
+    /// Fill the RAM with a random pattern on power on and rom load, as the hardware does, instead of zeros
+    pub random_ram: bool,
+    /// Buttons held by the player on each joypad, one bit per button in the order of `Button::ALL`
+    held: [u8; 4],
+    /// Buttons held in turbo mode, which are pressed and released every `turbo_frames` frames
+    turbo: [u8; 4],
+    /// Frames a turbo button stays pressed, then released: 1 for 30 presses per second, 2 for 15
+    pub turbo_frames: u32,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
 }
 
 impl GameBoy {
-    pub fn new() -> Self {
+    /// Creates a gameboy without a rom, which starts with the boot rom of `model`.
+    pub fn new(model: Model) -> Self {
+        Self::from_mmu(memory::mmu::Mmu::from_model(model))
+    }
+
+    fn from_mmu(mmu: memory::mmu::Mmu) -> Self {
         Self {
-            cpu: cpu::cpu::Cpu::new(),
+            cpu: boot_cpu(&mmu),
+            model: mmu.get_model(),
             ppu: gpu::ppu::Ppu::new(),
-            mmu: memory::mmu::Mmu::default(),
+            palette: boot_palette(&mmu).unwrap_or_default(),
+            mmu,
             cycles: 0,
+            elapsed_cycles: 0,
+            cpu_cycles: 0,
+            half_dot: false,
+            frames: 0,
+            lag_frame: false,
+            lag_frames: 0,
+            rewind: rewind::Rewind::default(),
+            snapshot_on_screen: false,
+            movie: None,
+            breakpoints: BTreeSet::new(),
+            tracer: None,
             debug_paused: false,
-            speed: 1,
+            color_correction: true,
+            random_ram: false,
+            held: [0; 4],
+            turbo: [0; 4],
+            turbo_frames: 2,
+        }
+    }
+
+    /// Creates a gameboy which starts directly at 0x0100 with the rom loaded, as if the boot rom of `model` had just
+    /// finished.
+    pub fn without_bios(rom_path: PathBuf, model: Model) -> std::io::Result<Self> {
+        let mmu = memory::mmu::Mmu::without_bios(rom_path, model)?;
+        Ok(Self::from_mmu(mmu))
+    }
+
+    /// Runs until the PPU enters VBlank, leaving the finished frame in `frame`. While the LCD is off, the run returns
+    /// once a frame worth of time has passed with a blank frame, which counts as a frame like the drawn ones.
+    pub fn run_frame(&mut self, frame: &mut [u8]) -> StopReason {
+        let start = self.elapsed_cycles;
+        let mut lcd_idle = false;
+        let reason = self.run(frame, |gameboy, tick| {
+            lcd_idle = !tick.frame_done
+                && gameboy.is_screen_off()
+                && gameboy.elapsed_cycles - start >= DOTS_PER_FRAME;
+            (tick.frame_done || lcd_idle).then_some(StopReason::FrameDone)
+        });
+
+        if lcd_idle && reason == StopReason::FrameDone {
+            frame.fill(0xFF);
+            self.end_frame(frame);
+        }
+        reason
+    }
+
+    /// Runs until the next instruction or interrupt dispatch has been executed, leaving the CPU ready to fetch the
+    /// following one. Gives up after one second of emulated time if the CPU never leaves HALT.
+    pub fn step_instruction(&mut self, frame: &mut [u8]) -> StopReason {
+        self.run_cycles_until(CLOCK_SPEED as u64, frame, |_, tick| {
+            tick.instruction_done.then_some(StopReason::InstructionDone)
+        })
+    }
+
+    /// Runs `cycles` CPU cycles, which last half a dot in CGB double speed mode.
+    pub fn run_cycles(&mut self, cycles: u64, frame: &mut [u8]) -> StopReason {
+        self.run_cycles_until(cycles, frame, |_, _| None)
+    }
+
+    /// Runs until `predicate` returns true, checking it after every CPU cycle.
+    pub fn run_until(
+        &mut self,
+        frame: &mut [u8],
+        mut predicate: impl FnMut(&GameBoy) -> bool,
+    ) -> StopReason {
+        self.run(frame, |gameboy, _| {
+            predicate(gameboy).then_some(StopReason::PredicateMet)
+        })
+    }
+
+    /// Runs at most `cycles` CPU cycles, until `stop` returns a reason.
+    fn run_cycles_until(
+        &mut self,
+        cycles: u64,
+        frame: &mut [u8],
+        mut stop: impl FnMut(&GameBoy, &Tick) -> Option<StopReason>,
+    ) -> StopReason {
+        if cycles == 0 {
+            return StopReason::CyclesDone;
+        }
+
+        let mut remaining = cycles;
+        self.run(frame, |gameboy, tick| {
+            remaining -= 1;
+            stop(gameboy, tick).or((remaining == 0).then_some(StopReason::CyclesDone))
+        })
+    }
+
+    /// Runs CPU cycles until `stop` returns a reason, the CPU reaches a breakpoint or locks up.
+    fn run(
+        &mut self,
+        frame: &mut [u8],
+        mut stop: impl FnMut(&GameBoy, &Tick) -> Option<StopReason>,
+    ) -> StopReason {
+        loop {
+            let locked = self.cpu.locked;
+            let tick = self.tick(frame);
+
+            if !locked && self.cpu.locked {
+                return StopReason::Lockup;
+            }
+            if tick.instruction_done && self.breakpoints.contains(&self.cpu.pc) {
+                return StopReason::Breakpoint(self.cpu.pc);
+            }
+            if let Some(reason) = stop(self, &tick) {
+                return reason;
+            }
         }
     }
 
-    pub fn cycle(&mut self, frame: &mut [u8], fps: u32) {
-        if self.debug_paused {
+    /// Runs a single CPU cycle, and the PPU for a dot once the dot is complete.
+    /// In CGB double speed mode, the CPU and the timer run two cycles per dot while the PPU keeps its pace.
+    fn tick(&mut self, frame: &mut [u8]) -> Tick {
+        let mut tick = Tick {
+            instruction_done: self.tick_cpu(),
+            frame_done: false,
+        };
+
+        if self.mmu.is_double_speed() {
+            self.half_dot = !self.half_dot;
+            if self.half_dot {
+                return tick;
+            }
+        } else {
+            self.half_dot = false;
+        }
+
+        // The LCD stops along with the CPU in STOP mode
+        if self.cpu.stopped {
+            self.elapsed_cycles += 1;
+            return tick;
+        }
+
+        self.mmu.set_cpu_halted(self.cpu.halt);
+        tick.frame_done =
+            self.ppu
+                .update(frame, &mut self.mmu, &self.palette, self.color_correction);
+        self.elapsed_cycles += 1;
+        // Last, so that the snapshots taken at the end of the frame hold the whole dot
+        if tick.frame_done {
+            if let Some(sgb) = self.mmu.get_sgb_mut() {
+                sgb.end_frame(self.ppu.get_shades());
+            }
+            self.end_frame(frame);
+        }
+
+        tick
+    }
+
+    /// Counts the frame, toggles the turbo buttons, moves the movie to the next frame and takes a rewind snapshot every
+    /// `rewind.interval` frames.
+    fn end_frame(&mut self, frame: &[u8]) {
+        self.frames += 1;
+        self.lag_frame = !self.mmu.take_joypad_polled();
+        self.lag_frames += self.lag_frame as u64;
+        if self.turbo.iter().any(|turbo| *turbo != 0) {
+            (0..4).for_each(|player| self.update_joypad(player));
+        }
+        self.end_movie_frame();
+        self.snapshot_on_screen = self.rewind.enabled
+            && self
+                .frames
+                .is_multiple_of(self.rewind.interval.max(1) as u64);
+        if self.snapshot_on_screen {
+            let snapshot = self.save_state(frame);
+            self.rewind.push(snapshot);
+        }
+    }
+
+    /// Starts recording the joypads into a movie, from power-on or from the current state.
+    pub fn record_movie(&mut self, from_power_on: bool, frame: &[u8]) {
+        self.finish_movie();
+
+        let mut ram_seed = None;
+        let start_state = if from_power_on {
+            ram_seed = self.random_ram.then(random_seed);
+            let mmu = self.mmu.power_cycle(self.model);
+            self.restart(mmu, ram_seed);
+            None
+        } else {
+            Some(self.save_state(frame))
+        };
+
+        let movie = Movie {
+            rom_checksum: self.mmu.get_rom_checksum(),
+            model: self.mmu.get_model(),
+            boot_rom_checksum: self.mmu.get_boot_rom_checksum(),
+            access_restrictions: self.mmu.is_access_restricted(),
+            ram_seed,
+            start_state,
+            frames: Vec::new(),
+        };
+        let joypad = self.mmu.get_joypad();
+        let input = [0, 1, 2, 3].map(|player| joypad.get_pressed(player));
+        self.movie = Some(MovieSession::new(
+            movie,
+            MovieMode::Recording,
+            self.frames,
+            input,
+        ));
+        self.start_movie_frame();
+    }
+
+    /// Replays a movie from its start. The joypads of the player are ignored until the end of the movie.
+    pub fn play_movie(&mut self, movie: Movie, frame: &mut [u8]) -> Result<(), MovieError> {
+        if movie.rom_checksum != self.mmu.get_rom_checksum() {
+            return Err(MovieError::WrongRom);
+        }
+        self.movie = None;
+
+        match &movie.start_state {
+            Some(state) => {
+                self.load_state(state, frame).map_err(MovieError::State)?;
+                self.mmu.set_access_restrictions(movie.access_restrictions);
+            }
+            None => {
+                let mut mmu = self.mmu.power_cycle(movie.model);
+                mmu.set_access_restrictions(movie.access_restrictions);
+                if mmu.get_boot_rom_checksum() != movie.boot_rom_checksum {
+                    return Err(MovieError::WrongBootRom);
+                }
+                self.model = movie.model;
+                self.restart(mmu, movie.ram_seed);
+            }
+        }
+
+        self.movie = Some(MovieSession::new(
+            movie,
+            MovieMode::Playing,
+            self.frames,
+            [0; 4],
+        ));
+        self.start_movie_frame();
+        Ok(())
+    }
+
+    /// Stops recording or playing the movie and returns it. A recording keeps the frames completed so far.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.finish_movie();
+        self.movie.take().map(|session| session.movie)
+    }
+
+    pub fn get_movie(&self) -> Option<&MovieSession> {
+        self.movie.as_ref()
+    }
+
+    /// Gives access to the frames of the movie, to edit them. The frames before the current one have already been run.
+    pub fn get_movie_mut(&mut self) -> Option<&mut MovieSession> {
+        self.movie.as_mut()
+    }
+
+    /// Returns the frame of the movie being run.
+    pub fn get_movie_frame(&self) -> Option<u64> {
+        let session = self.movie.as_ref()?;
+        self.frames.checked_sub(session.get_start_frame())
+    }
+
+    /// Ends the recording or the playback, dropping the frame of the recording which is not complete.
+    fn finish_movie(&mut self) {
+        let session = match self.movie.as_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        if session.get_mode() == MovieMode::Recording {
+            let completed = self.frames.saturating_sub(session.get_start_frame()) as usize;
+            session.movie.frames.truncate(completed);
+        }
+        session.set_mode(MovieMode::Finished);
+    }
+
+    /// Records the hash of the state at the end of a movie frame, or checks it against the recorded one, then moves
+    /// to the next frame.
+    fn end_movie_frame(&mut self) {
+        if !self
+            .movie
+            .as_ref()
+            .is_some_and(|session| session.get_mode() != MovieMode::Finished)
+        {
             return;
         }
 
-        let mut cycles = 0;
-        for _ in 0..(CLOCK_SPEED / fps) * self.speed {
-            if !self.cpu.get_halt(&mut self.mmu) && cycles == 0 {
-                cycles = self.cpu.cycle(&mut self.mmu);
+        let mut state = state::StateWriter::default();
+        self.save_machine(&mut state);
+        let hash = movie::hash(&state.into_bytes());
+        if let Some(session) = self.movie.as_mut() {
+            let index = self.frames.saturating_sub(session.get_start_frame() + 1) as usize;
+            match session.get_mode() {
+                MovieMode::Recording => {
+                    if let Some(frame) = session.movie.frames.get_mut(index) {
+                        frame.hash = hash;
+                    }
+                }
+                MovieMode::Playing => session.check(index, hash),
+                MovieMode::Finished => (),
+            }
+        }
+
+        self.start_movie_frame();
+    }
+
+    /// Sets the joypads for the current frame of the movie: the buttons held by the player when recording, which
+    /// replace the frames recorded after this one, or the recorded buttons when playing. Playback past the end records
+    /// if the session extends the movie.
+    fn start_movie_frame(&mut self) {
+        let session = match self.movie.as_mut() {
+            Some(session) if session.get_mode() != MovieMode::Finished => session,
+            _ => return,
+        };
+        let index = match self.frames.checked_sub(session.get_start_frame()) {
+            Some(index) => index as usize,
+            None => {
+                session.set_mode(MovieMode::Finished);
+                return;
+            }
+        };
+
+        let input = if session.get_mode() == MovieMode::Recording {
+            let input = session.get_input();
+            session.movie.frames.truncate(index);
+            session.movie.frames.push(MovieFrame { input, hash: 0 });
+            input
+        } else {
+            match session.movie.frames.get(index) {
+                Some(frame) => frame.input,
+                None if session.extend => {
+                    session.set_mode(MovieMode::Recording);
+                    let input = session.get_input();
+                    session.movie.frames.truncate(index);
+                    session.movie.frames.push(MovieFrame { input, hash: 0 });
+                    input
+                }
+                None => {
+                    session.set_mode(MovieMode::Finished);
+                    return;
+                }
+            }
+        };
+
+        for (player, pressed) in input.into_iter().enumerate() {
+            for (bit, button) in Button::ALL.into_iter().enumerate() {
+                self.mmu
+                    .set_button(player, button, pressed & (1 << bit) != 0);
             }
+        }
+    }
 
-            cycles += io::interrupts::exectute_interrupts(&mut self.cpu, &mut self.mmu);
-            self.ppu.update(frame, &mut self.mmu);
-            io::timer::update(&mut self.mmu);
-            cycles = cycles.checked_sub(1).unwrap_or(0);
+    /// Continues the movie from the frame of a loaded state. A recording keeps the joypads of the state for this frame,
+    /// as they were applied over the previous frame, and records over the following frames.
+    fn resume_movie(&mut self) {
+        let session = match self.movie.as_mut() {
+            Some(session) if session.get_mode() == MovieMode::Recording => session,
+            _ => return self.start_movie_frame(),
+        };
+
+        match self.frames.checked_sub(session.get_start_frame()) {
+            Some(index) => {
+                let joypad = self.mmu.get_joypad();
+                let input = [0, 1, 2, 3].map(|player| joypad.get_pressed(player));
+                session.movie.frames.truncate(index as usize);
+                session.movie.frames.push(MovieFrame { input, hash: 0 });
+            }
+            None => session.set_mode(MovieMode::Finished),
         }
     }
 
+    /// Steps back `rewind.rate` snapshots in the rewind history. Returns false once the history is exhausted.
+    pub fn rewind(&mut self, frame: &mut [u8]) -> bool {
+        if std::mem::take(&mut self.snapshot_on_screen) {
+            self.rewind.pop();
+        }
+
+        let mut snapshot = None;
+        for _ in 0..self.rewind.rate.max(1) {
+            match self.rewind.pop() {
+                Some(popped) => snapshot = Some(popped),
+                None => break,
+            }
+        }
+
+        match snapshot {
+            Some(snapshot) => self.load_snapshot(&snapshot, frame),
+            None => false,
+        }
+    }
+
+    /// Goes back to snapshot `index` of the rewind history, 0 being the oldest. The newer snapshots are kept until
+    /// the game runs again.
+    pub fn rewind_seek(&mut self, index: usize, frame: &mut [u8]) -> bool {
+        match self.rewind.seek(index) {
+            Some(snapshot) => {
+                self.snapshot_on_screen = self.load_snapshot(&snapshot, frame);
+                self.snapshot_on_screen
+            }
+            None => false,
+        }
+    }
+
+    fn load_snapshot(&mut self, snapshot: &[u8], frame: &mut [u8]) -> bool {
+        if let Err(e) = self.load_state(snapshot, frame) {
+            println!("Error: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Runs a single CPU cycle: instruction fetch, interrupts and timer. Returns true if an instruction or an
+    /// interrupt dispatch finished during this cycle.
+    fn tick_cpu(&mut self) -> bool {
+        self.cpu_cycles += 1;
+        if self.cpu.stopped {
+            // STOP mode ends when one of the selected buttons is pressed, nothing runs until then
+            if self.mmu.get_joypad().read() & 0x0F == 0x0F {
+                return false;
+            }
+            self.cpu.stopped = false;
+        }
+        // A VRAM DMA stops the CPU between two instructions, a lockup for good
+        if (self.cycles == 0 && self.mmu.stall_cpu()) || self.cpu.locked {
+            self.mmu.tick_timer();
+            return false;
+        }
+
+        if !self.cpu.get_halt(&mut self.mmu) && self.cycles == 0 {
+            self.trace_instruction();
+            self.cycles = self.cpu.cycle(&mut self.mmu);
+        }
+
+        self.cycles += io::interrupts::exectute_interrupts(&mut self.cpu, &mut self.mmu);
+        self.mmu.tick_timer();
+        let instruction_done = self.cycles == 1;
+        self.cycles = self.cycles.saturating_sub(1);
+
+        instruction_done
+    }
+
+    /// Starts writing a gameboy-doctor compatible trace of every executed instruction to `path`.
+    /// Replaces any trace already running.
+    pub fn start_trace(
+        &mut self,
+        path: PathBuf,
+        start: cpu::trace::TraceStart,
+    ) -> std::io::Result<()> {
+        self.stop_trace();
+        self.tracer = Some(cpu::trace::Tracer::new(path, start)?);
+        Ok(())
+    }
+
+    /// Stops the running trace, if any, and flushes it to disk.
+    pub fn stop_trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if let Err(e) = tracer.flush() {
+                println!("Error: {}", e);
+            }
+        }
+    }
+
+    /// Returns true while the LCD is off, or showing the blank first frame which follows its activation, or stopped along
+    /// with the CPU in STOP mode.
+    pub fn is_screen_off(&self) -> bool {
+        self.ppu.is_screen_off() || self.cpu.stopped
+    }
+
+    /// Updates the state of a button on the joypad of `player` (0-3, only the first is read outside of SGB multiplayer).
+    /// While a movie is recorded, the buttons are recorded and applied from the next frame. While it is played, they are
+    /// only kept for when the recording resumes.
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        set_bit(&mut self.held[player], button, pressed);
+        self.update_joypad(player);
+    }
+
+    /// Holds or releases a button in turbo mode: while held, it is pressed and released every `turbo_frames` frames.
+    /// The turbo follows the emulated frames, so that it is recorded in movies like any other input.
+    pub fn set_turbo(&mut self, player: usize, button: Button, pressed: bool) {
+        set_bit(&mut self.turbo[player], button, pressed);
+        self.update_joypad(player);
+    }
+
+    /// Returns the buttons held in turbo mode by `player`, one bit per button in the order of `Button::ALL`.
+    pub fn get_turbo(&self, player: usize) -> u8 {
+        self.turbo[player]
+    }
+
+    /// Presses the buttons held by the player, with the turbo buttons in the pressed half of their period.
+    fn update_joypad(&mut self, player: usize) {
+        let turbo_pressed = (self.frames / self.turbo_frames.max(1) as u64).is_multiple_of(2);
+        let pressed = self.held[player] | if turbo_pressed { self.turbo[player] } else { 0 };
+
+        for (bit, button) in Button::ALL.into_iter().enumerate() {
+            let pressed = pressed & (1 << bit) != 0;
+            match self.movie.as_mut() {
+                Some(session) if session.get_mode() != MovieMode::Finished => {
+                    session.set_input(player, bit, pressed)
+                }
+                _ => self.mmu.set_button(player, button, pressed),
+            }
+        }
+    }
+
+    /// Returns true if the last frame completed without the game reading the joypad, so that inputs held during it
+    /// had no effect.
+    pub fn is_lag_frame(&self) -> bool {
+        self.lag_frame
+    }
+
+    /// Frames completed since the rom was loaded or the machine powered on.
+    pub fn get_frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Lag frames since the rom was loaded or the machine powered on, see `is_lag_frame`.
+    pub fn get_lag_count(&self) -> u64 {
+        self.lag_frames
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        self.mmu.get_sgb().is_some()
+    }
+
+    /// Returns the 256x224 RGBA output of the SGB, border included, or None outside of SGB mode.
+    pub fn sgb_frame(&self) -> Option<&[u8]> {
+        self.mmu.get_sgb().map(|sgb| sgb.get_frame())
+    }
+
+    /// Saves the whole machine, with `frame` as thumbnail. The state can only be loaded back with the same rom.
+    pub fn save_state(&self, frame: &[u8]) -> Vec<u8> {
+        let mut state = state::StateWriter::new(self.mmu.get_rom_checksum(), frame);
+        self.save_machine(&mut state);
+        state.bytes(&self.held);
+        state.bytes(&self.turbo);
+        state.into_bytes()
+    }
+
+    /// Saves what the game runs on, without the buttons the player holds, which a movie overrides.
+    fn save_machine(&self, state: &mut state::StateWriter) {
+        self.cpu.save(state);
+        self.ppu.save(state);
+        self.mmu.save(state);
+        state.u8(self.cycles);
+        state.u64(self.elapsed_cycles);
+        state.u64(self.cpu_cycles);
+        state.bool(self.half_dot);
+        state.u64(self.frames);
+        state.bool(self.lag_frame);
+        state.u64(self.lag_frames);
+    }
+
+    /// Restores a state saved by `save_state`, and its thumbnail in `frame`. The machine is left untouched if the
+    /// state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8], frame: &mut [u8]) -> Result<(), state::StateError> {
+        let mut state = state::StateReader::new(data, self.mmu.get_rom_checksum())?;
+        let thumbnail = state::StateReader::thumbnail(data)?;
+        if thumbnail.len() != frame.len() {
+            return Err(state::StateError::InvalidFormat);
+        }
+
+        let backup = self.save_state(frame);
+        if let Err(e) = self.load_parts(&mut state) {
+            let mut backup = state::StateReader::new(&backup, self.mmu.get_rom_checksum())?;
+            self.load_parts(&mut backup)?;
+            return Err(e);
+        }
+
+        frame.copy_from_slice(thumbnail);
+        self.model = self.mmu.get_model();
+        self.snapshot_on_screen = false;
+        // Reads made before the state was loaded don't count for this frame
+        self.mmu.take_joypad_polled();
+        self.resume_movie();
+        Ok(())
+    }
+
+    fn load_parts(&mut self, state: &mut state::StateReader) -> Result<(), state::StateError> {
+        self.cpu.load(state)?;
+        self.ppu.load(state)?;
+        self.mmu.load(state)?;
+        self.cycles = state.u8()?;
+        self.elapsed_cycles = state.u64()?;
+        self.cpu_cycles = state.u64()?;
+        self.half_dot = state.bool()?;
+        self.frames = state.u64()?;
+        self.lag_frame = state.bool()?;
+        self.lag_frames = state.u64()?;
+        state.bytes_into(&mut self.held)?;
+        state.bytes_into(&mut self.turbo)?;
+        state.finish()
+    }
+
+    pub fn save_state_file(&self, path: &Path, frame: &[u8]) -> Result<(), state::StateError> {
+        std::fs::write(path, self.save_state(frame))?;
+        Ok(())
+    }
+
+    pub fn load_state_file(
+        &mut self,
+        path: &Path,
+        frame: &mut [u8],
+    ) -> Result<(), state::StateError> {
+        let data = std::fs::read(path)?;
+        self.load_state(&data, frame)
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    fn trace_instruction(&mut self) {
+        let tracer = match self.tracer.as_mut() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+
+        if let Err(e) = tracer.log(&self.cpu, &self.mmu, self.cpu_cycles) {
+            println!("Error: {}", e);
+            self.tracer = None;
+        }
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    /// Changes the emulated model. Takes effect on the next power cycle or rom load.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
     pub fn load_roam(&mut self, rom_path: PathBuf) {
-        self.reset();
-        self.mmu = memory::mmu::Mmu::new(rom_path)
+        let mut mmu = memory::mmu::Mmu::new(rom_path, self.model);
+        mmu.set_access_restrictions(self.mmu.is_access_restricted());
+        self.restart(mmu, self.random_ram.then(random_seed));
     }
 
+    /// Restarts the CPU and the hardware without turning the console off: the RAMs, cartridge RAM included, keep
+    /// their contents.
     pub fn reset(&mut self) {
-        self.cpu = cpu::cpu::Cpu::new();
+        let mmu = self.mmu.reset();
+        self.restart(mmu, None);
+    }
+
+    /// Turns the console off and on again with the cartridge inserted, switching to the model selected with
+    /// `set_model`. The RAMs start zeroed, or random with `random_ram`, except the cartridge RAM backed by a battery.
+    /// No mapper/RTC state exists yet.
+    pub fn power_cycle(&mut self) {
+        let mmu = self.mmu.power_cycle(self.model);
+        self.restart(mmu, self.random_ram.then(random_seed));
+    }
+
+    /// Starts the machine over with `mmu`, its RAM filled from `ram_seed` or zeroed. Ends the movie.
+    fn restart(&mut self, mut mmu: memory::mmu::Mmu, ram_seed: Option<u64>) {
+        self.finish_movie();
+        if let Some(seed) = ram_seed {
+            mmu.randomize_ram(seed);
+        }
         self.ppu = gpu::ppu::Ppu::new();
-        self.mmu = memory::mmu::Mmu::default();
+        self.cpu = boot_cpu(&mmu);
+        if let Some(palette) = boot_palette(&mmu) {
+            self.palette = palette;
+        }
+        self.mmu = mmu;
         self.cycles = 0;
+        self.elapsed_cycles = 0;
+        self.cpu_cycles = 0;
+        self.half_dot = false;
+        self.frames = 0;
+        self.lag_frame = false;
+        self.lag_frames = 0;
+        self.rewind.clear();
+        self.snapshot_on_screen = false;
     }
 }
+
+fn set_bit(buttons: &mut u8, button: Button, pressed: bool) {
+    if pressed {
+        *buttons |= 1 << button as u8;
+    } else {
+        *buttons &= !(1 << button as u8);
+    }
+}
+
+/// Seed of the random RAM contents, different on each run.
+fn random_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+/// Returns the palette the CGB boot rom gives to the DMG games, None when the rom isn't colorized.
+fn boot_palette(mmu: &memory::mmu::Mmu) -> Option<palette::Palette> {
+    (mmu.get_model().is_cgb() && !mmu.is_cgb()).then(|| colorization::automatic_palette(mmu))
+}
+
+/// Returns a CPU ready to run the boot rom, or in the state the boot rom leaves it when the boot rom is skipped.
+fn boot_cpu(mmu: &memory::mmu::Mmu) -> cpu::cpu::Cpu {
+    let mut cpu = cpu::cpu::Cpu::new();
+    if mmu.is_bios_enabled() {
+        return cpu;
+    }
+
+    let [af, bc, de, hl] = mmu
+        .get_model()
+        .post_boot_registers(mmu.peek_byte(0x014D), mmu.is_cgb());
+    cpu.set_rr(cpu::cpu::RegisterPair::AF, af);
+    cpu.set_rr(cpu::cpu::RegisterPair::BC, bc);
+    cpu.set_rr(cpu::cpu::RegisterPair::DE, de);
+    cpu.set_rr(cpu::cpu::RegisterPair::HL, hl);
+    cpu.sp = 0xFFFE;
+    cpu.pc = 0x0100;
+    cpu
+}
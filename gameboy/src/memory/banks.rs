@@ -0,0 +1,161 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const VRAM_START: usize = 0x8000;
+const VRAM_SIZE: usize = 0x2000;
+const WRAM_START: usize = 0xD000;
+const WRAM_SIZE: usize = 0x1000;
+
+/// Switchable VRAM (VBK) and WRAM (SVBK) banks of the CGB.
+/// The selected banks stay mapped in the flat memory of the MMU, the others are saved here until they are selected.
+#[derive(Clone)]
+pub struct Banks {
+    vram: [[u8; VRAM_SIZE]; 2],
+    vram_bank: usize,
+    wram: [[u8; WRAM_SIZE]; 8],
+    wram_bank: usize,
+}
+
+impl Default for Banks {
+    fn default() -> Self {
+        Self {
+            vram: [[0; VRAM_SIZE]; 2],
+            vram_bank: 0,
+            wram: [[0; WRAM_SIZE]; 8],
+            wram_bank: 1,
+        }
+    }
+}
+
+impl Banks {
+    pub fn get_vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    pub fn get_wram_bank(&self) -> usize {
+        self.wram_bank
+    }
+
+    /// Maps VRAM bank `bank` (0-1) at 0x8000-0x9FFF.
+    pub fn switch_vram(&mut self, data: &mut [u8], bank: usize) {
+        let mapped = &mut data[VRAM_START..VRAM_START + VRAM_SIZE];
+        self.vram[self.vram_bank].copy_from_slice(mapped);
+        mapped.copy_from_slice(&self.vram[bank]);
+        self.vram_bank = bank;
+    }
+
+    /// Maps WRAM bank `bank` (1-7) at 0xD000-0xDFFF.
+    pub fn switch_wram(&mut self, data: &mut [u8], bank: usize) {
+        let mapped = &mut data[WRAM_START..WRAM_START + WRAM_SIZE];
+        self.wram[self.wram_bank].copy_from_slice(mapped);
+        mapped.copy_from_slice(&self.wram[bank]);
+        self.wram_bank = bank;
+    }
+
+    /// Fills the WRAM banks which are not mapped with the bytes returned by `byte`.
+    pub fn fill_wram(&mut self, mut byte: impl FnMut() -> u8) {
+        for (bank, wram) in self.wram.iter_mut().enumerate() {
+            if bank != self.wram_bank {
+                wram.fill_with(&mut byte);
+            }
+        }
+    }
+
+    /// Reads `addr` (0x8000-0x9FFF) in VRAM bank `bank`, whichever bank is mapped.
+    pub fn read_vram(&self, data: &[u8], bank: usize, addr: u16) -> u8 {
+        if bank == self.vram_bank {
+            data[addr as usize]
+        } else {
+            self.vram[bank][addr as usize - VRAM_START]
+        }
+    }
+}
+
+impl SaveState for Banks {
+    fn save(&self, state: &mut StateWriter) {
+        for bank in self.vram.iter() {
+            state.bytes(bank);
+        }
+        state.u8(self.vram_bank as u8);
+        for bank in self.wram.iter() {
+            state.bytes(bank);
+        }
+        state.u8(self.wram_bank as u8);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for bank in self.vram.iter_mut() {
+            state.bytes_into(bank)?;
+        }
+        self.vram_bank = state.u8()? as usize & 0x01;
+        for bank in self.wram.iter_mut() {
+            state.bytes_into(bank)?;
+        }
+        self.wram_bank = state.u8()? as usize & 0x07;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::mmu::Mmu;
+    use crate::model::Model;
+
+    #[test]
+    fn vbk_switches_the_vram_bank() {
+        let mut mmu = Mmu::blank_cgb();
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0x9FFF, 0x34);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+
+        mmu.write_byte(0xFF4F, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0x00);
+        mmu.write_byte(0x8000, 0x56);
+
+        // Only bit 0 selects the bank
+        mmu.write_byte(0xFF4F, 0xFE);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0x9FFF), 0x34);
+        mmu.write_byte(0xFF4F, 0x01);
+        assert_eq!(mmu.read_byte(0x8000), 0x56);
+    }
+
+    #[test]
+    fn svbk_switches_the_wram_bank() {
+        let mut mmu = Mmu::blank_cgb();
+        mmu.write_byte(0xC000, 0xC0);
+        for bank in 1..8 {
+            mmu.write_byte(0xFF70, bank);
+            mmu.write_byte(0xD000, bank);
+        }
+
+        for bank in 1..8 {
+            mmu.write_byte(0xFF70, bank);
+            assert_eq!(mmu.read_byte(0xFF70), 0xF8 | bank);
+            assert_eq!(mmu.read_byte(0xD000), bank);
+            assert_eq!(mmu.read_byte(0xC000), 0xC0);
+        }
+
+        // Bank 0 selects bank 1, and the upper bits are ignored
+        mmu.write_byte(0xFF70, 0x00);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF9);
+        assert_eq!(mmu.read_byte(0xD000), 1);
+        mmu.write_byte(0xFF70, 0xFA);
+        assert_eq!(mmu.read_byte(0xFF70), 0xFA);
+        assert_eq!(mmu.read_byte(0xD000), 2);
+    }
+
+    #[test]
+    fn banks_are_not_mapped_on_dmg() {
+        let mut mmu = Mmu::blank(Model::Dmg);
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xD000, 0x34);
+        mmu.write_byte(0xFF4F, 0x01);
+        mmu.write_byte(0xFF70, 0x02);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0xFF70), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xD000), 0x34);
+    }
+}
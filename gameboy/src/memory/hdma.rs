@@ -0,0 +1,226 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const HDMA1_ADDRESS: u16 = 0xFF51;
+pub const HDMA2_ADDRESS: u16 = 0xFF52;
+pub const HDMA3_ADDRESS: u16 = 0xFF53;
+pub const HDMA4_ADDRESS: u16 = 0xFF54;
+pub const HDMA5_ADDRESS: u16 = 0xFF55;
+
+/// CGB VRAM DMA. Copies blocks of 16 bytes to VRAM, either all at once (general purpose DMA), or one block per
+/// HBlank (HBlank DMA). The MMU does the copy, this only keeps track of the addresses and of the remaining length.
+pub struct Hdma {
+    source: u16,
+    /// Offset in VRAM, 0x0000-0x1FF0
+    destination: u16,
+    /// Number of blocks left minus one, as read from HDMA5
+    remaining: u8,
+    hblank_active: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblank_active: false,
+        }
+    }
+}
+
+impl Hdma {
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Bit 7 reads 0 while an HBlank DMA is running
+            HDMA5_ADDRESS if self.hblank_active => self.remaining,
+            HDMA5_ADDRESS => 0x80 | self.remaining,
+            // HDMA1-HDMA4 are write only
+            _ => 0xFF,
+        }
+    }
+
+    /// Handles writes to HDMA1-HDMA4. HDMA5 starts the transfer, see `start`.
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            HDMA1_ADDRESS => self.source = (self.source & 0x00FF) | (val as u16) << 8,
+            HDMA2_ADDRESS => self.source = (self.source & 0xFF00) | (val & 0xF0) as u16,
+            HDMA3_ADDRESS => {
+                self.destination = (self.destination & 0x00FF) | ((val & 0x1F) as u16) << 8
+            }
+            HDMA4_ADDRESS => self.destination = (self.destination & 0xFF00) | (val & 0xF0) as u16,
+            _ => unreachable!("{:04X} is not an HDMA register", addr),
+        }
+    }
+
+    /// Starts a transfer of `(val & 0x7F) + 1` blocks, during HBlanks if bit 7 is set.
+    /// Writing bit 7 = 0 while an HBlank DMA is running cancels it instead. Returns true for a general purpose DMA,
+    /// which the MMU must copy right away.
+    pub fn start(&mut self, val: u8) -> bool {
+        if self.hblank_active && val & 0x80 == 0 {
+            self.hblank_active = false;
+            return false;
+        }
+
+        self.remaining = val & 0x7F;
+        self.hblank_active = val & 0x80 == 0x80;
+        !self.hblank_active
+    }
+
+    pub fn is_hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    /// Returns the number of blocks left to copy.
+    pub fn get_blocks(&self) -> u16 {
+        self.remaining as u16 + 1
+    }
+
+    /// Returns the source and the VRAM destination of the next block, and moves to the following one.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.destination);
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+
+        if self.remaining == 0 {
+            // HDMA5 reads 0xFF once the transfer is complete
+            self.remaining = 0x7F;
+            self.hblank_active = false;
+        } else {
+            self.remaining -= 1;
+        }
+
+        block
+    }
+}
+
+impl SaveState for Hdma {
+    fn save(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.remaining);
+        state.bool(self.hblank_active);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.u16()?;
+        self.destination = state.u16()? & 0x1FF0;
+        self.remaining = state.u8()? & 0x7F;
+        self.hblank_active = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::mmu::Mmu;
+
+    /// CGB memory with 0x40 bytes counting up at 0xC000 and a DMA set up to copy them to 0x8000.
+    fn setup(lcd_on: bool) -> Mmu {
+        let mut mmu = Mmu::blank_cgb();
+        mmu.set_access_restrictions(false);
+        for i in 0..0x40 {
+            mmu.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        mmu.write_byte(HDMA1_ADDRESS, 0xC0);
+        mmu.write_byte(HDMA2_ADDRESS, 0x00);
+        mmu.write_byte(HDMA3_ADDRESS, 0x00);
+        mmu.write_byte(HDMA4_ADDRESS, 0x00);
+        if lcd_on {
+            mmu.write_byte(0xFF40, 0x80);
+        }
+        mmu
+    }
+
+    /// Returns the number of blocks copied to 0x8000.
+    fn copied_blocks(mmu: &Mmu) -> usize {
+        (0..4)
+            .take_while(|block| mmu.read_byte(0x8000 + block * 0x10) == (block * 0x10) as u8 + 1)
+            .count()
+    }
+
+    /// Returns the number of CPU cycles the DMA stalls the CPU for.
+    fn stall(mmu: &mut Mmu) -> usize {
+        std::iter::from_fn(|| mmu.stall_cpu().then_some(())).count()
+    }
+
+    #[test]
+    fn general_dma_copies_everything_at_once() {
+        let mut mmu = setup(true);
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0xFF);
+        mmu.write_byte(HDMA5_ADDRESS, 0x02);
+        assert_eq!(copied_blocks(&mmu), 3);
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0xFF);
+        // HDMA1-HDMA4 are write only
+        assert_eq!(mmu.read_byte(HDMA1_ADDRESS), 0xFF);
+        assert_eq!(mmu.read_byte(HDMA4_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_hblank() {
+        let mut mmu = setup(true);
+        mmu.write_byte(HDMA5_ADDRESS, 0x82);
+        assert_eq!(copied_blocks(&mmu), 0);
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0x02);
+
+        for blocks in 1..=3 {
+            mmu.hblank_dma();
+            assert_eq!(copied_blocks(&mmu), blocks);
+        }
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0xFF);
+        mmu.hblank_dma();
+        assert_eq!(copied_blocks(&mmu), 3);
+    }
+
+    #[test]
+    fn hblank_dma_is_cancelled_by_clearing_bit_7() {
+        let mut mmu = setup(true);
+        mmu.write_byte(HDMA5_ADDRESS, 0x82);
+        mmu.hblank_dma();
+        mmu.write_byte(HDMA5_ADDRESS, 0x00);
+
+        // Bit 7 reads 1 again, the length keeps the blocks left
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0x81);
+        mmu.hblank_dma();
+        assert_eq!(copied_blocks(&mmu), 1);
+    }
+
+    #[test]
+    fn hblank_dma_waits_while_the_cpu_is_halted() {
+        let mut mmu = setup(true);
+        mmu.write_byte(HDMA5_ADDRESS, 0x82);
+        mmu.set_cpu_halted(true);
+        mmu.hblank_dma();
+        assert_eq!(copied_blocks(&mmu), 0);
+        mmu.set_cpu_halted(false);
+        mmu.hblank_dma();
+        assert_eq!(copied_blocks(&mmu), 1);
+    }
+
+    #[test]
+    fn hblank_dma_with_the_lcd_off_copies_a_block_right_away() {
+        let mut mmu = setup(false);
+        mmu.write_byte(HDMA5_ADDRESS, 0x82);
+        assert_eq!(copied_blocks(&mmu), 1);
+        assert_eq!(mmu.read_byte(HDMA5_ADDRESS), 0x01);
+    }
+
+    #[test]
+    fn dma_stalls_the_cpu_32_dots_per_block() {
+        let mut mmu = setup(true);
+        mmu.write_byte(HDMA5_ADDRESS, 0x02);
+        assert_eq!(stall(&mut mmu), 3 * 32);
+
+        let mut mmu = setup(true);
+        mmu.write_byte(HDMA5_ADDRESS, 0x82);
+        mmu.hblank_dma();
+        assert_eq!(stall(&mut mmu), 32);
+
+        // Twice as many CPU cycles in double speed
+        let mut mmu = setup(true);
+        mmu.write_byte(0xFF4D, 0x01);
+        assert!(mmu.switch_speed());
+        mmu.write_byte(HDMA5_ADDRESS, 0x02);
+        assert_eq!(stall(&mut mmu), 3 * 64);
+    }
+}
@@ -1,86 +1,466 @@
+use std::cell::Cell;
 use std::path::PathBuf;
 
+use crate::gpu::color_ram::ColorRam;
+use crate::io::joypad::{Button, Joypad, JOYPAD_ADDRESS};
+use crate::io::timer::{Timer, DIV_ADDRESS, TAC_ADDRESS};
+use crate::memory::banks::Banks;
+use crate::memory::hdma::{Hdma, HDMA1_ADDRESS, HDMA4_ADDRESS, HDMA5_ADDRESS};
+use crate::model::Model;
+use crate::sgb::sgb::Sgb;
+use crate::state::{self, SaveState, StateError, StateReader, StateWriter};
+
 const MEM_SIZE: usize = 0x10000;
 
+/// Hardware registers as left by the DMG boot rom.
+const POST_BOOT_IO_REGISTERS: [(u16, u8); 11] = [
+    (0xFF0F, 0xE1),
+    (0xFF40, 0x91),
+    (0xFF41, 0x85),
+    (0xFF42, 0x00),
+    (0xFF43, 0x00),
+    (0xFF45, 0x00),
+    (0xFF47, 0xFC),
+    (0xFF48, 0xFF),
+    (0xFF49, 0xFF),
+    (0xFF4A, 0x00),
+    (0xFF4B, 0x00),
+];
+
 pub struct Mmu {
     data: [u8; MEM_SIZE],
-    bios: [u8; 0x100],
+    bios: Vec<u8>,
     bios_enabled: bool,
+    model: Model,
+    /// Rom of the inserted cartridge, mapped again on reset
+    rom: Vec<u8>,
+    /// CRC-32 of the loaded rom, which identifies the game in the save states
+    rom_checksum: u32,
+    ly_override: Option<u8>,
+    timer: Timer,
+    stat_written: bool,
+    /// The CPU has read the joypad register, to detect the lag frames
+    joypad_polled: Cell<bool>,
+    /// The CPU can't access VRAM during mode 3, nor OAM during modes 2 and 3
+    access_restrictions: bool,
+    /// Row of OAM read by the PPU during the OAM scan, for the OAM corruption bug
+    oam_scan_row: Option<usize>,
+    /// The cartridge header asks for the CGB features
+    cgb: bool,
+    banks: Banks,
+    /// KEY1 bit 0: the next STOP switches the CPU speed
+    speed_switch_armed: bool,
+    double_speed: bool,
+    bg_color_ram: ColorRam,
+    obj_color_ram: ColorRam,
+    hdma: Hdma,
+    /// CPU cycles left during which the CPU is stopped by a VRAM DMA
+    cpu_stall: u32,
+    /// The CPU is halted, which pauses the HBlank DMA
+    cpu_halted: bool,
+    joypad: Joypad,
+    /// Present when the cartridge header asks for the SGB features
+    sgb: Option<Sgb>,
 }
 
 impl Default for Mmu {
     fn default() -> Self {
-        let bios = match std::fs::read("resources/bios.bin") {
-            Ok(bytes) => bytes,
-            Err(e) => panic!("Error: {}", e),
-        };
-
-        Self {
-            data: [0; MEM_SIZE],
-            bios: bios.try_into().unwrap_or_else(|v: Vec<u8>| {
-                panic!("Expected a Vec of length {} but it was {}", 100, v.len())
-            }),
-            bios_enabled: true,
-        }
+        Self::from_model(Model::default())
     }
 }
 
 impl Mmu {
-    pub fn new(rom_path: PathBuf) -> Self {
-        let rom = match std::fs::read(rom_path) {
-            Ok(bytes) => bytes,
+    /// Creates an empty memory which starts with the boot rom of `model`, or as the boot rom would leave it if the
+    /// boot rom can't be loaded.
+    pub fn from_model(model: Model) -> Self {
+        let mut mmu = Self::blank(model);
+        match read_boot_rom(model) {
+            Ok(bios) => {
+                mmu.bios = bios;
+                mmu.bios_enabled = true;
+            }
             Err(e) => {
-                println!("Error: {}", e);
-                return Self {
-                    ..Default::default()
-                };
+                println!("Error: {}: {}", model.boot_rom_path(), e);
+                mmu.skip_boot_rom();
             }
-        };
+        }
+        mmu
+    }
 
-        let mut data = [0; MEM_SIZE];
-        data[0..0x8000].copy_from_slice(&rom);
+    pub fn new(rom_path: PathBuf, model: Model) -> Self {
+        let mut mmu = Self::from_model(model);
+        match std::fs::read(rom_path) {
+            Ok(rom) => mmu.load_rom(&rom),
+            Err(e) => println!("Error: {}", e),
+        }
+        mmu
+    }
+
+    /// Loads the rom without reading the boot rom from disk. Hardware registers are set as the boot rom would leave them.
+    pub fn without_bios(rom_path: PathBuf, model: Model) -> std::io::Result<Self> {
+        let rom = std::fs::read(rom_path)?;
+
+        let mut mmu = Self::blank(model);
+        mmu.load_rom(&rom);
+        mmu.skip_boot_rom();
+        Ok(mmu)
+    }
+
+    /// Memory with every register cleared and no boot rom, for the tests which set up the hardware themselves.
+    pub(crate) fn blank(model: Model) -> Self {
+        Self {
+            data: [0; MEM_SIZE],
+            bios: Vec::new(),
+            bios_enabled: false,
+            model,
+            rom: Vec::new(),
+            rom_checksum: 0,
+            ly_override: None,
+            timer: Timer::default(),
+            stat_written: false,
+            joypad_polled: Cell::new(false),
+            access_restrictions: true,
+            oam_scan_row: None,
+            cgb: false,
+            banks: Banks::default(),
+            speed_switch_armed: false,
+            cpu_halted: false,
+            double_speed: false,
+            bg_color_ram: ColorRam::default(),
+            obj_color_ram: ColorRam::default(),
+            hdma: Hdma::default(),
+            cpu_stall: 0,
+            joypad: Joypad::default(),
+            sgb: None,
+        }
+    }
 
+    /// Blank memory of a CGB running in CGB mode, as with a CGB rom inserted.
+    #[cfg(test)]
+    pub(crate) fn blank_cgb() -> Self {
         Self {
-            data,
-            ..Default::default()
+            cgb: true,
+            ..Self::blank(Model::Cgb)
         }
     }
 
+    /// Blank memory of an SGB running in SGB mode, as with an SGB rom inserted.
+    #[cfg(test)]
+    pub(crate) fn blank_sgb() -> Self {
+        Self {
+            sgb: Some(Sgb::default()),
+            ..Self::blank(Model::Sgb)
+        }
+    }
+
+    /// Maps the first 32KB of the rom. The CGB and SGB features are enabled if both the model and the header ask for
+    /// them.
+    fn load_rom(&mut self, rom: &[u8]) {
+        let rom_size = rom.len().min(0x8000);
+        self.data[0..rom_size].copy_from_slice(&rom[0..rom_size]);
+        self.rom = rom.to_vec();
+        self.rom_checksum = state::crc32(rom);
+        self.cgb = self.model.is_cgb() && is_cgb_rom(rom);
+        self.sgb = (self.model.is_sgb() && is_sgb_rom(rom)).then(Sgb::default);
+    }
+
+    /// Returns the memory of `model` starting over with the same cartridge, as the console is turned off and on: only
+    /// the cartridge RAM backed by a battery is kept, as well as the debug settings.
+    pub fn power_cycle(&self, model: Model) -> Self {
+        let mut mmu = Self::from_model(model);
+        mmu.load_rom(&self.rom);
+        if has_battery(&self.rom) {
+            mmu.data[0xA000..0xC000].copy_from_slice(&self.data[0xA000..0xC000]);
+        }
+        mmu.ly_override = self.ly_override;
+        mmu.access_restrictions = self.access_restrictions;
+        mmu
+    }
+
+    /// Returns the memory starting over after a reset: the registers are back to their power-on values, the RAMs keep
+    /// their contents.
+    pub fn reset(&self) -> Self {
+        let mut mmu = self.power_cycle(self.model);
+        mmu.data[0x8000..0xE000].copy_from_slice(&self.data[0x8000..0xE000]);
+        mmu.data[0xFE00..0xFEA0].copy_from_slice(&self.data[0xFE00..0xFEA0]);
+        mmu.data[0xFF80..0xFFFF].copy_from_slice(&self.data[0xFF80..0xFFFF]);
+        // The banks mapped in the copy are the ones of the previous run, VBK and SVBK select banks 0 and 1 again
+        mmu.banks = self.banks.clone();
+        mmu.banks.switch_vram(&mut mmu.data, 0);
+        mmu.banks.switch_wram(&mut mmu.data, 1);
+        mmu
+    }
+
+    /// Fills the work RAM, OAM and HRAM with a pattern generated from `seed`. The hardware powers on with random
+    /// contents in them, which some games rely on as a source of randomness.
+    pub fn randomize_ram(&mut self, seed: u64) {
+        let mut state = seed | 1;
+        let mut random = move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        };
+
+        for addr in (0xC000..0xE000).chain(0xFE00..0xFEA0).chain(0xFF80..0xFFFF) {
+            self.data[addr] = random();
+        }
+        self.banks.fill_wram(random);
+    }
+
+    /// Sets the hardware registers and the timer as the boot rom would leave them.
+    fn skip_boot_rom(&mut self) {
+        self.bios_enabled = false;
+        for (addr, value) in POST_BOOT_IO_REGISTERS {
+            self.data[addr as usize] = value;
+        }
+        self.timer.set_counter(self.model.post_boot_div());
+    }
+
+    /// Forces reads of LY to return `value`. gameboy-doctor expects LY to always read 0x90.
+    pub fn set_ly_override(&mut self, value: Option<u8>) {
+        self.ly_override = value;
+    }
+
     pub fn get_data(&self) -> [u8; MEM_SIZE] {
         self.data
     }
 
     pub fn get_slice_data(&self, addr: usize) -> Vec<u8> {
-        self.data[addr..addr + 16].to_vec()
+        (addr..addr + 16)
+            .map(|a| self.peek_byte(a as u16))
+            .collect()
+    }
+
+    /// Enables or disables the VRAM and OAM lockout during PPU modes. Disabling it helps finding timing bugs.
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.access_restrictions = enabled;
+    }
+
+    pub fn is_access_restricted(&self) -> bool {
+        self.access_restrictions
+    }
+
+    /// Returns true if the rom runs with the CGB features (VRAM/WRAM banks, double speed).
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Switches the CPU speed if it was requested through KEY1. Called by STOP, returns true if the speed changed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || !self.speed_switch_armed {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        // STOP resets DIV
+        self.timer.write(DIV_ADDRESS, 0);
+        true
+    }
+
+    /// Copies the next block of a running HBlank DMA. Called by the PPU when it enters HBlank.
+    /// No block is copied during the HBlanks the CPU spends halted, the transfer resumes once it wakes up.
+    pub fn hblank_dma(&mut self) {
+        if self.hdma.is_hblank_active() && !self.cpu_halted {
+            self.copy_hdma_block();
+        }
+    }
+
+    /// Consumes one CPU cycle of the stall caused by a VRAM DMA. Returns true if the CPU is stalled.
+    pub fn stall_cpu(&mut self) -> bool {
+        if self.cpu_stall == 0 {
+            return false;
+        }
+        self.cpu_stall -= 1;
+        true
+    }
+
+    /// Copies 16 bytes to VRAM. The CPU is stopped during the copy, 32 dots whatever the speed, so twice as many CPU
+    /// cycles in double speed mode.
+    fn copy_hdma_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..0x10 {
+            self.data[(destination + i) as usize] = self.peek_byte(source.wrapping_add(i));
+        }
+
+        self.cpu_stall += if self.double_speed { 64 } else { 32 };
+    }
+
+    /// Returns the 15-bit color `color` of the CGB background palette `palette`.
+    pub fn get_cgb_background_color(&self, palette: u8, color: u8) -> u16 {
+        self.bg_color_ram.get_color(palette, color)
+    }
+
+    /// Returns the 15-bit color `color` of the CGB object palette `palette`.
+    pub fn get_cgb_object_color(&self, palette: u8, color: u8) -> u16 {
+        self.obj_color_ram.get_color(palette, color)
+    }
+
+    /// Reads `addr` (0x8000-0x9FFF) in the given VRAM bank, whatever the bank selected by VBK.
+    pub fn read_vram(&self, bank: usize, addr: u16) -> u8 {
+        self.banks.read_vram(&self.data, bank, addr)
     }
 
     pub fn set_bios_enabled(&mut self, enabled: bool) {
         self.bios_enabled = enabled;
     }
 
+    pub fn is_bios_enabled(&self) -> bool {
+        self.bios_enabled
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    pub fn get_rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    /// CRC-32 of the boot rom, 0 without one.
+    pub fn get_boot_rom_checksum(&self) -> u32 {
+        state::crc32(&self.bios)
+    }
+
+    /// Tells whether the CPU is halted, before the PPU runs a dot.
+    pub fn set_cpu_halted(&mut self, halted: bool) {
+        self.cpu_halted = halted;
+    }
+
+    /// Sets the OAM row read by the PPU, None outside of the OAM scan.
+    pub fn set_oam_scan_row(&mut self, row: Option<usize>) {
+        self.oam_scan_row = row;
+    }
+
+    /// DMG OAM corruption bug, triggered when `addr` is put on the address bus by a write or a 16-bit
+    /// increment/decrement while the PPU scans OAM. The first word of the row being scanned is mixed with the
+    /// previous row, and the rest of the row is replaced by the previous row.
+    /// Reads also corrupt OAM with a different pattern, which is not emulated.
+    pub fn corrupt_oam(&mut self, addr: u16) {
+        if !self.model.has_oam_corruption() || !(0xFE00..=0xFEFF).contains(&addr) {
+            return;
+        }
+        // The first row is never corrupted
+        let row = match self.oam_scan_row {
+            Some(row) if row > 0 => 0xFE00 + row * 8,
+            _ => return,
+        };
+        let previous = row - 8;
+
+        let word = |addr: usize| u16::from_le_bytes([self.data[addr], self.data[addr + 1]]);
+        let (a, b, c) = (word(row), word(previous), word(previous + 4));
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+
+        self.data[row..row + 2].copy_from_slice(&first.to_le_bytes());
+        self.data.copy_within(previous + 2..previous + 8, row + 2);
+    }
+
+    /// Reads a byte as the CPU sees it. VRAM and OAM read 0xFF while the PPU is using them.
     pub fn read_byte(&self, addr: u16) -> u8 {
-        if self.bios_enabled & (addr < 0x100) {
-            return self.bios[addr as usize];
+        if self.is_locked(addr) {
+            return 0xFF;
         }
-        // if addr == 0xFF00 {}
-        self.data[addr as usize]
+        if addr == JOYPAD_ADDRESS {
+            self.joypad_polled.set(true);
+        }
+        self.peek_byte(addr)
     }
 
+    /// Reads a byte without the CPU access restrictions, as the PPU, the DMA and the debugger see it.
+    pub fn peek_byte(&self, addr: u16) -> u8 {
+        // The CGB boot rom leaves the cartridge header visible at 0x0100-0x01FF
+        if self.bios_enabled && (addr < 0x100 || (0x200..0x900).contains(&addr)) {
+            if let Some(byte) = self.bios.get(addr as usize) {
+                return *byte;
+            }
+        }
+        match addr {
+            JOYPAD_ADDRESS => self.joypad.read(),
+            // Unmapped, between the serial and the timer registers
+            0xFF03 => 0xFF,
+            DIV_ADDRESS..=TAC_ADDRESS => self.timer.read(addr),
+            0xFF44 => self.ly_override.unwrap_or(self.data[0xFF44]),
+            0xFF4D if self.cgb => {
+                0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+            }
+            0xFF4F if self.cgb => 0xFE | self.banks.get_vram_bank() as u8,
+            0xFF70 if self.cgb => 0xF8 | self.banks.get_wram_bank() as u8,
+            0xFF68 if self.cgb => self.bg_color_ram.read_spec(),
+            0xFF69 if self.cgb => self.bg_color_ram.read_data(),
+            0xFF6A if self.cgb => self.obj_color_ram.read_spec(),
+            0xFF6B if self.cgb => self.obj_color_ram.read_data(),
+            HDMA1_ADDRESS..=HDMA5_ADDRESS if self.cgb => self.hdma.read(addr),
+            // CGB registers are not mapped on DMG
+            0xFF4D | 0xFF4F | HDMA1_ADDRESS..=HDMA5_ADDRESS | 0xFF68..=0xFF6B | 0xFF70 => 0xFF,
+            _ => self.data[addr as usize],
+        }
+    }
+
+    /// Writes a byte as the CPU does. Writes to VRAM and OAM are ignored while the PPU is using them.
     pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.corrupt_oam(addr);
+        if self.is_locked(addr) {
+            return;
+        }
+
+        let previous = self.data[addr as usize];
         self.data[addr as usize] = val;
 
         match addr {
             0xFF50 => self.set_bios_enabled(val == 0),
-            0xFF04 => {
-                // Reset timer registers if writting to DIV
-                self.data[0xFF03] = 0;
-                self.data[0xFF04] = 0;
-                self.data[0xFF05] = 0;
+            JOYPAD_ADDRESS => {
+                self.joypad.write(val);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(val);
+                    self.joypad.set_player(sgb.get_players(), sgb.get_player());
+                }
+            }
+            DIV_ADDRESS..=TAC_ADDRESS => self.timer.write(addr, val),
+            0xFF46 => {
+                // OAM DMA: copies 0xXX00-0xXX9F to the object attribute memory
+                let source = (val as u16) << 8;
+                for i in 0..0xA0 {
+                    self.data[0xFE00 + i as usize] = self.peek_byte(source + i);
+                }
+            }
+            0xFF41 => {
+                // Mode and coincidence bits are read only
+                self.data[0xFF41] = 0x80 | (val & 0x78) | (previous & 0x07);
+                self.stat_written = true;
             }
             0xFF44 => {
                 // Reset LY register if writting to it
                 self.data[0xFF44] = 0;
             }
+            0xFF4D if self.cgb => self.speed_switch_armed = val & 1 == 1,
+            0xFF4F if self.cgb => self.banks.switch_vram(&mut self.data, (val & 1) as usize),
+            0xFF70 if self.cgb => {
+                // Bank 0 can't be selected, it selects bank 1
+                let bank = (val & 7).max(1) as usize;
+                self.banks.switch_wram(&mut self.data, bank)
+            }
+            0xFF68 if self.cgb => self.bg_color_ram.write_spec(val),
+            0xFF69 if self.cgb => self.bg_color_ram.write_data(val),
+            0xFF6A if self.cgb => self.obj_color_ram.write_spec(val),
+            0xFF6B if self.cgb => self.obj_color_ram.write_data(val),
+            HDMA1_ADDRESS..=HDMA4_ADDRESS if self.cgb => self.hdma.write(addr, val),
+            HDMA5_ADDRESS if self.cgb => {
+                if self.hdma.start(val) {
+                    // General purpose DMA: everything is copied at once
+                    for _ in 0..self.hdma.get_blocks() {
+                        self.copy_hdma_block();
+                    }
+                } else if self.hdma.is_hblank_active() && self.data[0xFF40] & 0x80 == 0 {
+                    // With the LCD off, one block is copied right away
+                    self.copy_hdma_block();
+                }
+            }
             0xFF01 => {
                 // Serial debug
                 print!("{}", val as char);
@@ -89,6 +469,22 @@ impl Mmu {
         }
     }
 
+    /// Returns true if the CPU can't access `addr` in the current PPU mode.
+    fn is_locked(&self, addr: u16) -> bool {
+        if !self.access_restrictions || self.data[0xFF40] & 0x80 == 0 {
+            return false;
+        }
+
+        let mode = self.data[0xFF41] & 0x3;
+        match addr {
+            0x8000..=0x9FFF => mode == 3,
+            // CGB palette data
+            0xFF69 | 0xFF6B => mode == 3,
+            0xFE00..=0xFE9F => mode == 2 || mode == 3,
+            _ => false,
+        }
+    }
+
     pub fn get_ly(&self) -> u8 {
         self.data[0xFF44]
     }
@@ -121,31 +517,59 @@ impl Mmu {
         if opb0 {
             return (self.data[0xFF48] >> (index * 2) & 0x3) as usize;
         }
-        return (self.data[0xFF49] >> (index * 2) & 0x3) as usize;
+        (self.data[0xFF49] >> (index * 2) & 0x3) as usize
     }
 
     pub fn set_stat_mode_flag(&mut self, mode: u8) {
         self.data[0xFF41] = (self.data[0xFF41] & 0xFC) | mode;
     }
 
+    pub fn set_coincidence_flag(&mut self, coincidence: bool) {
+        self.data[0xFF41] = (self.data[0xFF41] & 0xFB) | (coincidence as u8) << 2;
+    }
+
+    /// Returns true if STAT has been written since the last call.
+    pub fn take_stat_write(&mut self) -> bool {
+        std::mem::take(&mut self.stat_written)
+    }
+
+    /// Returns true if the CPU has read the joypad register since the last call.
+    pub fn take_joypad_polled(&self) -> bool {
+        self.joypad_polled.take()
+    }
+
     pub fn set_interrupt_flag(&mut self, flag: u8) {
         self.data[0xFF0F] |= 1 << flag;
     }
 
-    pub fn increment_div_high(&mut self) {
-        self.data[0xFF04] = self.data[0xFF04].checked_add(1).unwrap_or(0);
+    /// Advances the timer by one T-cycle and requests the timer interrupt on TIMA reload.
+    pub fn tick_timer(&mut self) {
+        if self.timer.tick() {
+            self.set_interrupt_flag(2);
+        }
+    }
+
+    /// Updates the state of a button and requests the joypad interrupt when a selected key gets pressed.
+    pub fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        if self.joypad.set_button(player, button, pressed) {
+            self.set_interrupt_flag(4);
+        }
+    }
+
+    pub fn get_joypad(&self) -> &Joypad {
+        &self.joypad
     }
 
-    pub fn reset_div_low(&mut self) {
-        self.data[0xFF03] = 0;
+    pub fn get_sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
     }
 
-    pub fn reset_div_high(&mut self) {
-        self.data[0xFF04] = 0;
+    pub fn get_sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_mut()
     }
 
-    pub fn increment_div_low(&mut self) {
-        self.data[0xFF03] += 1;
+    pub fn get_timer(&self) -> &Timer {
+        &self.timer
     }
 
     pub fn _dump_hex(&self, start: usize, end: usize) {
@@ -176,3 +600,236 @@ impl Mmu {
         self.data[0x9800 + index]
     }
 }
+
+impl SaveState for Mmu {
+    /// Saves the memory and the state of the hardware registers. The boot rom and the debug settings are not saved.
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.bool(self.bios_enabled);
+        state.u8(self.model.id());
+        self.timer.save(state);
+        state.bool(self.stat_written);
+        state.bool(self.oam_scan_row.is_some());
+        state.u8(self.oam_scan_row.unwrap_or(0) as u8);
+        state.bool(self.cgb);
+        self.banks.save(state);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.double_speed);
+        self.bg_color_ram.save(state);
+        self.obj_color_ram.save(state);
+        self.hdma.save(state);
+        state.u32(self.cpu_stall);
+        self.joypad.save(state);
+        state.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save(state);
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.data)?;
+        self.bios_enabled = state.bool()?;
+        let model = Model::from_id(state.u8()?).ok_or(StateError::InvalidFormat)?;
+        if model != self.model {
+            self.model = model;
+            self.bios = read_boot_rom(model).unwrap_or_default();
+        }
+        self.timer.load(state)?;
+        self.stat_written = state.bool()?;
+        let scanning = state.bool()?;
+        let row = state.u8()? as usize;
+        self.oam_scan_row = scanning.then_some(row);
+        self.cgb = state.bool()?;
+        self.banks.load(state)?;
+        self.speed_switch_armed = state.bool()?;
+        self.double_speed = state.bool()?;
+        self.bg_color_ram.load(state)?;
+        self.obj_color_ram.load(state)?;
+        self.hdma.load(state)?;
+        self.cpu_stall = state.u32()?;
+        self.joypad.load(state)?;
+        self.sgb = if state.bool()? {
+            let mut sgb = Sgb::default();
+            sgb.load(state)?;
+            Some(sgb)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+/// Returns true if the CGB flag of the header (0x0143) is set, for both CGB enhanced and CGB only roms.
+fn is_cgb_rom(rom: &[u8]) -> bool {
+    rom.get(0x143).is_some_and(|flag| flag & 0x80 == 0x80)
+}
+
+/// Returns true if the cartridge type (0x0147) has a battery, which keeps the cartridge RAM while the console is off.
+fn has_battery(rom: &[u8]) -> bool {
+    matches!(
+        rom.get(0x147),
+        Some(0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF)
+    )
+}
+
+/// Returns true if the header asks for the SGB features: SGB flag (0x0146) set to 0x03 and old licensee code (0x014B)
+/// set to 0x33.
+fn is_sgb_rom(rom: &[u8]) -> bool {
+    rom.get(0x146) == Some(&0x03) && rom.get(0x14B) == Some(&0x33)
+}
+
+/// Reads the boot rom of `model`, checking its size.
+fn read_boot_rom(model: Model) -> std::io::Result<Vec<u8>> {
+    let bios = std::fs::read(model.boot_rom_path())?;
+    if bios.len() != model.boot_rom_size() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "expected a boot rom of {} bytes but it was {}",
+                model.boot_rom_size(),
+                bios.len()
+            ),
+        ));
+    }
+    Ok(bios)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Memory with the LCD on in `mode`, a byte already written in VRAM, OAM and the CGB palettes.
+    fn setup(mode: u8) -> Mmu {
+        let mut mmu = Mmu::blank_cgb();
+        mmu.set_access_restrictions(false);
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xFE00, 0x34);
+        mmu.write_byte(0xFF68, 0x00);
+        mmu.write_byte(0xFF69, 0x56);
+        mmu.write_byte(0xFF6A, 0x00);
+        mmu.write_byte(0xFF6B, 0x78);
+        mmu.write_byte(0xFF40, 0x80);
+        mmu.set_stat_mode_flag(mode);
+        mmu.set_access_restrictions(true);
+        mmu
+    }
+
+    /// Checks that the CPU reads `expected` at `addr` and that its writes there are ignored while locked.
+    fn assert_access(mmu: &mut Mmu, addr: u16, expected: u8, locked: bool) {
+        let mode = mmu.peek_byte(0xFF41) & 0x3;
+        assert_eq!(
+            mmu.read_byte(addr),
+            if locked { 0xFF } else { expected },
+            "read {:04X} in mode {}",
+            addr,
+            mode
+        );
+        mmu.write_byte(addr, 0xAB);
+        mmu.set_access_restrictions(false);
+        let written = mmu.read_byte(addr);
+        mmu.write_byte(addr, expected);
+        mmu.set_access_restrictions(true);
+        assert_eq!(
+            written,
+            if locked { expected } else { 0xAB },
+            "write {:04X} in mode {}",
+            addr,
+            mode
+        );
+    }
+
+    #[test]
+    fn vram_oam_and_palettes_are_locked_by_mode() {
+        for mode in 0..4 {
+            let mut mmu = setup(mode);
+            assert_access(&mut mmu, 0x8000, 0x12, mode == 3);
+            assert_access(&mut mmu, 0x9FFF, 0x00, mode == 3);
+            assert_access(&mut mmu, 0xFE00, 0x34, mode == 2 || mode == 3);
+            assert_access(&mut mmu, 0xFE9F, 0x00, mode == 2 || mode == 3);
+            assert_access(&mut mmu, 0xFF69, 0x56, mode == 3);
+            assert_access(&mut mmu, 0xFF6B, 0x78, mode == 3);
+            // The rest of the memory and the palette indexes stay accessible
+            assert_access(&mut mmu, 0xC000, 0x00, false);
+            assert_eq!(mmu.read_byte(0xFF68), 0x40);
+        }
+    }
+
+    #[test]
+    fn nothing_is_locked_with_the_lcd_off() {
+        let mut mmu = setup(3);
+        mmu.write_byte(0xFF40, 0x00);
+        assert_access(&mut mmu, 0x8000, 0x12, false);
+        assert_access(&mut mmu, 0xFE00, 0x34, false);
+        assert_access(&mut mmu, 0xFF69, 0x56, false);
+    }
+
+    #[test]
+    fn nothing_is_locked_without_access_restrictions() {
+        let mut mmu = setup(3);
+        mmu.set_access_restrictions(false);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0x34);
+        assert_eq!(mmu.read_byte(0xFF69), 0x56);
+        mmu.write_byte(0x8000, 0xAB);
+        assert_eq!(mmu.read_byte(0x8000), 0xAB);
+    }
+
+    /// Memory with a cartridge of type `cartridge_type` and a byte written in each RAM.
+    fn cartridge(cartridge_type: u8) -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cartridge_type;
+        let mut mmu = Mmu::blank(Model::Dmg);
+        mmu.load_rom(&rom);
+        mmu.skip_boot_rom();
+        for addr in [0x8000, 0xA000, 0xC000, 0xFE00, 0xFF80] {
+            mmu.write_byte(addr, 0x5A);
+        }
+        mmu
+    }
+
+    #[test]
+    fn reset_keeps_the_ram() {
+        let mmu = cartridge(0x00).reset();
+        for addr in [0x8000, 0xA000, 0xC000, 0xFE00, 0xFF80] {
+            assert_eq!(mmu.read_byte(addr), 0x5A, "{:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn power_cycle_only_keeps_the_ram_backed_by_a_battery() {
+        // MBC1+RAM+BATTERY
+        let mmu = cartridge(0x03).power_cycle(Model::Dmg);
+        assert_eq!(mmu.read_byte(0xA000), 0x5A);
+        for addr in [0x8000, 0xC000, 0xFE00, 0xFF80] {
+            assert_eq!(mmu.read_byte(addr), 0x00, "{:04X}", addr);
+        }
+
+        // MBC1+RAM
+        let mmu = cartridge(0x02).power_cycle(Model::Dmg);
+        assert_eq!(mmu.read_byte(0xA000), 0x00);
+    }
+
+    #[test]
+    fn reset_maps_the_first_banks_again() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut mmu = Mmu::blank(Model::Cgb);
+        mmu.load_rom(&rom);
+        mmu.write_byte(0x8000, 0x01);
+        mmu.write_byte(0xD000, 0x01);
+        mmu.write_byte(0xFF4F, 0x01);
+        mmu.write_byte(0xFF70, 0x03);
+        mmu.write_byte(0x8000, 0x02);
+        mmu.write_byte(0xD000, 0x03);
+
+        let mut mmu = mmu.reset();
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF9);
+        assert_eq!(mmu.read_byte(0x8000), 0x01);
+        assert_eq!(mmu.read_byte(0xD000), 0x01);
+        mmu.write_byte(0xFF4F, 0x01);
+        mmu.write_byte(0xFF70, 0x03);
+        assert_eq!(mmu.read_byte(0x8000), 0x02);
+        assert_eq!(mmu.read_byte(0xD000), 0x03);
+    }
+}
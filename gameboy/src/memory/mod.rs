@@ -1 +1,3 @@
+pub mod banks;
+pub mod hdma;
 pub mod mmu;
@@ -0,0 +1,137 @@
+/// Hardware revision to emulate. Each model has its own boot rom, leaves the CPU and DIV in a different state, which
+/// games and test roms use to identify it, and has its own quirks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Model {
+    /// Early DMG revision
+    Dmg0,
+    /// DMG revisions A, B and C
+    #[default]
+    Dmg,
+    /// Game Boy Pocket and Light
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    /// Game Boy Advance, running GB and GBC games
+    Agb,
+}
+
+impl Model {
+    pub const ALL: [Model; 7] = [
+        Model::Dmg0,
+        Model::Dmg,
+        Model::Mgb,
+        Model::Sgb,
+        Model::Sgb2,
+        Model::Cgb,
+        Model::Agb,
+    ];
+
+    /// Number identifying the model in the saved files.
+    pub fn id(&self) -> u8 {
+        Model::ALL
+            .iter()
+            .position(|model| model == self)
+            .unwrap_or(0) as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Model::ALL.get(id as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG-ABC",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Sgb2 => "SGB2",
+            Model::Cgb => "CGB",
+            Model::Agb => "AGB",
+        }
+    }
+
+    /// Picks the model a rom is made for from its header: CGB for the CGB flag, SGB for the SGB flag, DMG otherwise.
+    pub fn from_header(rom: &[u8]) -> Self {
+        if rom.get(0x143).is_some_and(|flag| flag & 0x80 == 0x80) {
+            Model::Cgb
+        } else if rom.get(0x146) == Some(&0x03) && rom.get(0x14B) == Some(&0x33) {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    /// Runs CGB games in CGB mode.
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    /// Handles the SGB commands of the games which support it.
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    /// Path of the boot rom dump expected for this model.
+    pub fn boot_rom_path(&self) -> &'static str {
+        match self {
+            Model::Dmg0 => "resources/dmg0_bios.bin",
+            Model::Dmg => "resources/bios.bin",
+            Model::Mgb => "resources/mgb_bios.bin",
+            Model::Sgb => "resources/sgb_bios.bin",
+            Model::Sgb2 => "resources/sgb2_bios.bin",
+            Model::Cgb => "resources/cgb_bios.bin",
+            Model::Agb => "resources/agb_bios.bin",
+        }
+    }
+
+    /// Size of the boot rom. The CGB one is mapped at 0x0000-0x00FF and 0x0200-0x08FF, around the cartridge header.
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() {
+            0x900
+        } else {
+            0x100
+        }
+    }
+
+    /// AF, BC, DE and HL as the boot rom leaves them. A identifies the model: 0x01 for DMG and SGB, 0xFF for MGB and
+    /// SGB2, 0x11 for CGB and AGB, B bit 0 telling an AGB apart. The DMG boot rom sets H and C unless the header
+    /// checksum is 0, the CGB one leaves different values when running a DMG game.
+    pub fn post_boot_registers(&self, header_checksum: u8, cgb_game: bool) -> [u16; 4] {
+        let half_carry_carry = if header_checksum == 0 { 0x00 } else { 0x30 };
+
+        match self {
+            Model::Dmg0 => [0x0100, 0xFF13, 0x00C1, 0x8403],
+            Model::Dmg => [0x0180 | half_carry_carry, 0x0013, 0x00D8, 0x014D],
+            Model::Mgb => [0xFF80 | half_carry_carry, 0x0013, 0x00D8, 0x014D],
+            Model::Sgb => [0x0100, 0x0014, 0x0000, 0xC060],
+            Model::Sgb2 => [0xFF00, 0x0014, 0x0000, 0xC060],
+            Model::Cgb if cgb_game => [0x1180, 0x0000, 0xFF56, 0x000D],
+            Model::Cgb => [0x1180, 0x0000, 0x0008, 0x007C],
+            Model::Agb if cgb_game => [0x1100, 0x0100, 0xFF56, 0x000D],
+            Model::Agb => [0x1100, 0x0100, 0x0008, 0x007C],
+        }
+    }
+
+    /// System counter of the timer when the boot rom hands over, DIV being its upper byte. The boot roms take a
+    /// different time to run, so games which read DIV early see a different value on each model.
+    pub fn post_boot_div(&self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            // The SGB boot rom waits for the SNES, its duration varies
+            Model::Sgb | Model::Sgb2 => 0x0000,
+            Model::Cgb | Model::Agb => 0x1EA0,
+        }
+    }
+
+    /// Writing to STAT requests the STAT interrupt as if every source was enabled for one cycle.
+    pub fn has_stat_write_bug(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    /// 16-bit increments, decrements and writes in OAM during the OAM scan corrupt the row being scanned.
+    pub fn has_oam_corruption(&self) -> bool {
+        !self.is_cgb()
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Identifies a movie file.
+const MAGIC: &[u8; 4] = b"GBMV";
+/// Incremented whenever the layout of the movie files changes, or the one of the save states the hashes are computed
+/// from. Movies of other versions are rejected.
+pub const VERSION: u16 = 5;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(std::io::Error),
+    /// Not a movie, or a truncated one
+    InvalidFormat,
+    /// Saved by another version of the emulator
+    UnsupportedVersion(u16),
+    /// Recorded with another rom
+    WrongRom,
+    /// Recorded with another boot rom, or with a boot rom missing here
+    WrongBootRom,
+    /// The save state the movie starts from can't be loaded
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "{}", e),
+            MovieError::InvalidFormat => write!(f, "not a valid movie"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "movie version {} is not supported, expected version {}",
+                version, VERSION
+            ),
+            MovieError::WrongRom => write!(f, "the movie was recorded with another rom"),
+            MovieError::WrongBootRom => {
+                write!(f, "the movie was recorded with another boot rom")
+            }
+            MovieError::State(e) => write!(f, "the movie's save state can't be loaded: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<std::io::Error> for MovieError {
+    fn from(e: std::io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+/// Errors of the values read from the movie itself.
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> Self {
+        match e {
+            StateError::Io(e) => MovieError::Io(e),
+            _ => MovieError::InvalidFormat,
+        }
+    }
+}
+
+/// Joypads during a frame, and the state they lead to.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MovieFrame {
+    /// Buttons pressed on each joypad, one bit per button in the order of `Button::ALL`
+    pub input: [u8; 4],
+    /// Hash of the machine at the end of the frame, to detect desyncs. 0 until the frame has been run
+    pub hash: u64,
+}
+
+/// Joypad inputs recorded frame by frame, with what is needed to replay them exactly: the machine they start from
+/// and the settings which change how it runs.
+#[derive(Clone)]
+pub struct Movie {
+    pub rom_checksum: u32,
+    pub model: Model,
+    pub boot_rom_checksum: u32,
+    pub access_restrictions: bool,
+    /// Seed of the RAM contents on power-on, None if the RAM starts zeroed
+    pub ram_seed: Option<u64>,
+    /// Save state the movie starts from, None if it starts from power-on
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+        writer.u32(self.rom_checksum);
+        writer.u8(self.model.id());
+        writer.u32(self.boot_rom_checksum);
+        writer.bool(self.access_restrictions);
+        writer.bool(self.ram_seed.is_some());
+        writer.u64(self.ram_seed.unwrap_or(0));
+        writer.bool(self.start_state.is_some());
+        if let Some(state) = &self.start_state {
+            writer.u32(state.len() as u32);
+            writer.bytes(state);
+        }
+
+        writer.u32(self.frames.len() as u32);
+        for frame in self.frames.iter() {
+            writer.bytes(&frame.input);
+            writer.u64(frame.hash);
+        }
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = StateReader::without_header(data);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(MovieError::InvalidFormat);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_checksum = reader.u32()?;
+        let model = Model::from_id(reader.u8()?).ok_or(MovieError::InvalidFormat)?;
+        let boot_rom_checksum = reader.u32()?;
+        let access_restrictions = reader.bool()?;
+        let has_ram_seed = reader.bool()?;
+        let ram_seed = reader.u64()?;
+        let start_state = if reader.bool()? {
+            let len = reader.u32()? as usize;
+            Some(reader.bytes(len)?.to_vec())
+        } else {
+            None
+        };
+
+        let len = reader.u32()? as usize;
+        let mut frames = Vec::with_capacity(len.min(data.len()));
+        for _ in 0..len {
+            let mut frame = MovieFrame::default();
+            reader.bytes_into(&mut frame.input)?;
+            frame.hash = reader.u64()?;
+            frames.push(frame);
+        }
+        reader.finish()?;
+
+        Ok(Self {
+            rom_checksum,
+            model,
+            boot_rom_checksum,
+            access_restrictions,
+            ram_seed: has_ram_seed.then_some(ram_seed),
+            start_state,
+            frames,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MovieMode {
+    /// The inputs of the player are recorded
+    Recording,
+    /// The inputs of the movie replace the ones of the player
+    Playing,
+    /// Playback reached the end of the movie, or the machine was reset
+    Finished,
+}
+
+/// A movie being recorded or played back.
+pub struct MovieSession {
+    pub movie: Movie,
+    /// Playback records the player past the end of the movie instead of finishing
+    pub extend: bool,
+    mode: MovieMode,
+    /// Frame counter of the GameBoy when the movie starts
+    start_frame: u64,
+    /// Buttons held by the player, recorded from the next frame
+    input: [u8; 4],
+    /// First frame which didn't end in the recorded state
+    desync: Option<usize>,
+}
+
+impl MovieSession {
+    pub fn new(movie: Movie, mode: MovieMode, start_frame: u64, input: [u8; 4]) -> Self {
+        Self {
+            movie,
+            extend: false,
+            mode,
+            start_frame,
+            input,
+            desync: None,
+        }
+    }
+
+    pub fn get_mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MovieMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_start_frame(&self) -> u64 {
+        self.start_frame
+    }
+
+    pub fn get_input(&self) -> [u8; 4] {
+        self.input
+    }
+
+    /// Updates the buttons held by the player, see `MovieFrame::input`.
+    pub fn set_input(&mut self, player: usize, button: usize, pressed: bool) {
+        if pressed {
+            self.input[player] |= 1 << button;
+        } else {
+            self.input[player] &= !(1 << button);
+        }
+    }
+
+    pub fn get_desync(&self) -> Option<usize> {
+        self.desync
+    }
+
+    /// Forgets the states recorded from `frame` on, after the inputs of that frame were edited.
+    pub fn invalidate(&mut self, frame: usize) {
+        for recorded in self.movie.frames.iter_mut().skip(frame) {
+            recorded.hash = 0;
+        }
+        if self.desync.is_some_and(|desync| desync >= frame) {
+            self.desync = None;
+        }
+    }
+
+    /// Compares the state at the end of `frame` with the recorded one, or records it if the frame has not been run.
+    /// The first desync is kept for the frontend, see `get_desync`.
+    pub fn check(&mut self, frame: usize, hash: u64) {
+        let desynced = match self.movie.frames.get_mut(frame) {
+            Some(recorded) if recorded.hash == 0 => {
+                recorded.hash = hash;
+                false
+            }
+            Some(recorded) => recorded.hash != hash,
+            None => false,
+        };
+        if desynced && self.desync.is_none() {
+            self.desync = Some(frame);
+        }
+    }
+}
+
+/// FNV-1a hash of a state.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::joypad::Button;
+    use crate::GameBoy;
+    use std::path::PathBuf;
+
+    const FRAME_SIZE: usize = 160 * 144 * 4;
+    const FRAMES: usize = 45;
+
+    /// Writes a rom which adds the joypad register to 0xC000 in a loop, so that the state depends on every input.
+    fn joypad_rom(name: &str) -> PathBuf {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0160].copy_from_slice(&[
+            0x3E, 0x10, 0xE0, 0x00, // ld a, 0x10 ; ldh (0x00), a
+            0xF0, 0x00, 0x47, // ldh a, (0x00) ; ld b, a
+            0xFA, 0x00, 0xC0, 0x80, 0xEA, 0x00,
+            0xC0, // ld a, (0xC000) ; add b ; ld (0xC000), a
+            0x18, 0xF4, // jr -12
+        ]);
+        let path = std::env::temp_dir().join(format!("gameboy_movie_{}.gb", name));
+        std::fs::write(&path, rom).unwrap();
+        path
+    }
+
+    /// Records `FRAMES` frames, pressing A and Start for a while.
+    fn record(rom: PathBuf) -> (Movie, Vec<u8>) {
+        let mut gameboy = GameBoy::without_bios(rom, Model::Dmg).unwrap();
+        let mut frame = vec![0; FRAME_SIZE];
+        gameboy.record_movie(false, &frame);
+        for i in 0..FRAMES {
+            gameboy.set_button(0, Button::A, (10..20).contains(&i));
+            gameboy.set_button(0, Button::Start, (15..40).contains(&i));
+            gameboy.run_frame(&mut frame);
+        }
+        let end_state = gameboy.save_state(&frame);
+        (gameboy.stop_movie().unwrap(), end_state)
+    }
+
+    /// Replays `movie` to its end, returns the frame it desynced at and the state it ends in.
+    fn replay(rom: PathBuf, movie: Movie) -> (Option<usize>, Vec<u8>) {
+        let mut gameboy = GameBoy::without_bios(rom, Model::Dmg).unwrap();
+        let mut frame = vec![0; FRAME_SIZE];
+        gameboy.play_movie(movie, &mut frame).unwrap();
+        for _ in 0..FRAMES {
+            gameboy.run_frame(&mut frame);
+        }
+        let desync = gameboy.get_movie().and_then(MovieSession::get_desync);
+        (desync, gameboy.save_state(&frame))
+    }
+
+    #[test]
+    fn movie_reads_back_from_bytes() {
+        let (movie, _) = record(joypad_rom("bytes"));
+        let bytes = movie.to_bytes();
+        let read = Movie::from_bytes(&bytes).unwrap();
+
+        assert_eq!(read.rom_checksum, movie.rom_checksum);
+        assert_eq!(read.model, movie.model);
+        assert_eq!(read.start_state, movie.start_state);
+        assert_eq!(read.frames, movie.frames);
+        assert_eq!(read.to_bytes(), bytes);
+        assert!(matches!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::InvalidFormat)
+        ));
+    }
+
+    #[test]
+    fn replayed_movie_stays_in_sync() {
+        let rom = joypad_rom("replay");
+        let (movie, end_state) = record(rom.clone());
+        assert_eq!(movie.frames.len(), FRAMES);
+        assert!(movie.frames.iter().all(|frame| frame.hash != 0));
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let (desync, replay_state) = replay(rom, movie);
+        assert_eq!(desync, None);
+        assert_eq!(replay_state, end_state);
+    }
+
+    #[test]
+    fn edited_movie_desyncs() {
+        let rom = joypad_rom("edited");
+        let (mut movie, _) = record(rom.clone());
+        movie.frames[30].input[0] ^= 1 << Button::B as u8;
+
+        let (desync, _) = replay(rom, movie);
+        assert_eq!(desync, Some(30));
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::VecDeque;
+
+/// History of save states to run the game backwards.
+/// Only the latest snapshot is kept whole. Each older one is stored as the XOR with the snapshot which follows it,
+/// compressed by runs of zeros: two consecutive frames share most of their state, so a snapshot usually takes a few
+/// KB instead of the size of a full save state.
+pub struct Rewind {
+    /// Frames between two snapshots
+    pub interval: u32,
+    /// Snapshots stepped back for each frame displayed while rewinding
+    pub rate: usize,
+    pub enabled: bool,
+    /// Maximum number of snapshots
+    depth: usize,
+    latest: Option<Vec<u8>>,
+    /// `deltas[i]` turns snapshot `i + 1` back into snapshot `i`, the oldest first
+    deltas: VecDeque<Vec<u8>>,
+    /// Snapshot selected on the timeline, newer snapshots are dropped when the game runs again
+    position: Option<usize>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        let mut rewind = Self {
+            interval: 4,
+            rate: 1,
+            enabled: true,
+            depth: 1,
+            latest: None,
+            deltas: VecDeque::new(),
+            position: None,
+        };
+        rewind.set_duration(60.);
+        rewind
+    }
+}
+
+impl Rewind {
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Sets the maximum number of snapshots, dropping the oldest ones if needed.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.max(1);
+        self.drop_oldest();
+    }
+
+    /// Seconds of game the history goes back when it is full.
+    pub fn get_duration(&self) -> f64 {
+        self.depth as f64 * self.interval.max(1) as f64 / crate::REFRESH_RATE
+    }
+
+    /// Sets the depth for the history to go back `seconds` of game with the current interval.
+    pub fn set_duration(&mut self, seconds: f64) {
+        self.set_depth(
+            (seconds * crate::REFRESH_RATE / self.interval.max(1) as f64).round() as usize,
+        );
+    }
+
+    /// Number of snapshots in the history.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Returns the snapshot selected on the timeline, if any.
+    pub fn get_position(&self) -> Option<usize> {
+        self.position
+    }
+
+    /// Memory used by the history, in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.position = None;
+    }
+
+    /// Adds a snapshot after the latest one, or after the one selected on the timeline.
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(position) = self.position.take() {
+            self.truncate(position + 1);
+        }
+
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(delta(&snapshot, &latest));
+        }
+        self.latest = Some(snapshot);
+        self.drop_oldest();
+    }
+
+    /// Removes the latest snapshot and returns it, so that the next call returns the one before.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if let Some(position) = self.position.take() {
+            self.truncate(position + 1);
+        }
+
+        let latest = self.latest.take()?;
+        self.latest = self.deltas.pop_back().map(|delta| apply(&latest, &delta));
+        Some(latest)
+    }
+
+    /// Returns snapshot `index`, 0 being the oldest, and selects it on the timeline.
+    pub fn seek(&mut self, index: usize) -> Option<Vec<u8>> {
+        let mut snapshot = self.latest.clone()?;
+        for delta in self.deltas.iter().skip(index).rev() {
+            snapshot = apply(&snapshot, delta);
+        }
+        self.position = Some(index.min(self.deltas.len()));
+        Some(snapshot)
+    }
+
+    /// Keeps the `len` oldest snapshots.
+    fn truncate(&mut self, len: usize) {
+        while self.len() > len.max(1) {
+            self.pop();
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        while self.len() > self.depth {
+            self.deltas.pop_front();
+        }
+    }
+}
+
+/// Encodes how to turn `from` into `to`: length of `to`, then the XOR of both, as runs of zeros followed by literal
+/// bytes. Each run starts with the number of zeros and the number of literals, as LEB128.
+pub fn delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let len = from.len().max(to.len());
+    let byte = |data: &[u8], i: usize| data.get(i).copied().unwrap_or(0);
+
+    let mut encoded = Vec::new();
+    write_varint(&mut encoded, to.len());
+
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && byte(from, i) == byte(to, i) {
+            i += 1;
+        }
+        let literals_start = i;
+        while i < len && byte(from, i) != byte(to, i) {
+            i += 1;
+        }
+
+        write_varint(&mut encoded, literals_start - zeros_start);
+        write_varint(&mut encoded, i - literals_start);
+        encoded.extend((literals_start..i).map(|j| byte(from, j) ^ byte(to, j)));
+    }
+
+    encoded
+}
+
+/// Turns `from` into the snapshot encoded by `delta`.
+pub fn apply(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut delta = delta.iter().copied();
+    let len = read_varint(&mut delta);
+
+    let mut snapshot = from.to_vec();
+    snapshot.resize(snapshot.len().max(len), 0);
+
+    let mut i = 0;
+    while i < snapshot.len() {
+        let zeros = read_varint(&mut delta);
+        let literals = read_varint(&mut delta);
+        if zeros == 0 && literals == 0 {
+            break;
+        }
+        // A corrupted delta may point past the end of the snapshot, those bytes are dropped
+        i = i.saturating_add(zeros);
+        for byte in snapshot.iter_mut().skip(i).take(literals) {
+            *byte ^= delta.next().unwrap_or(0);
+        }
+        i = i.saturating_add(literals);
+    }
+
+    snapshot.truncate(len);
+    snapshot
+}
+
+fn write_varint(data: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        data.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    data.push(val as u8);
+}
+
+fn read_varint(data: &mut impl Iterator<Item = u8>) -> usize {
+    let mut val = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = match data.next() {
+            Some(byte) => byte,
+            None => break,
+        };
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    val
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::GameBoy;
+
+    #[test]
+    fn varints_read_back() {
+        let values = [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, usize::MAX];
+        let mut data = Vec::new();
+        for val in values {
+            write_varint(&mut data, val);
+        }
+        let mut data = data.into_iter();
+        for val in values {
+            assert_eq!(read_varint(&mut data), val);
+        }
+        assert_eq!(data.next(), None);
+    }
+
+    #[test]
+    fn delta_applies_back_to_the_target() {
+        let from: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut changed = from.clone();
+        changed[0] ^= 1;
+        // Runs longer than a single varint byte
+        changed[300..500].iter_mut().for_each(|byte| *byte = !*byte);
+        changed[999] = 0;
+
+        let targets = [
+            from.clone(),
+            changed.clone(),
+            changed[..600].to_vec(),
+            [changed.as_slice(), &[1, 2, 3]].concat(),
+            Vec::new(),
+        ];
+        for to in targets {
+            assert_eq!(apply(&from, &delta(&from, &to)), to);
+        }
+    }
+
+    #[test]
+    fn corrupted_delta_does_not_panic() {
+        let from = vec![0; 16];
+        let mut corrupted = Vec::new();
+        write_varint(&mut corrupted, 16);
+        write_varint(&mut corrupted, 10);
+        write_varint(&mut corrupted, 100);
+        corrupted.extend([0xFF; 100]);
+        let snapshot = apply(&from, &corrupted);
+        assert_eq!(snapshot.len(), 16);
+        assert_eq!(snapshot[..10], [0; 10]);
+        assert_eq!(snapshot[10..], [0xFF; 6]);
+
+        assert_eq!(apply(&from, &[0x80, 0x80, 0x80]).len(), 0);
+    }
+
+    #[test]
+    fn default_depth_is_one_minute() {
+        let rewind = Rewind::default();
+        assert_eq!(rewind.get_depth(), 896);
+        assert!((rewind.get_duration() - 60.).abs() < 0.1);
+    }
+
+    #[test]
+    fn rewinding_skips_the_snapshot_of_the_frame_on_screen() {
+        let mut gameboy = GameBoy::new(Model::Dmg);
+        let mut frame = vec![0; 160 * 144 * 4];
+        gameboy.rewind.interval = 2;
+        for _ in 0..6 {
+            gameboy.run_frame(&mut frame);
+        }
+
+        // Snapshots of frames 2, 4 and 6, the last one is on screen
+        assert!(gameboy.rewind(&mut frame));
+        assert_eq!(gameboy.get_frame_count(), 4);
+        gameboy.run_frame(&mut frame);
+        assert!(gameboy.rewind(&mut frame));
+        assert_eq!(gameboy.get_frame_count(), 2);
+        assert!(!gameboy.rewind(&mut frame));
+
+        for _ in 0..4 {
+            gameboy.run_frame(&mut frame);
+        }
+        // Snapshots of frames 4 and 6, the one seeked to is on screen
+        assert!(gameboy.rewind_seek(0, &mut frame));
+        assert_eq!(gameboy.get_frame_count(), 4);
+        assert!(gameboy.rewind_seek(1, &mut frame));
+        assert_eq!(gameboy.get_frame_count(), 6);
+        assert!(gameboy.rewind(&mut frame));
+        assert_eq!(gameboy.get_frame_count(), 4);
+    }
+}
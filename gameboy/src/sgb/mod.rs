@@ -0,0 +1,3 @@
+pub mod packet;
+#[allow(clippy::module_inception)]
+pub mod sgb;
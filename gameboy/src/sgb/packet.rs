@@ -0,0 +1,201 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// Size of a packet in bytes.
+pub const PACKET_SIZE: usize = 16;
+
+/// Receives the SGB command packets sent bit by bit through P1.
+/// A packet starts with a reset pulse (P14 and P15 low), then each bit is a pulse of P14 (0) or P15 (1) followed by
+/// both lines high, least significant bit first. A 0 stop bit follows the 128 bits of the packet.
+/// The first byte of a command is `command << 3 | number of packets`.
+pub struct PacketReceiver {
+    packet: [u8; PACKET_SIZE],
+    /// Bits received in the current packet, None while waiting for a reset pulse
+    bits: Option<usize>,
+    /// The lines went back high since the last pulse
+    ready: bool,
+    command: Vec<u8>,
+    /// Packets left to receive for the current command
+    packets_left: u8,
+}
+
+impl Default for PacketReceiver {
+    fn default() -> Self {
+        Self {
+            packet: [0; PACKET_SIZE],
+            bits: None,
+            ready: false,
+            command: Vec::with_capacity(PACKET_SIZE * 7),
+            packets_left: 0,
+        }
+    }
+}
+
+impl PacketReceiver {
+    /// Handles a write to P1. Returns the data of a command once all its packets are received.
+    pub fn write(&mut self, val: u8) -> Option<Vec<u8>> {
+        match val & 0x30 {
+            0x00 => {
+                // Reset pulse: a new packet starts
+                self.packet = [0; PACKET_SIZE];
+                self.bits = Some(0);
+                self.ready = false;
+                None
+            }
+            0x30 => {
+                self.ready = true;
+                None
+            }
+            pulse if self.ready => {
+                self.ready = false;
+                self.receive_bit(pulse == 0x10)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true while a packet is being received.
+    pub fn is_receiving(&self) -> bool {
+        self.bits.is_some()
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<Vec<u8>> {
+        let bits = self.bits?;
+
+        if bits == PACKET_SIZE * 8 {
+            // Stop bit, it must be 0 for the packet to be valid
+            self.bits = None;
+            if bit {
+                return None;
+            }
+            return self.receive_packet();
+        }
+
+        if bit {
+            self.packet[bits / 8] |= 1 << (bits % 8);
+        }
+        self.bits = Some(bits + 1);
+        None
+    }
+
+    fn receive_packet(&mut self) -> Option<Vec<u8>> {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = (self.packet[0] & 0x07).max(1);
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+        if self.packets_left > 0 {
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.command))
+    }
+}
+
+impl SaveState for PacketReceiver {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.packet);
+        state.bool(self.bits.is_some());
+        state.u8(self.bits.unwrap_or(0) as u8);
+        state.bool(self.ready);
+        state.u8(self.command.len() as u8);
+        state.bytes(&self.command);
+        state.u8(self.packets_left);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(&mut self.packet)?;
+        let receiving = state.bool()?;
+        let bits = state.u8()? as usize;
+        if bits > PACKET_SIZE * 8 {
+            return Err(StateError::InvalidFormat);
+        }
+        self.bits = receiving.then_some(bits);
+        self.ready = state.bool()?;
+        let command_size = state.u8()? as usize;
+        self.command = state.bytes(command_size)?.to_vec();
+        self.packets_left = state.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a packet bit by bit, followed by `stop_bit`. Returns the command if it is complete.
+    fn send(
+        receiver: &mut PacketReceiver,
+        packet: &[u8; PACKET_SIZE],
+        stop_bit: bool,
+    ) -> Option<Vec<u8>> {
+        receiver.write(0x00);
+        receiver.write(0x30);
+        for bit in 0..PACKET_SIZE * 8 {
+            let one = (packet[bit / 8] >> (bit % 8)) & 1 == 1;
+            assert_eq!(receiver.write(if one { 0x10 } else { 0x20 }), None);
+            receiver.write(0x30);
+        }
+        let command = receiver.write(if stop_bit { 0x10 } else { 0x20 });
+        receiver.write(0x30);
+        command
+    }
+
+    fn packet(first: u8, fill: u8) -> [u8; PACKET_SIZE] {
+        let mut packet = [fill; PACKET_SIZE];
+        packet[0] = first;
+        packet
+    }
+
+    #[test]
+    fn single_packet_command() {
+        let mut receiver = PacketReceiver::default();
+        let first = packet(0x11 << 3 | 1, 0xA5);
+        assert_eq!(send(&mut receiver, &first, false), Some(first.to_vec()));
+        assert!(!receiver.is_receiving());
+    }
+
+    #[test]
+    fn command_is_complete_after_its_last_packet() {
+        let mut receiver = PacketReceiver::default();
+        let first = packet(0x04 << 3 | 3, 0x11);
+        let second = packet(0x22, 0x22);
+        let third = packet(0x33, 0x33);
+        assert_eq!(send(&mut receiver, &first, false), None);
+        assert_eq!(send(&mut receiver, &second, false), None);
+
+        let command = send(&mut receiver, &third, false).unwrap();
+        assert_eq!(command, [first, second, third].concat());
+
+        // The count of a packet following a complete command starts a new one
+        let next = packet(0x11 << 3 | 1, 0x00);
+        assert_eq!(send(&mut receiver, &next, false), Some(next.to_vec()));
+    }
+
+    #[test]
+    fn packet_with_a_stop_bit_of_1_is_dropped() {
+        let mut receiver = PacketReceiver::default();
+        let first = packet(0x11 << 3 | 1, 0x01);
+        assert_eq!(send(&mut receiver, &first, true), None);
+        assert!(!receiver.is_receiving());
+        assert_eq!(send(&mut receiver, &first, false), Some(first.to_vec()));
+    }
+
+    #[test]
+    fn bits_need_the_lines_back_high_between_pulses() {
+        let mut receiver = PacketReceiver::default();
+        receiver.write(0x00);
+        receiver.write(0x30);
+        // Holding P15 low is a single 1 bit
+        for _ in 0..4 {
+            receiver.write(0x10);
+        }
+        receiver.write(0x30);
+        for _ in 1..PACKET_SIZE * 8 {
+            receiver.write(0x20);
+            receiver.write(0x30);
+        }
+        assert_eq!(receiver.write(0x20), Some(packet(0x01, 0x00).to_vec()));
+    }
+}
@@ -0,0 +1,639 @@
+use crate::gpu::palette;
+use crate::sgb::packet::PacketReceiver;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+/// Position of the game screen in the SGB output
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+/// The SGB palettes apply to 8x8 cells of the game screen
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = 90;
+const BORDER_TILE_SIZE: usize = 32;
+
+/// Palette 1-A of the SGB, used until the game sends its own.
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// Data copied from the game screen on the next frame.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    /// PAL_TRN: the 512 system palettes
+    Palettes,
+    /// CHR_TRN: 128 border tiles, the upper half if true
+    BorderTiles(bool),
+    /// PCT_TRN: border map and palettes 4-7
+    Border,
+    /// ATTR_TRN: the 45 attribute files
+    Attributes,
+}
+
+/// MASK_EN: what the SGB shows in place of the game screen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mask {
+    None,
+    /// Keep showing the last frame
+    Freeze,
+    Black,
+    /// Fill with color 0
+    Color0,
+}
+
+/// Super Game Boy. Receives the commands sent by the game through P1, colorizes the 4 shades of the game screen by
+/// 8x8 cells with 4 palettes, and surrounds it with a border.
+pub struct Sgb {
+    receiver: PacketReceiver,
+    /// P1 as last written, to detect the joypad switches
+    joypad_select: u8,
+    /// 15-bit colors of palettes 0-3. Color 0 is shared by all of them.
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    /// Palette of each 8x8 cell of the game screen
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    /// 4bpp tiles of the border, in the SNES format
+    border_tiles: Vec<u8>,
+    /// 32x28 border map: tile in bits 0-7, palette 4-7 in bits 10-12, X flip in bit 14, Y flip in bit 15
+    border_map: Vec<u16>,
+    /// Border palettes 4-7, color 0 is transparent
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+    /// Number of joypads enabled by MLT_REQ
+    players: u8,
+    player: u8,
+    pending_transfer: Option<Transfer>,
+    /// RGBA output, border included
+    frame: Vec<u8>,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self {
+            receiver: PacketReceiver::default(),
+            joypad_select: 0x30,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            border_tiles: vec![0; 256 * BORDER_TILE_SIZE],
+            border_map: vec![0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            players: 1,
+            player: 0,
+            pending_transfer: None,
+            frame: vec![0; SGB_WIDTH * SGB_HEIGHT * 4],
+        }
+    }
+}
+
+impl Sgb {
+    /// Handles a write to P1: command packets, and switching between the joypads when multiplayer is enabled.
+    pub fn write_joypad(&mut self, val: u8) {
+        let receiving = self.receiver.is_receiving();
+        if let Some(command) = self.receiver.write(val) {
+            self.execute(&command);
+        }
+
+        // The next joypad is read after P15 goes from low to high
+        let rising = self.joypad_select & 0x20 == 0 && val & 0x20 == 0x20;
+        if self.players > 1 && rising && !receiving {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.joypad_select = val & 0x30;
+    }
+
+    pub fn get_players(&self) -> u8 {
+        self.players
+    }
+
+    pub fn get_player(&self) -> u8 {
+        self.player
+    }
+
+    pub fn get_mask(&self) -> Mask {
+        self.mask
+    }
+
+    /// Returns the 256x224 RGBA output.
+    pub fn get_frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Called once the game has drawn a frame, with the shade (0-3) of each pixel of the game screen.
+    /// Runs the pending VRAM transfer, which reads its data from that frame, then renders the output.
+    pub fn end_frame(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.pending_transfer.take() {
+            self.transfer(transfer, &screen_data(shades));
+        }
+
+        if self.mask != Mask::Freeze {
+            self.render(shades);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.pending_transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            0x13 => self.pending_transfer = Some(Transfer::BorderTiles(data[1] & 1 == 1)),
+            0x14 => self.pending_transfer = Some(Transfer::Border),
+            0x15 => self.pending_transfer = Some(Transfer::Attributes),
+            0x16 => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 == 0x40 {
+                    self.mask = Mask::None;
+                }
+            }
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::None,
+                }
+            }
+            // Sound, SNES memory and the other commands are not emulated
+            _ => (),
+        }
+    }
+
+    /// PAL01, PAL23, PAL03 and PAL12: color 0 for all palettes, then colors 1-3 of both palettes.
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    /// ATTR_BLK: sets the palette inside, on the border and outside of rectangles.
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+
+        for set in data[2..].chunks_exact(6).take(count) {
+            let mut control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let mut border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            // When only the inside or only the outside is changed, the border takes the same palette
+            if control == 0x01 {
+                control |= 0x02;
+                border = inside;
+            } else if control == 0x04 {
+                control |= 0x02;
+                border = outside;
+            }
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let in_rectangle = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = in_rectangle && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let palette = if on_border {
+                        (control & 0x02 == 0x02).then_some(border)
+                    } else if in_rectangle {
+                        (control & 0x01 == 0x01).then_some(inside)
+                    } else {
+                        (control & 0x04 == 0x04).then_some(outside)
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: sets the palette of whole rows or columns.
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+
+        for &line in data[2..].iter().take(count) {
+            let position = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+
+            if line & 0x80 == 0x80 {
+                if position < CELLS_Y {
+                    self.attributes[position * CELLS_X..(position + 1) * CELLS_X].fill(palette);
+                }
+            } else if position < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + position] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV: splits the screen in two with a line, each part and the line having their own palette.
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 == 0x40;
+        let line = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    /// ATTR_CHR: sets the palette of consecutive cells, 2 bits per cell.
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 1 == 1;
+
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => return,
+            };
+            if x >= CELLS_X || y >= CELLS_Y {
+                return;
+            }
+
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// PAL_SET: copies 4 of the system palettes to palettes 0-3, and optionally applies an attribute file.
+    fn pal_set(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let index =
+                u16::from_le_bytes([data[1 + palette * 2], data[2 + palette * 2]]) as usize & 0x1FF;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[index * 4..index * 4 + 4]);
+        }
+        // Color 0 of palette 0 is used by all palettes
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+
+        if data[9] & 0x80 == 0x80 {
+            self.apply_attribute_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 == 0x40 {
+            self.mask = Mask::None;
+        }
+    }
+
+    /// Copies an attribute file, 2 bits per cell, to the palette of the cells.
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+
+        let data =
+            &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..(file + 1) * ATTRIBUTE_FILE_SIZE];
+        for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (data[cell / 4] >> (6 - (cell % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (color, bytes) in self.system_palettes.iter_mut().zip(data.chunks_exact(2)) {
+                    *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            Transfer::BorderTiles(upper) => {
+                let start = if upper { 128 * BORDER_TILE_SIZE } else { 0 };
+                self.border_tiles[start..start + 128 * BORDER_TILE_SIZE].copy_from_slice(data);
+            }
+            Transfer::Border => {
+                for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks_exact(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                for (i, bytes) in data[0x800..0x880].chunks_exact(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    fn render(&mut self, shades: &[u8]) {
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let in_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+
+                let color = match self.border_color(x, y) {
+                    Some(color) => color,
+                    None if in_screen => self.screen_color(x - SCREEN_X, y - SCREEN_Y, shades),
+                    None => self.palettes[0][0],
+                };
+
+                let index = (y * SGB_WIDTH + x) * 4;
+                self.frame[index..index + 4].copy_from_slice(&palette::cgb_color(color, false));
+            }
+        }
+    }
+
+    fn screen_color(&self, x: usize, y: usize, shades: &[u8]) -> u16 {
+        match self.mask {
+            Mask::Black => 0,
+            Mask::Color0 => self.palettes[0][0],
+            Mask::None | Mask::Freeze => {
+                let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                self.palettes[palette][shades[y * SCREEN_WIDTH + x] as usize]
+            }
+        }
+    }
+
+    /// Returns the color of the border at (x, y), None if it is transparent.
+    fn border_color(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile =
+            &self.border_tiles[(entry & 0xFF) as usize * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let palette = ((entry >> 10) & 0x03) as usize;
+
+        let row = if entry & 0x8000 == 0x8000 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        let bit = if entry & 0x4000 == 0x4000 {
+            x % 8
+        } else {
+            7 - x % 8
+        };
+
+        let color = (0..4).fold(0, |color, plane| {
+            let byte = tile[(plane / 2) * 16 + row * 2 + plane % 2];
+            color | ((byte >> bit) & 1) << plane
+        });
+
+        (color != 0).then(|| self.border_palettes[palette][color as usize])
+    }
+}
+
+impl SaveState for Sgb {
+    fn save(&self, state: &mut StateWriter) {
+        self.receiver.save(state);
+        state.u8(self.joypad_select);
+        for color in self.palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        for color in self.system_palettes.iter() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.bytes(&self.attribute_files);
+        state.bytes(&self.border_tiles);
+        for entry in self.border_map.iter() {
+            state.u16(*entry);
+        }
+        for color in self.border_palettes.iter().flatten() {
+            state.u16(*color);
+        }
+        state.u8(match self.mask {
+            Mask::None => 0,
+            Mask::Freeze => 1,
+            Mask::Black => 2,
+            Mask::Color0 => 3,
+        });
+        state.u8(self.players);
+        state.u8(self.player);
+        state.u8(match self.pending_transfer {
+            None => 0,
+            Some(Transfer::Palettes) => 1,
+            Some(Transfer::BorderTiles(false)) => 2,
+            Some(Transfer::BorderTiles(true)) => 3,
+            Some(Transfer::Border) => 4,
+            Some(Transfer::Attributes) => 5,
+        });
+        state.bytes(&self.frame);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.receiver.load(state)?;
+        self.joypad_select = state.u8()?;
+        for color in self.palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        for color in self.system_palettes.iter_mut() {
+            *color = state.u16()?;
+        }
+        state.bytes_into(&mut self.attributes)?;
+        state.bytes_into(&mut self.attribute_files)?;
+        state.bytes_into(&mut self.border_tiles)?;
+        for entry in self.border_map.iter_mut() {
+            *entry = state.u16()?;
+        }
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = state.u16()?;
+        }
+        self.mask = match state.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.players = match state.u8()? {
+            players @ (1 | 2 | 4) => players,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.player = match state.u8()? {
+            player if player < self.players => player,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.pending_transfer = match state.u8()? {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::BorderTiles(false)),
+            3 => Some(Transfer::BorderTiles(true)),
+            4 => Some(Transfer::Border),
+            5 => Some(Transfer::Attributes),
+            _ => return Err(StateError::InvalidFormat),
+        };
+        if self.attributes.iter().any(|palette| *palette > 3) {
+            return Err(StateError::InvalidFormat);
+        }
+        state.bytes_into(&mut self.frame)
+    }
+}
+
+/// Reads the 4KB of a VRAM transfer back from the game screen: its first 256 tiles, 20 per row, as 2bpp tiles.
+fn screen_data(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; 0x1000];
+
+    for tile in 0..256 {
+        let (tile_x, tile_y) = (tile % CELLS_X, tile / CELLS_X);
+        for row in 0..8 {
+            let line = &shades[(tile_y * 8 + row) * SCREEN_WIDTH + tile_x * 8..][..8];
+            for (pixel, shade) in line.iter().enumerate() {
+                data[tile * 16 + row * 2] |= (shade & 1) << (7 - pixel);
+                data[tile * 16 + row * 2 + 1] |= ((shade >> 1) & 1) << (7 - pixel);
+            }
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::joypad::Button;
+    use crate::memory::mmu::Mmu;
+    use crate::sgb::packet::PACKET_SIZE;
+
+    /// Sends `command`, padded to whole packets, through P1 the way the games do.
+    fn send(mmu: &mut Mmu, command: &[u8]) {
+        let mut data = command.to_vec();
+        data.resize(data.len().div_ceil(PACKET_SIZE) * PACKET_SIZE, 0);
+        for packet in data.chunks_exact(PACKET_SIZE) {
+            mmu.write_byte(0xFF00, 0x00);
+            mmu.write_byte(0xFF00, 0x30);
+            for bit in 0..PACKET_SIZE * 8 + 1 {
+                let one = packet
+                    .get(bit / 8)
+                    .is_some_and(|byte| (byte >> (bit % 8)) & 1 == 1);
+                mmu.write_byte(0xFF00, if one { 0x10 } else { 0x20 });
+                mmu.write_byte(0xFF00, 0x30);
+            }
+        }
+    }
+
+    fn sgb(mmu: &Mmu) -> &Sgb {
+        mmu.get_sgb().unwrap()
+    }
+
+    #[test]
+    fn pal01_sets_palettes_0_and_1_and_the_shared_color_0() {
+        let mut mmu = Mmu::blank_sgb();
+        let colors: [u16; 7] = [0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777];
+        // PAL01 is command 0x00, in 1 packet
+        let mut command = vec![0x01];
+        command.extend(colors.iter().flat_map(|color| color.to_le_bytes()));
+        send(&mut mmu, &command);
+
+        let palettes = sgb(&mmu).palettes;
+        assert_eq!(palettes[0], [0x1111, 0x2222, 0x3333, 0x4444]);
+        assert_eq!(palettes[1], [0x1111, 0x5555, 0x6666, 0x7777]);
+        for palette in &palettes[2..] {
+            assert_eq!(palette[0], 0x1111);
+            assert_eq!(palette[1..], DEFAULT_PALETTE[1..]);
+        }
+    }
+
+    #[test]
+    fn attr_blk_sets_the_inside_the_border_and_the_outside() {
+        let mut mmu = Mmu::blank_sgb();
+        // Inside 1, border 2 and outside 3 of the cells (2, 3)-(5, 7)
+        send(&mut mmu, &[0x04 << 3 | 1, 1, 0x07, 0x39, 2, 3, 5, 7]);
+
+        let attributes = sgb(&mmu).attributes;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let expected = match (x, y) {
+                    (3..=4, 4..=6) => 1,
+                    (2..=5, 3..=7) => 2,
+                    _ => 3,
+                };
+                assert_eq!(attributes[y * CELLS_X + x], expected, "cell ({}, {})", x, y);
+            }
+        }
+
+        // Changing only the inside also changes the border, the rest is kept
+        send(&mut mmu, &[0x04 << 3 | 1, 1, 0x01, 0x00, 0, 0, 3, 3]);
+        let attributes = sgb(&mmu).attributes;
+        assert_eq!(attributes[0], 0);
+        assert_eq!(attributes[3 * CELLS_X + 3], 0);
+        assert_eq!(attributes[3 * CELLS_X + 4], 2);
+        assert_eq!(attributes[CELLS_X * CELLS_Y - 1], 3);
+    }
+
+    #[test]
+    fn mlt_req_switches_the_joypad_on_p15_rising_edges() {
+        let mut mmu = Mmu::blank_sgb();
+        mmu.set_button(1, Button::Right, true);
+        mmu.set_button(3, Button::Start, true);
+        assert_eq!(mmu.read_byte(0xFF00), 0xFF);
+
+        send(&mut mmu, &[0x11 << 3 | 1, 0x03]);
+        assert_eq!(sgb(&mmu).get_players(), 4);
+
+        // With nothing selected, P1 reads the ID of the joypad: 0xF minus the player
+        for player in [0, 1, 2, 3, 0] {
+            assert_eq!(mmu.read_byte(0xFF00), 0xFF - player);
+            mmu.write_byte(0xFF00, 0x20);
+            let directions = mmu.read_byte(0xFF00) & 0x0F;
+            assert_eq!(directions, if player == 1 { 0x0E } else { 0x0F });
+            mmu.write_byte(0xFF00, 0x10);
+            let buttons = mmu.read_byte(0xFF00) & 0x0F;
+            assert_eq!(buttons, if player == 3 { 0x07 } else { 0x0F });
+            mmu.write_byte(0xFF00, 0x30);
+        }
+
+        // Back to a single joypad
+        send(&mut mmu, &[0x11 << 3 | 1, 0x00]);
+        assert_eq!(sgb(&mmu).get_players(), 1);
+        assert_eq!(mmu.read_byte(0xFF00), 0xFF);
+    }
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+
+/// Identifies a save state file.
+const MAGIC: &[u8; 4] = b"GBSS";
+/// Incremented whenever the layout of the saved state changes. States of other versions are rejected.
+pub const VERSION: u16 = 7;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(std::io::Error),
+    /// Not a save state, or a truncated one
+    InvalidFormat,
+    /// Saved by another version of the emulator
+    UnsupportedVersion(u16),
+    /// Saved with another rom
+    WrongRom,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::InvalidFormat => write!(f, "not a valid save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported, expected version {}",
+                version, VERSION
+            ),
+            StateError::WrongRom => write!(f, "the save state was made with another rom"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<std::io::Error> for StateError {
+    fn from(e: std::io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+/// A part of the machine which can be saved and restored. `load` reads back exactly what `save` wrote.
+pub trait SaveState {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Builds a save state. Values are written in little endian, one after the other.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Starts a save state with its header: magic, version, checksum of the rom and thumbnail.
+    pub fn new(rom_checksum: u32, thumbnail: &[u8]) -> Self {
+        let mut state = Self::default();
+        state.bytes(MAGIC);
+        state.u16(VERSION);
+        state.u32(rom_checksum);
+        state.u32(thumbnail.len() as u32);
+        state.bytes(thumbnail);
+        state
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    /// Writes bytes of a known length, without their length.
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.data.extend_from_slice(val);
+    }
+
+    /// Writes a list of values, preceded by its length.
+    pub fn items<'b, T: SaveState + 'b>(&mut self, items: impl ExactSizeIterator<Item = &'b T>) {
+        self.u32(items.len() as u32);
+        for item in items {
+            item.save(self);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back a save state built by `StateWriter`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header of the save state and skips the thumbnail.
+    pub fn new(data: &'a [u8], rom_checksum: u32) -> Result<Self, StateError> {
+        let mut state = Self { data };
+        state.header()?;
+        if state.u32()? != rom_checksum {
+            return Err(StateError::WrongRom);
+        }
+        let thumbnail_size = state.u32()? as usize;
+        state.bytes(thumbnail_size)?;
+        Ok(state)
+    }
+
+    /// Reads data which has no save state header, such as the other files made of the same values.
+    pub fn without_header(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Returns the thumbnail of a save state, without checking which rom it belongs to.
+    pub fn thumbnail(data: &'a [u8]) -> Result<&'a [u8], StateError> {
+        let mut state = Self { data };
+        state.header()?;
+        state.u32()?;
+        let thumbnail_size = state.u32()? as usize;
+        state.bytes(thumbnail_size)
+    }
+
+    fn header(&mut self) -> Result<(), StateError> {
+        if self.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::InvalidFormat);
+        }
+        let version = self.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        self.bytes_into(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        self.bytes_into(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        self.bytes_into(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidFormat),
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::InvalidFormat);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Fills `val` with the next bytes.
+    pub fn bytes_into(&mut self, val: &mut [u8]) -> Result<(), StateError> {
+        val.copy_from_slice(self.bytes(val.len())?);
+        Ok(())
+    }
+
+    /// Reads back a list written by `StateWriter::items`.
+    pub fn items<T: SaveState + Default>(&mut self) -> Result<Vec<T>, StateError> {
+        let len = self.u32()? as usize;
+        if len > self.data.len() {
+            return Err(StateError::InvalidFormat);
+        }
+        (0..len)
+            .map(|_| {
+                let mut item = T::default();
+                item.load(self).map(|_| item)
+            })
+            .collect()
+    }
+
+    /// Fails if the state has data left once everything has been loaded.
+    pub fn finish(&self) -> Result<(), StateError> {
+        if !self.data.is_empty() {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 of the rom, stored in the save states to reject the ones of other games.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+    use crate::GameBoy;
+
+    const FRAME_SIZE: usize = 160 * 144 * 4;
+
+    /// A gameboy a few frames into the run, and the last frame it drew.
+    fn running_gameboy() -> (GameBoy, Vec<u8>) {
+        let mut gameboy = GameBoy::new(Model::Dmg);
+        let mut frame = vec![0; FRAME_SIZE];
+        for _ in 0..3 {
+            gameboy.run_frame(&mut frame);
+        }
+        (gameboy, frame)
+    }
+
+    /// Loads `state` and checks that it is rejected and that the machine is left as it was.
+    fn load_rejected(state: &[u8]) -> StateError {
+        let (mut gameboy, mut frame) = running_gameboy();
+        let before = gameboy.save_state(&frame);
+        let error = gameboy.load_state(state, &mut frame).unwrap_err();
+        assert_eq!(gameboy.save_state(&frame), before);
+        error
+    }
+
+    #[test]
+    fn loaded_state_saves_back_identically() {
+        let (gameboy, frame) = running_gameboy();
+        let state = gameboy.save_state(&frame);
+
+        let mut loaded = GameBoy::new(Model::Dmg);
+        let mut loaded_frame = vec![0; FRAME_SIZE];
+        loaded.load_state(&state, &mut loaded_frame).unwrap();
+        assert_eq!(loaded_frame, frame);
+        assert_eq!(loaded.save_state(&loaded_frame), state);
+    }
+
+    #[test]
+    fn state_with_bad_magic_is_rejected() {
+        let (gameboy, frame) = running_gameboy();
+        let mut state = gameboy.save_state(&frame);
+        state[0] = b'X';
+        assert!(matches!(load_rejected(&state), StateError::InvalidFormat));
+    }
+
+    #[test]
+    fn state_with_other_version_is_rejected() {
+        let (gameboy, frame) = running_gameboy();
+        let mut state = gameboy.save_state(&frame);
+        state[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            load_rejected(&state),
+            StateError::UnsupportedVersion(version) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn state_with_other_rom_checksum_is_rejected() {
+        let (gameboy, frame) = running_gameboy();
+        let mut state = gameboy.save_state(&frame);
+        state[6] ^= 0xFF;
+        assert!(matches!(load_rejected(&state), StateError::WrongRom));
+    }
+
+    #[test]
+    fn truncated_state_is_rejected() {
+        let (gameboy, frame) = running_gameboy();
+        let state = gameboy.save_state(&frame);
+        assert!(matches!(
+            load_rejected(&state[..state.len() - 1]),
+            StateError::InvalidFormat
+        ));
+    }
+}
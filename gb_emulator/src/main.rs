@@ -1,30 +1,88 @@
+mod pacing;
 mod widgets;
 
-use chrono::Utc;
 use eframe::egui;
-use gameboy::GameBoy;
+use gameboy::io::joypad::Button;
+use gameboy::{GameBoy, Model, StopReason};
 use std::ops::Add;
+use std::time::Instant;
 
 const MARGIN: f32 = 10.;
 
+/// Held to run the game backwards.
+const REWIND_KEY: egui::Key = egui::Key::R;
+/// Held to run the game as fast as possible.
+const FAST_FORWARD_KEY: egui::Key = egui::Key::Tab;
+
+/// Keys of the 4 joypads. Players 2-4 are only read by the SGB games which enable multiplayer.
+const KEY_BINDINGS: [[(egui::Key, Button); 8]; 4] = [
+    [
+        (egui::Key::ArrowRight, Button::Right),
+        (egui::Key::ArrowLeft, Button::Left),
+        (egui::Key::ArrowUp, Button::Up),
+        (egui::Key::ArrowDown, Button::Down),
+        (egui::Key::X, Button::A),
+        (egui::Key::Z, Button::B),
+        (egui::Key::Backspace, Button::Select),
+        (egui::Key::Enter, Button::Start),
+    ],
+    [
+        (egui::Key::L, Button::Right),
+        (egui::Key::J, Button::Left),
+        (egui::Key::I, Button::Up),
+        (egui::Key::K, Button::Down),
+        (egui::Key::M, Button::A),
+        (egui::Key::N, Button::B),
+        (egui::Key::U, Button::Select),
+        (egui::Key::O, Button::Start),
+    ],
+    [
+        (egui::Key::H, Button::Right),
+        (egui::Key::F, Button::Left),
+        (egui::Key::T, Button::Up),
+        (egui::Key::G, Button::Down),
+        (egui::Key::B, Button::A),
+        (egui::Key::V, Button::B),
+        (egui::Key::E, Button::Select),
+        (egui::Key::Y, Button::Start),
+    ],
+    [
+        (egui::Key::Num6, Button::Right),
+        (egui::Key::Num4, Button::Left),
+        (egui::Key::Num8, Button::Up),
+        (egui::Key::Num2, Button::Down),
+        (egui::Key::Num3, Button::A),
+        (egui::Key::Num1, Button::B),
+        (egui::Key::Num7, Button::Select),
+        (egui::Key::Num9, Button::Start),
+    ],
+];
+
+/// Keys of the turbo buttons of the first joypad until they are changed in the menu, next to the keys of A and B.
+const TURBO_KEY_BINDINGS: [(egui::Key, Button); 2] =
+    [(egui::Key::S, Button::A), (egui::Key::A, Button::B)];
+
 fn main() {
+    let sync_mode = pacing::SyncMode::from_args();
     let native_options = eframe::NativeOptions {
         centered: true,
         resizable: false,
         decorated: false,
+        // In vsync mode, presenting a frame waits for the next refresh of the monitor
+        vsync: sync_mode == pacing::SyncMode::Vsync,
         ..Default::default()
     };
 
     eframe::run_native(
         "Gameboy emulator",
         native_options,
-        Box::new(|cc| Box::new(GameboyEmulatorGUI::new(cc, 3., 60))),
+        Box::new(move |cc| Box::new(GameboyEmulatorGUI::new(cc, 3., sync_mode))),
     );
 }
 
 struct GameboyEmulatorGUI {
     gameboy: GameBoy,
-    fps: u32,
+    pacer: pacing::Pacer,
     resize_requested: bool,
     debug_widget: widgets::debug_widget::DebugWidget,
     menu_bar_widget: widgets::menu_bar::MenuBar,
@@ -32,10 +90,10 @@ struct GameboyEmulatorGUI {
 }
 
 impl GameboyEmulatorGUI {
-    fn new(_cc: &eframe::CreationContext<'_>, scale: f32, fps: u32) -> Self {
+    fn new(_cc: &eframe::CreationContext<'_>, scale: f32, sync_mode: pacing::SyncMode) -> Self {
         Self {
-            gameboy: GameBoy::new(),
-            fps,
+            gameboy: GameBoy::new(Model::default()),
+            pacer: pacing::Pacer::new(sync_mode),
             resize_requested: true,
             debug_widget: widgets::debug_widget::DebugWidget::default(),
             menu_bar_widget: widgets::menu_bar::MenuBar::default(),
@@ -100,37 +158,101 @@ impl GameboyEmulatorGUI {
             &mut self.resize_requested,
             &mut self.gameboy,
         );
-        self.gameboy_screen_widget.show(ctx);
+        self.gameboy_screen_widget.show(ctx, &self.gameboy);
         self.debug_widget.show(
             ctx,
             self.gameboy_screen_widget.scaled_size[0],
             &mut self.gameboy,
+            &mut self.gameboy_screen_widget.pixels,
+            &mut self.pacer,
         );
     }
+
+    /// Runs the frames due at the current speed. In turbo mode, runs as many frames as possible during a host frame,
+    /// only the last one is shown.
+    fn run_frames(&mut self) {
+        if self.pacer.is_turbo() {
+            let start = Instant::now();
+            while start.elapsed() < pacing::TURBO_TIME {
+                if !self.run_frame() {
+                    return;
+                }
+            }
+        } else {
+            for _ in 0..self.pacer.frames_due() {
+                if !self.run_frame() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Runs a frame. Returns false if the emulation paused on a breakpoint or a lockup.
+    fn run_frame(&mut self) -> bool {
+        match self
+            .gameboy
+            .run_frame(&mut self.gameboy_screen_widget.pixels)
+        {
+            StopReason::Breakpoint(_) => {
+                self.gameboy.debug_paused = true;
+                false
+            }
+            StopReason::Lockup => {
+                println!("Error: the CPU locked up at {:04X}", self.gameboy.cpu.pc);
+                self.gameboy.debug_paused = true;
+                false
+            }
+            StopReason::FrameDone => {
+                self.pacer.frame_emulated();
+                self.debug_widget
+                    .tas_editor_window
+                    .frame_done(&self.gameboy, &self.gameboy_screen_widget.pixels);
+                true
+            }
+            _ => true,
+        }
+    }
 }
 
 impl eframe::App for GameboyEmulatorGUI {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        if self.gameboy.is_sgb() != self.gameboy_screen_widget.is_sgb() {
+            self.gameboy_screen_widget.set_sgb(self.gameboy.is_sgb());
+            self.resize_requested = true;
+        }
+
         if self.resize_requested {
             self.resize_window(frame, self.get_corect_window_size());
             self.resize_requested = false;
         }
 
-        if !ctx.input().keys_down.is_empty() {
-            // TODO: Process inputs
-            println!("Keys down: {:?}", ctx.input().keys_down);
+        for (player, bindings) in KEY_BINDINGS.into_iter().enumerate() {
+            for (key, button) in bindings {
+                let pressed = ctx.input().key_down(key);
+                self.gameboy.set_button(player, button, pressed);
+            }
+        }
+        for (key, button) in self.menu_bar_widget.turbo_key_bindings {
+            let pressed = ctx.input().key_down(key);
+            self.gameboy.set_turbo(0, button, pressed);
         }
 
-        let time = Utc::now();
+        self.menu_bar_widget.save_slots.handle_hotkeys(
+            ctx,
+            &mut self.gameboy,
+            &mut self.gameboy_screen_widget.pixels,
+        );
 
-        self.gameboy
-            .cycle(&mut self.gameboy_screen_widget.pixels, self.fps);
+        self.pacer.fast_forward = ctx.input().key_down(FAST_FORWARD_KEY);
 
-        self.draw_widgets(ctx, frame);
+        if ctx.input().key_down(REWIND_KEY) && self.gameboy.rewind.enabled {
+            self.gameboy.rewind(&mut self.gameboy_screen_widget.pixels);
+        } else if !self.gameboy.debug_paused {
+            self.run_frames();
+        }
 
-        while Utc::now().timestamp_micros() - time.timestamp_micros()
-            <= (1_000_000 / self.fps) as i64
-        {}
+        self.draw_widgets(ctx, frame);
+        self.pacer.wait();
 
         ctx.request_repaint();
     }
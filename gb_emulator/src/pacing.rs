@@ -0,0 +1,231 @@
+use gameboy::REFRESH_RATE;
+use std::time::{Duration, Instant};
+
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 8.;
+/// In turbo mode, frames are run for this long before the last one is shown.
+pub const TURBO_TIME: Duration = Duration::from_millis(15);
+
+/// The last part of the wait is spent spinning, sleeping is not precise enough.
+const SPIN_TIME: Duration = Duration::from_millis(2);
+/// Further behind than this, the timeline starts over instead of running fast to catch up,
+/// e.g. after the window was moved or the emulation paused by a breakpoint.
+const MAX_DRIFT: Duration = Duration::from_millis(100);
+/// Most frames of the timeline run for a frame presented in vsync mode, the others are caught up on the next ones.
+const MAX_VSYNC_FRAMES: u64 = 2;
+
+/// How the host frames are paced. The swap interval of the window can't change once it is created, so the mode is
+/// picked on startup: the timer needs vsync off, or presenting a frame would wait for the monitor on top of the timer.
+/// There is no sound yet, hence no audio sync.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncMode {
+    /// Shows frames at the refresh rate of the LCD, sleeping between them
+    Timer,
+    /// Shows a frame on every refresh of the monitor, with the frames of the timeline which came due since the last
+    /// one: the game still runs at the refresh rate of the LCD
+    Vsync,
+}
+
+impl SyncMode {
+    /// Vsync when the emulator is started with `--vsync`, the timer otherwise.
+    pub fn from_args() -> Self {
+        if std::env::args().skip(1).any(|arg| arg == "--vsync") {
+            SyncMode::Vsync
+        } else {
+            SyncMode::Timer
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncMode::Timer => "Timer (59.73 Hz)",
+            SyncMode::Vsync => "Vsync",
+        }
+    }
+}
+
+/// Paces the host frames, and measures how many frames are shown and emulated per second.
+pub struct Pacer {
+    mode: SyncMode,
+    /// Emulated frames per host frame, between MIN_SPEED and MAX_SPEED
+    pub speed: f64,
+    /// Runs as fast as the host allows
+    pub turbo: bool,
+    /// Turbo while the fast forward key is held
+    pub fast_forward: bool,
+    /// Fraction of a frame carried over to the next host frame at fractional speeds
+    frame_budget: f64,
+    /// Frames of the timeline the current host frame stands for: always 1 with the timer, 0 to MAX_VSYNC_FRAMES with
+    /// vsync
+    timeline_frames: u64,
+    /// Frame `n` is due at `start + n / REFRESH_RATE`. Working on this timeline rather than waiting a frame duration
+    /// after the previous frame keeps the rounding of each wait from adding up.
+    start: Instant,
+    frames: u64,
+    /// Time dropped by the timelines which started over because they were too far behind
+    lost: Duration,
+    resyncs: u32,
+    /// How far behind the wall clock the emulation is since the session started, resyncs included
+    drift: Duration,
+    /// Frames counted since `fps_start`
+    fps_start: Instant,
+    host_frames: u32,
+    emulated_frames: u32,
+    host_fps: f64,
+    emulated_fps: f64,
+}
+
+impl Pacer {
+    pub fn new(mode: SyncMode) -> Self {
+        let now = Instant::now();
+        Self {
+            mode,
+            speed: 1.,
+            turbo: false,
+            fast_forward: false,
+            frame_budget: 0.,
+            timeline_frames: 1,
+            start: now,
+            frames: 0,
+            lost: Duration::ZERO,
+            resyncs: 0,
+            drift: Duration::ZERO,
+            fps_start: now,
+            host_frames: 0,
+            emulated_frames: 0,
+            host_fps: 0.,
+            emulated_fps: 0.,
+        }
+    }
+
+    pub fn get_mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo || self.fast_forward
+    }
+
+    /// Number of frames to emulate during this host frame, `speed` per frame of the timeline. At fractional speeds,
+    /// some host frames run one more frame than others, e.g. one host frame out of four runs a frame at 0.25x.
+    pub fn frames_due(&mut self) -> u32 {
+        self.frame_budget += self.timeline_frames as f64 * self.speed.clamp(MIN_SPEED, MAX_SPEED);
+        let frames = self.frame_budget.floor();
+        self.frame_budget -= frames;
+        frames as u32
+    }
+
+    /// Counts a frame completed by the emulator.
+    pub fn frame_emulated(&mut self) {
+        self.emulated_frames += 1;
+    }
+
+    /// Waits until the next frame is due. Called once per host frame.
+    pub fn wait(&mut self) {
+        self.count_host_frame();
+
+        if self.is_turbo() {
+            // Turbo doesn't wait at all
+            self.restart();
+            return;
+        }
+
+        match self.mode {
+            SyncMode::Timer => self.sleep_until_next_frame(),
+            SyncMode::Vsync => self.count_vsync_frames(),
+        }
+    }
+
+    fn sleep_until_next_frame(&mut self) {
+        self.frames += 1;
+        let due = self.due(self.frames);
+        if self.resync_if_late(due) {
+            return;
+        }
+
+        let now = Instant::now();
+        if let Some(sleep) = due
+            .checked_duration_since(now)
+            .and_then(|wait| wait.checked_sub(SPIN_TIME))
+        {
+            std::thread::sleep(sleep);
+        }
+        while Instant::now() < due {
+            std::hint::spin_loop();
+        }
+
+        self.drift = self.lost + (Instant::now() - due);
+    }
+
+    /// Presenting the frame blocks until the monitor refreshes, so there is no wait: the next host frame runs the
+    /// frames of the timeline which came due meanwhile.
+    fn count_vsync_frames(&mut self) {
+        if self.resync_if_late(self.due(self.frames + 1)) {
+            self.timeline_frames = 1;
+            return;
+        }
+
+        let now = Instant::now();
+        let due = ((now - self.start).as_secs_f64() * REFRESH_RATE) as u64;
+        self.timeline_frames = due.saturating_sub(self.frames).min(MAX_VSYNC_FRAMES);
+        self.frames += self.timeline_frames;
+        self.drift = self.lost + now.saturating_duration_since(self.due(self.frames));
+    }
+
+    /// Time at which frame `frame` of the timeline is due.
+    fn due(&self, frame: u64) -> Instant {
+        self.start + Duration::from_secs_f64(frame as f64 / REFRESH_RATE)
+    }
+
+    /// Starts a new timeline if the frame due at `due` is too late to catch up. Returns true if it did.
+    fn resync_if_late(&mut self, due: Instant) -> bool {
+        let now = Instant::now();
+        if now <= due + MAX_DRIFT {
+            return false;
+        }
+
+        self.lost += now - due;
+        self.resyncs += 1;
+        self.drift = self.lost;
+        self.restart();
+        true
+    }
+
+    /// Starts a new timeline from now.
+    fn restart(&mut self) {
+        self.start = Instant::now();
+        self.frames = 0;
+    }
+
+    fn count_host_frame(&mut self) {
+        self.host_frames += 1;
+
+        let elapsed = self.fps_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.host_fps = self.host_frames as f64 / elapsed.as_secs_f64();
+            self.emulated_fps = self.emulated_frames as f64 / elapsed.as_secs_f64();
+            self.fps_start = Instant::now();
+            self.host_frames = 0;
+            self.emulated_frames = 0;
+        }
+    }
+
+    /// Frames shown per second, over the last second.
+    pub fn get_host_fps(&self) -> f64 {
+        self.host_fps
+    }
+
+    /// Frames emulated per second, over the last second.
+    pub fn get_emulated_fps(&self) -> f64 {
+        self.emulated_fps
+    }
+
+    pub fn get_drift(&self) -> Duration {
+        self.drift
+    }
+
+    /// Number of times the timeline started over since the session started.
+    pub fn get_resyncs(&self) -> u32 {
+        self.resyncs
+    }
+}
@@ -28,7 +28,12 @@ impl Default for BackgroundMap {
 }
 // button window
 impl BackgroundMap {
-    pub fn show(&mut self, ctx: &egui::Context, mmu: &gameboy::memory::mmu::Mmu) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        mmu: &gameboy::memory::mmu::Mmu,
+        palette: &gameboy::palette::Palette,
+    ) {
         if !self.visible {
             return;
         }
@@ -55,7 +60,14 @@ impl BackgroundMap {
                     }
                 });
                 ui.checkbox(&mut self.viewport_visible, "Show Viewport");
-                show_tile_map_image(&self.tile_map_area, self.viewport_visible, ui, ctx, mmu);
+                show_tile_map_image(
+                    &self.tile_map_area,
+                    self.viewport_visible,
+                    ui,
+                    ctx,
+                    mmu,
+                    palette,
+                );
             });
     }
 
@@ -70,8 +82,9 @@ fn show_tile_map_image(
     ui: &mut egui::Ui,
     ctx: &egui::Context,
     mmu: &gameboy::memory::mmu::Mmu,
+    palette: &gameboy::palette::Palette,
 ) {
-    let mut tile_map_rgba_array = get_tile_map_rgba_array(tile_map_area, mmu);
+    let mut tile_map_rgba_array = get_tile_map_rgba_array(tile_map_area, mmu, palette);
     if viewport_visible {
         add_viewport_border_to_array(&mut tile_map_rgba_array, mmu);
     }
@@ -90,6 +103,7 @@ fn show_tile_map_image(
 fn get_tile_map_rgba_array(
     tile_map_area: &TileMapArea,
     mmu: &gameboy::memory::mmu::Mmu,
+    palette: &gameboy::palette::Palette,
 ) -> [u8; WIDTH * HEIGHT * 4] {
     let mut res = [0; WIDTH * HEIGHT * 4];
     let tile_map_area: Range<usize> = match tile_map_area {
@@ -113,6 +127,7 @@ fn get_tile_map_rgba_array(
                         .chunks(16)
                         .nth(*tile_id as usize)
                         .unwrap(), // Get tile data for tile_id in 8000-97FF
+                    palette,
                     &mut res,
                 );
             });
@@ -145,7 +160,13 @@ fn add_viewport_border_to_array(
     }
 }
 
-fn add_tile_to_array(x: usize, y: usize, tile_data: &[u8], array: &mut [u8; WIDTH * HEIGHT * 4]) {
+fn add_tile_to_array(
+    x: usize,
+    y: usize,
+    tile_data: &[u8],
+    palette: &gameboy::palette::Palette,
+    array: &mut [u8; WIDTH * HEIGHT * 4],
+) {
     let x = x * 8 * 4;
     let y = y * 8 * 4;
 
@@ -179,18 +200,9 @@ fn add_tile_to_array(x: usize, y: usize, tile_data: &[u8], array: &mut [u8; WIDT
                     3 => 3,
                     _ => unreachable!(),
                 };
-                let color = COLORS[color_id];
-                for i in 0..4 {
-                    // RGBA
-                    array[index + i] = color[i];
-                }
+                let color = palette.bg[color_id];
+                // RGBA
+                array[index..index + 4].copy_from_slice(&color);
             })
         });
 }
-
-const COLORS: [[u8; 4]; 4] = [
-    [0xFF, 0xFF, 0xFF, 0xFF],
-    [0x7E, 0x7E, 0x7E, 0xFF],
-    [0x3F, 0x3F, 0x3F, 0xFF],
-    [0x00, 0x00, 0x00, 0xFF],
-];
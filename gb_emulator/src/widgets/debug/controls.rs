@@ -1,21 +1,35 @@
 use eframe::egui;
 
+use crate::pacing;
+
 pub struct Controls {
     visible: bool,
+    /// Address typed in to add a breakpoint
+    new_breakpoint: u16,
 }
 
 impl Default for Controls {
     fn default() -> Self {
-        Self { visible: true }
+        Self {
+            visible: true,
+            new_breakpoint: 0x0100,
+        }
     }
 }
 
 impl Controls {
-    pub fn show(&mut self, ctx: &egui::Context, gmb: &mut gameboy::GameBoy) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        gmb: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+        pacer: &mut pacing::Pacer,
+    ) {
         if !self.visible {
             return;
         }
 
+        let new_breakpoint = &mut self.new_breakpoint;
         egui::Window::new("Controls")
             .collapsible(false)
             .resizable(false)
@@ -30,23 +44,157 @@ impl Controls {
 
                 // play/pause
                 ui.horizontal(|ui| {
-                    if ui.button(format!("{}", play_button_label)).clicked() {
+                    if ui.button(play_button_label.to_string()).clicked() {
                         gmb.debug_paused = !gmb.debug_paused;
                     }
-                    ui.label(format!("{}", play_label));
+                    ui.label(play_label.to_string());
                 });
 
-                // Speed control
-                ui.horizontal(|ui| {
-                    ui.add(egui::Slider::new(&mut gmb.speed, 0..=10).prefix("x"));
-                    ui.label(format!("Speed: {}%", gmb.speed * 100));
+                // Step while paused
+                ui.add_enabled_ui(gmb.debug_paused, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui.button("Step").clicked() {
+                            gmb.step_instruction(frame);
+                        }
+                        if ui.button("Next frame").clicked() {
+                            gmb.run_frame(frame);
+                        }
+                    });
                 });
 
+                Self::show_pacing(ui, pacer);
+
                 // Reset gameboy
-                if ui.button("Reset").clicked() {
-                    gmb.reset()
+                ui.horizontal(|ui| {
+                    if ui.button("Reset").clicked() {
+                        gmb.reset()
+                    }
+                    if ui.button("Power cycle").clicked() {
+                        gmb.power_cycle()
+                    }
+                });
+                ui.checkbox(&mut gmb.random_ram, "Random RAM on power on");
+
+                ui.separator();
+                Self::show_breakpoints(ui, gmb, new_breakpoint);
+
+                ui.separator();
+                Self::show_rewind(ui, gmb, frame);
+            });
+    }
+
+    /// Speed and sync mode, and the frames emulated per second against the frames shown by the host.
+    fn show_pacing(ui: &mut egui::Ui, pacer: &mut pacing::Pacer) {
+        ui.add_enabled_ui(!pacer.is_turbo(), |ui| {
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut pacer.speed, pacing::MIN_SPEED..=pacing::MAX_SPEED)
+                        .logarithmic(true)
+                        .max_decimals(2)
+                        .prefix("x"),
+                );
+                ui.label(format!("Speed: {:.0}%", pacer.speed * 100.));
+            });
+        });
+        ui.checkbox(&mut pacer.turbo, "Turbo (hold Tab)");
+
+        // The sync mode can't change once the window is created
+        ui.label(format!("Sync: {}", pacer.get_mode().name()))
+            .on_hover_text("Start with --vsync to sync to the monitor");
+
+        ui.label(format!(
+            "Emulated: {:.2} FPS, host: {:.2} FPS",
+            pacer.get_emulated_fps(),
+            pacer.get_host_fps()
+        ));
+        ui.label(format!(
+            "Drift: {:.2} ms, {} resyncs",
+            pacer.get_drift().as_secs_f64() * 1000.,
+            pacer.get_resyncs()
+        ))
+        .on_hover_text(
+            "Time the emulation fell behind the 59.73 Hz timeline since the start. The timeline starts over when it \
+             is too far behind to catch up, e.g. after moving the window",
+        );
+    }
+
+    /// List of the breakpoints, which pause the emulation before the instruction at their address.
+    fn show_breakpoints(ui: &mut egui::Ui, gmb: &mut gameboy::GameBoy, new_breakpoint: &mut u16) {
+        ui.horizontal(|ui| {
+            ui.label("Breakpoint");
+            ui.add(
+                egui::DragValue::new(new_breakpoint)
+                    .hexadecimal(4, false, true)
+                    .prefix("0x"),
+            );
+            if ui.button("Add").clicked() {
+                gmb.breakpoints.insert(*new_breakpoint);
+            }
+        });
+
+        let mut removed = None;
+        for address in gmb.breakpoints.iter() {
+            ui.horizontal(|ui| {
+                ui.monospace(format!("{:04X}", address));
+                if ui.small_button("Remove").clicked() {
+                    removed = Some(*address);
                 }
             });
+        }
+        if let Some(address) = removed {
+            gmb.breakpoints.remove(&address);
+        }
+    }
+
+    /// Rewind settings, and a timeline of the snapshots to go back to any of them.
+    fn show_rewind(ui: &mut egui::Ui, gmb: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        let rewind = &mut gmb.rewind;
+        ui.checkbox(&mut rewind.enabled, "Rewind (hold R)");
+
+        ui.horizontal(|ui| {
+            ui.label("Snapshot every");
+            ui.add(egui::DragValue::new(&mut rewind.interval).clamp_range(1..=60));
+            ui.label("frames");
+        });
+
+        // The depth is set in seconds of history
+        let mut seconds = rewind.get_duration().round() as usize;
+        ui.horizontal(|ui| {
+            ui.label("Keep");
+            if ui
+                .add(egui::DragValue::new(&mut seconds).clamp_range(1..=600))
+                .changed()
+            {
+                rewind.set_duration(seconds as f64);
+            }
+            ui.label("seconds");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Rewind speed");
+            ui.add(
+                egui::DragValue::new(&mut rewind.rate)
+                    .clamp_range(1..=16)
+                    .prefix("x"),
+            );
+        });
+
+        ui.label(format!(
+            "{} snapshots, {:.1} MB",
+            rewind.len(),
+            rewind.memory_size() as f32 / (1024. * 1024.)
+        ));
+
+        if rewind.is_empty() {
+            return;
+        }
+        let mut position = rewind.get_position().unwrap_or(rewind.len() - 1);
+        if ui
+            .add(egui::Slider::new(&mut position, 0..=rewind.len() - 1).text("Timeline"))
+            .changed()
+        {
+            gmb.rewind_seek(position, frame);
+        }
     }
 
     pub fn update_visibility(&mut self) {
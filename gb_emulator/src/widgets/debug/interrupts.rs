@@ -3,16 +3,11 @@ use eframe::egui;
 const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
 const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
 
+#[derive(Default)]
 pub struct Interrupts {
     visible: bool,
 }
 
-impl Default for Interrupts {
-    fn default() -> Self {
-        Self { visible: false }
-    }
-}
-
 impl Interrupts {
     pub fn show(&mut self, ctx: &egui::Context, mmu: &gameboy::memory::mmu::Mmu) {
         if !self.visible {
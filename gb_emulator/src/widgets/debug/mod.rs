@@ -3,5 +3,6 @@ pub mod controls;
 pub mod interrupts;
 pub mod memory_dump;
 pub mod registers;
+pub mod tas_editor;
 pub mod tile_data;
 pub mod timer;
@@ -1,15 +1,10 @@
 use eframe::egui;
 
+#[derive(Default)]
 pub struct Register {
     visible: bool,
 }
 
-impl Default for Register {
-    fn default() -> Self {
-        Self { visible: false }
-    }
-}
-
 impl Register {
     pub fn show(&mut self, ctx: &egui::Context, cpu: &gameboy::cpu::cpu::Cpu) {
         if !self.visible {
@@ -0,0 +1,564 @@
+use std::collections::BTreeMap;
+
+use eframe::egui;
+use gameboy::movie::{Movie, MovieError, MovieFrame, MovieMode};
+use gameboy::{rewind, StopReason};
+
+/// Frames between two snapshots of the greenzone.
+const GREENZONE_INTERVAL: usize = 10;
+/// Maximum number of snapshots.
+const GREENZONE_SIZE: usize = 300;
+/// One snapshot in this many is kept whole, about 230 KB, the others as a delta against it.
+const GREENZONE_KEYFRAME: usize = 10;
+
+/// Snapshot of the greenzone.
+enum Snapshot {
+    Full(Vec<u8>),
+    /// Encoded as in the rewind history, from the full snapshot of frame `base`
+    Delta {
+        base: usize,
+        delta: Vec<u8>,
+    },
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        match self {
+            Snapshot::Full(state) => state.len(),
+            Snapshot::Delta { delta, .. } => delta.len(),
+        }
+    }
+}
+
+/// Columns of the grid, in the order of `Button::ALL`.
+const BUTTON_LABELS: [&str; 8] = ["→", "←", "↑", "↓", "A", "B", "Se", "St"];
+const FRAME_COLUMN_WIDTH: f32 = 56.;
+const BUTTON_COLUMN_WIDTH: f32 = 22.;
+
+/// A named copy of the inputs, to try another route and come back to this one.
+struct Branch {
+    name: String,
+    frames: Vec<MovieFrame>,
+    /// Frame the branch was saved at
+    frame: usize,
+}
+
+/// What the user asked for while the window was drawn, applied once the GameBoy is no longer borrowed by the UI.
+enum Action {
+    New { from_power_on: bool },
+    Import,
+    Export,
+    Seek(usize),
+    Toggle { frame: usize, button: usize },
+    Insert(usize),
+    Delete(usize),
+    SaveBranch,
+    LoadBranch(usize),
+    DeleteBranch(usize),
+}
+
+/// Piano roll of the movie being run: a grid of frames × buttons to edit the inputs frame by frame. After an edit, the
+/// game is run again from the closest snapshot of the greenzone, the frames already run with the current inputs.
+pub struct TasEditor {
+    visible: bool,
+    /// Joypad shown in the grid
+    player: usize,
+    /// Frame the inserts and deletes apply to
+    selected: usize,
+    /// Keeps the current frame in view
+    follow: bool,
+    /// Playback records the joypads past the end of the movie
+    extend: bool,
+    /// Snapshots taken at the start of the frames, by frame of the movie
+    greenzone: BTreeMap<usize, Snapshot>,
+    /// For each frame run with the current inputs, whether the game didn't read the joypad during it
+    lag: BTreeMap<usize, bool>,
+    branches: Vec<Branch>,
+    /// Start of the movie session the greenzone belongs to
+    start_frame: Option<u64>,
+}
+
+impl Default for TasEditor {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            player: 0,
+            selected: 0,
+            follow: true,
+            extend: true,
+            greenzone: BTreeMap::new(),
+            lag: BTreeMap::new(),
+            branches: Vec::new(),
+            start_frame: None,
+        }
+    }
+}
+
+impl TasEditor {
+    pub fn show(&mut self, ctx: &egui::Context, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        self.sync(gameboy);
+        if !self.visible {
+            return;
+        }
+
+        let mut visible = self.visible;
+        let mut action = None;
+        egui::Window::new("TAS editor")
+            .open(&mut visible)
+            .collapsible(false)
+            .default_height(400.)
+            .show(ctx, |ui| action = self.show_contents(ui, gameboy));
+        self.visible = visible;
+
+        if let Some(action) = action {
+            self.apply(action, gameboy, frame);
+        }
+    }
+
+    /// Records the lag of the frame which just ended, and adds the next one to the greenzone. Called after every frame
+    /// run by the emulator.
+    pub fn frame_done(&mut self, gameboy: &gameboy::GameBoy, frame: &[u8]) {
+        self.sync(gameboy);
+        let (current, session) = match (gameboy.get_movie_frame(), gameboy.get_movie()) {
+            (Some(current), Some(session)) => (current as usize, session),
+            _ => return,
+        };
+        let len = session.movie.frames.len();
+
+        if let Some(done) = current.checked_sub(1).filter(|done| *done < len) {
+            self.lag.insert(done, gameboy.is_lag_frame());
+        }
+        // A recording replaces the frames after the current one
+        if session.get_mode() == MovieMode::Recording {
+            self.invalidate(current + 1);
+        }
+        if current <= len && current.is_multiple_of(GREENZONE_INTERVAL) {
+            self.capture(gameboy, frame);
+        }
+    }
+
+    pub fn update_visibility(&mut self) {
+        self.visible = !self.visible
+    }
+
+    /// Forgets the greenzone when the movie it was built for is gone.
+    fn sync(&mut self, gameboy: &gameboy::GameBoy) {
+        let start_frame = gameboy.get_movie().map(|session| session.get_start_frame());
+        if start_frame != self.start_frame {
+            self.greenzone.clear();
+            self.lag.clear();
+            self.start_frame = start_frame;
+        }
+    }
+
+    fn capture(&mut self, gameboy: &gameboy::GameBoy, frame: &[u8]) {
+        let current = match gameboy.get_movie_frame() {
+            Some(current) => current as usize,
+            None => return,
+        };
+        let state = gameboy.save_state(frame);
+        let base =
+            self.greenzone
+                .range(..current)
+                .rev()
+                .find_map(|(base, snapshot)| match snapshot {
+                    Snapshot::Full(full) => Some((*base, full)),
+                    Snapshot::Delta { .. } => None,
+                });
+        let snapshot = match base {
+            Some((base, full))
+                if !(current / GREENZONE_INTERVAL).is_multiple_of(GREENZONE_KEYFRAME) =>
+            {
+                Snapshot::Delta {
+                    base,
+                    delta: rewind::delta(full, &state),
+                }
+            }
+            _ => Snapshot::Full(state),
+        };
+        self.greenzone.insert(current, snapshot);
+
+        // The start of the movie is kept, the snapshots after it are dropped oldest first, along with the deltas
+        // which can't be decoded without them
+        while self.greenzone.len() > GREENZONE_SIZE {
+            let oldest = match self.greenzone.keys().nth(1) {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            self.greenzone.remove(&oldest);
+            self.greenzone.retain(
+                |_, snapshot| !matches!(snapshot, Snapshot::Delta { base, .. } if *base == oldest),
+            );
+        }
+    }
+
+    /// Returns the save state of a snapshot of the greenzone.
+    fn decode(&self, snapshot: &Snapshot) -> Option<Vec<u8>> {
+        match snapshot {
+            Snapshot::Full(state) => Some(state.clone()),
+            Snapshot::Delta { base, delta } => match self.greenzone.get(base) {
+                Some(Snapshot::Full(full)) => Some(rewind::apply(full, delta)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Drops the greenzone from `frame` on, as the inputs of that frame changed.
+    fn invalidate(&mut self, frame: usize) {
+        self.greenzone.split_off(&frame);
+        self.lag.split_off(&frame);
+    }
+
+    fn show_contents(&mut self, ui: &mut egui::Ui, gameboy: &gameboy::GameBoy) -> Option<Action> {
+        let mut action = None;
+
+        let session = match gameboy.get_movie() {
+            Some(session) => session,
+            None => {
+                ui.label("No movie");
+                ui.horizontal(|ui| {
+                    if ui.button("New from power on").clicked() {
+                        action = Some(Action::New {
+                            from_power_on: true,
+                        });
+                    }
+                    if ui.button("New from here").clicked() {
+                        action = Some(Action::New {
+                            from_power_on: false,
+                        });
+                    }
+                    if ui.button("Import").clicked() {
+                        action = Some(Action::Import);
+                    }
+                });
+                return action;
+            }
+        };
+        let frames = &session.movie.frames;
+        let current = gameboy.get_movie_frame().unwrap_or(0) as usize;
+
+        ui.horizontal(|ui| {
+            if ui.button("Import").clicked() {
+                action = Some(Action::Import);
+            }
+            if ui.button("Export").clicked() {
+                action = Some(Action::Export);
+            }
+            egui::ComboBox::from_id_source("tas_editor_player")
+                .selected_text(format!("Joypad {}", self.player + 1))
+                .show_ui(ui, |ui| {
+                    for player in 0..4 {
+                        ui.selectable_value(
+                            &mut self.player,
+                            player,
+                            format!("Joypad {}", player + 1),
+                        );
+                    }
+                });
+        });
+
+        let mode = match session.get_mode() {
+            MovieMode::Recording => "Recording",
+            MovieMode::Playing => "Playing",
+            MovieMode::Finished => "Finished",
+        };
+        ui.label(format!(
+            "{}: frame {} / {}, {} lag frames",
+            mode,
+            current,
+            frames.len(),
+            self.lag.values().filter(|lag| **lag).count()
+        ));
+        ui.label(format!(
+            "Greenzone: {} frames, {} snapshots, {:.1} MB",
+            self.lag.len(),
+            self.greenzone.len(),
+            self.greenzone.values().map(Snapshot::size).sum::<usize>() as f32 / (1024. * 1024.)
+        ));
+        if let Some(desync) = session.get_desync() {
+            ui.colored_label(egui::Color32::RED, format!("Desynced at frame {}", desync));
+        }
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.follow, "Follow");
+            ui.checkbox(&mut self.extend, "Record past the end");
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("Frame {}", self.selected));
+            if ui.button("Insert").clicked() {
+                action = Some(Action::Insert(self.selected));
+            }
+            if ui.button("Delete").clicked() {
+                action = Some(Action::Delete(self.selected));
+            }
+        });
+
+        ui.collapsing("Branches", |ui| {
+            if ui.button("Save branch").clicked() {
+                action = Some(Action::SaveBranch);
+            }
+            for (index, branch) in self.branches.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{}: frame {} / {}",
+                        branch.name,
+                        branch.frame,
+                        branch.frames.len()
+                    ));
+                    if ui.small_button("Load").clicked() {
+                        action = Some(Action::LoadBranch(index));
+                    }
+                    if ui.small_button("Delete").clicked() {
+                        action = Some(Action::DeleteBranch(index));
+                    }
+                });
+            }
+        });
+
+        ui.separator();
+        let row_height = ui.spacing().interact_size.y;
+        ui.horizontal(|ui| {
+            ui.add_sized([FRAME_COLUMN_WIDTH, row_height], egui::Label::new("Frame"));
+            for label in BUTTON_LABELS {
+                ui.add_sized([BUTTON_COLUMN_WIDTH, row_height], egui::Label::new(label));
+            }
+        });
+
+        let mut scroll = egui::ScrollArea::vertical().auto_shrink([false, false]);
+        if self.follow {
+            let spacing = row_height + ui.spacing().item_spacing.y;
+            scroll = scroll.vertical_scroll_offset((current.saturating_sub(5)) as f32 * spacing);
+        }
+        scroll.show_rows(ui, row_height, frames.len(), |ui, rows| {
+            for index in rows {
+                let input = frames[index].input[self.player];
+                ui.horizontal(|ui| {
+                    // Lag frames in red, the rest of the greenzone in green
+                    let text = egui::RichText::new(index.to_string()).monospace();
+                    let text = match self.lag.get(&index) {
+                        Some(true) => text.color(egui::Color32::RED),
+                        Some(false) => text.color(egui::Color32::GREEN),
+                        None => text,
+                    };
+                    if ui
+                        .add_sized(
+                            [FRAME_COLUMN_WIDTH, row_height],
+                            egui::SelectableLabel::new(index == current, text),
+                        )
+                        .clicked()
+                    {
+                        self.selected = index;
+                        action = Some(Action::Seek(index));
+                    }
+
+                    for (button, label) in BUTTON_LABELS.into_iter().enumerate() {
+                        let pressed = input & (1 << button) != 0;
+                        if ui
+                            .add_sized(
+                                [BUTTON_COLUMN_WIDTH, row_height],
+                                egui::SelectableLabel::new(pressed, label),
+                            )
+                            .clicked()
+                        {
+                            action = Some(Action::Toggle {
+                                frame: index,
+                                button,
+                            });
+                        }
+                    }
+                });
+            }
+        });
+
+        action
+    }
+
+    fn apply(&mut self, action: Action, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        match action {
+            Action::New { from_power_on } => {
+                gameboy.record_movie(from_power_on, frame);
+                self.start(gameboy, frame);
+            }
+            Action::Import => self.import(gameboy, frame),
+            Action::Export => export(gameboy),
+            Action::Seek(target) => self.seek(gameboy, frame, target),
+            Action::Toggle {
+                frame: index,
+                button,
+            } => {
+                let player = self.player;
+                self.edit(gameboy, frame, index, |frames| {
+                    frames[index].input[player] ^= 1 << button;
+                });
+            }
+            Action::Insert(index) => {
+                self.edit(gameboy, frame, index, |frames| {
+                    frames.insert(index.min(frames.len()), MovieFrame::default());
+                });
+            }
+            Action::Delete(index) => {
+                self.edit(gameboy, frame, index, |frames| {
+                    if index < frames.len() {
+                        frames.remove(index);
+                    }
+                });
+            }
+            Action::SaveBranch => {
+                if let Some(session) = gameboy.get_movie() {
+                    self.branches.push(Branch {
+                        name: format!("Branch {}", self.branches.len() + 1),
+                        frames: session.movie.frames.clone(),
+                        frame: gameboy.get_movie_frame().unwrap_or(0) as usize,
+                    });
+                }
+            }
+            Action::LoadBranch(index) => self.load_branch(index, gameboy, frame),
+            Action::DeleteBranch(index) => {
+                self.branches.remove(index);
+            }
+        }
+    }
+
+    /// Takes over the movie which was just started, from its first frame.
+    fn start(&mut self, gameboy: &mut gameboy::GameBoy, frame: &[u8]) {
+        self.sync(gameboy);
+        self.selected = 0;
+        if let Some(session) = gameboy.get_movie_mut() {
+            session.extend = self.extend;
+        }
+        self.capture(gameboy, frame);
+    }
+
+    /// Changes the inputs from frame `from` on, then runs the game again up to the current frame if it was run with
+    /// the old inputs.
+    fn edit(
+        &mut self,
+        gameboy: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+        from: usize,
+        edit: impl FnOnce(&mut Vec<MovieFrame>),
+    ) {
+        let current = gameboy.get_movie_frame().unwrap_or(0) as usize;
+        let session = match gameboy.get_movie_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        edit(&mut session.movie.frames);
+        session.invalidate(from);
+        self.invalidate(from);
+
+        if from <= current {
+            self.seek(gameboy, frame, current);
+        }
+    }
+
+    fn load_branch(&mut self, index: usize, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        let (branch, target) = match self.branches.get(index) {
+            Some(branch) => (branch.frames.clone(), branch.frame),
+            None => return,
+        };
+        let session = match gameboy.get_movie_mut() {
+            Some(session) => session,
+            None => return,
+        };
+
+        // The greenzone stays valid up to the first frame where the branch differs
+        let frames = &mut session.movie.frames;
+        let from = frames
+            .iter()
+            .zip(branch.iter())
+            .position(|(frame, other)| frame.input != other.input)
+            .unwrap_or(frames.len().min(branch.len()));
+        *frames = branch;
+        session.invalidate(from);
+        self.invalidate(from);
+
+        self.seek(gameboy, frame, target);
+    }
+
+    /// Goes to the start of frame `target`: loads the closest snapshot before it, or restarts the movie, and runs
+    /// the frames in between.
+    fn seek(&mut self, gameboy: &mut gameboy::GameBoy, frame: &mut [u8], target: usize) {
+        let session = match gameboy.get_movie_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        let target = target.min(session.movie.frames.len());
+        session.set_mode(MovieMode::Playing);
+        session.extend = self.extend;
+
+        let snapshot = self
+            .greenzone
+            .range(..=target)
+            .next_back()
+            .and_then(|(_, snapshot)| self.decode(snapshot));
+        match snapshot {
+            Some(snapshot) => {
+                if let Err(e) = gameboy.load_state(&snapshot, frame) {
+                    println!("Error: {}", e);
+                    return;
+                }
+            }
+            None => {
+                let movie = session.movie.clone();
+                if let Err(e) = gameboy.play_movie(movie, frame) {
+                    println!("Error: {}", e);
+                    return;
+                }
+                self.start(gameboy, frame);
+            }
+        }
+
+        while let Some(current) = gameboy.get_movie_frame() {
+            if current as usize >= target || gameboy.run_frame(frame) != StopReason::FrameDone {
+                break;
+            }
+            self.frame_done(gameboy, frame);
+        }
+    }
+
+    fn import(&mut self, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        let path = native_dialog::FileDialog::new()
+            .add_filter("Movie files", &["gbm"])
+            .show_open_single_file()
+            .unwrap();
+
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let result = std::fs::read(&path)
+            .map_err(MovieError::from)
+            .and_then(|data| Movie::from_bytes(&data))
+            .and_then(|movie| gameboy.play_movie(movie, frame));
+        match result {
+            Ok(()) => {
+                self.branches.clear();
+                self.start(gameboy, frame);
+            }
+            Err(e) => println!("Error: {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn export(gameboy: &gameboy::GameBoy) {
+    let session = match gameboy.get_movie() {
+        Some(session) => session,
+        None => return,
+    };
+
+    let path = native_dialog::FileDialog::new()
+        .add_filter("Movie files", &["gbm"])
+        .show_save_single_file()
+        .unwrap();
+
+    match path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(&path, session.movie.to_bytes()) {
+                println!("Error: {}: {}", path.display(), e);
+            }
+        }
+        None => println!("Invalid path"),
+    }
+}
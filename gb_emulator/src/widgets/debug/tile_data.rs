@@ -3,18 +3,18 @@ use eframe::egui;
 const HEIGHT: usize = 192;
 const WIDTH: usize = 128;
 
+#[derive(Default)]
 pub struct TileData {
     visible: bool,
 }
 
-impl Default for TileData {
-    fn default() -> Self {
-        Self { visible: false }
-    }
-}
-
 impl TileData {
-    pub fn show(&mut self, ctx: &egui::Context, mmu: &gameboy::memory::mmu::Mmu) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        mmu: &gameboy::memory::mmu::Mmu,
+        palette: &gameboy::palette::Palette,
+    ) {
         if !self.visible {
             return;
         }
@@ -24,7 +24,7 @@ impl TileData {
             .resizable(false)
             .collapsible(false)
             .show(ctx, |ui| {
-                show_tile_data_image(ui, ctx, mmu);
+                show_tile_data_image(ui, ctx, mmu, palette);
             });
     }
 
@@ -33,8 +33,13 @@ impl TileData {
     }
 }
 
-fn show_tile_data_image(ui: &mut egui::Ui, ctx: &egui::Context, mmu: &gameboy::memory::mmu::Mmu) {
-    let tile_data_rgba_array = get_tile_data_rgba_array(mmu);
+fn show_tile_data_image(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    mmu: &gameboy::memory::mmu::Mmu,
+    palette: &gameboy::palette::Palette,
+) {
+    let tile_data_rgba_array = get_tile_data_rgba_array(mmu, palette);
 
     let image = egui::ColorImage::from_rgba_unmultiplied([WIDTH, HEIGHT], &tile_data_rgba_array);
 
@@ -47,7 +52,10 @@ fn show_tile_data_image(ui: &mut egui::Ui, ctx: &egui::Context, mmu: &gameboy::m
     );
 }
 
-fn get_tile_data_rgba_array(mmu: &gameboy::memory::mmu::Mmu) -> [u8; WIDTH * HEIGHT * 4] {
+fn get_tile_data_rgba_array(
+    mmu: &gameboy::memory::mmu::Mmu,
+    palette: &gameboy::palette::Palette,
+) -> [u8; WIDTH * HEIGHT * 4] {
     // let mut res = [0; WIDTH * HEIGHT * 4];
     let mut res = [0; WIDTH * HEIGHT * 4];
 
@@ -82,21 +90,12 @@ fn get_tile_data_rgba_array(mmu: &gameboy::memory::mmu::Mmu) -> [u8; WIDTH * HEI
                             3 => 3,
                             _ => unreachable!(),
                         };
-                        let color = COLORS[color_id];
-                        for i in 0..4 {
-                            // RGBA
-                            res[index + i] = color[i];
-                        }
+                        let color = palette.bg[color_id];
+                        // RGBA
+                        res[index..index + 4].copy_from_slice(&color);
                     });
                 });
         });
 
     res
 }
-
-const COLORS: [[u8; 4]; 4] = [
-    [0xFF, 0xFF, 0xFF, 0xFF],
-    [0x7E, 0x7E, 0x7E, 0xFF],
-    [0x3F, 0x3F, 0x3F, 0xFF],
-    [0x00, 0x00, 0x00, 0xFF],
-];
@@ -5,16 +5,11 @@ const TIMER_COUNTER_ADDRESS: u16 = 0xFF05;
 const TIMER_MODULO_ADDRESS: u16 = 0xFF06;
 const TIMER_CONTROL_ADDRESS: u16 = 0xFF07;
 
+#[derive(Default)]
 pub struct Timer {
     visible: bool,
 }
 
-impl Default for Timer {
-    fn default() -> Self {
-        Self { visible: false }
-    }
-}
-
 impl Timer {
     pub fn show(&mut self, ctx: &egui::Context, mmu: &gameboy::memory::mmu::Mmu) {
         if !self.visible {
@@ -41,6 +36,9 @@ impl Timer {
 fn get_label_div(ui: &mut egui::Ui, mmu: &gameboy::memory::mmu::Mmu) {
     let div_value = mmu.read_byte(DIVIDER_REGISTER_ADDRESS);
     ui.label(format!("DIV: {:02X}", div_value));
+
+    let system_counter = mmu.get_timer().get_counter();
+    ui.label(format!("System counter: {:04X}", system_counter));
 }
 
 fn get_label_tima(ui: &mut egui::Ui, mmu: &gameboy::memory::mmu::Mmu) {
@@ -57,7 +55,7 @@ fn get_label_tac(ui: &mut egui::Ui, mmu: &gameboy::memory::mmu::Mmu) {
     let tac_value = mmu.read_byte(TIMER_CONTROL_ADDRESS);
     ui.label(format!("TAC: {:02X}", tac_value));
 
-    let timer_enable = if tac_value >> 2 == 0 { false } else { true };
+    let timer_enable = tac_value >> 2 != 0;
     ui.label(format!("Timer enabled: {}", timer_enable));
 
     let input_clock_select = tac_value & 0b11;
@@ -1,3 +1,4 @@
+use crate::pacing;
 use crate::widgets::debug;
 use eframe::egui;
 
@@ -11,6 +12,7 @@ pub struct DebugWidget {
     pub interrupts_widget: debug::interrupts::Interrupts,
     pub background_map_widget: debug::background_map::BackgroundMap,
     pub tile_data_widget: debug::tile_data::TileData,
+    pub tas_editor_window: debug::tas_editor::TasEditor,
 }
 
 impl Default for DebugWidget {
@@ -35,12 +37,20 @@ impl Default for DebugWidget {
             interrupts_widget: debug::interrupts::Interrupts::default(),
             background_map_widget: debug::background_map::BackgroundMap::default(),
             tile_data_widget: debug::tile_data::TileData::default(),
+            tas_editor_window: debug::tas_editor::TasEditor::default(),
         }
     }
 }
 
 impl DebugWidget {
-    pub fn show(&mut self, ctx: &egui::Context, width: f32, gameboy: &mut gameboy::GameBoy) {
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        width: f32,
+        gameboy: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+        pacer: &mut pacing::Pacer,
+    ) {
         if !self.visible {
             return;
         }
@@ -51,12 +61,15 @@ impl DebugWidget {
             .frame(self.frame)
             .show(ctx, |_| {
                 self.register_window.show(ctx, &gameboy.cpu);
-                self.controls_window.show(ctx, gameboy);
+                self.controls_window.show(ctx, gameboy, frame, pacer);
                 self.memory_dump_window.show(ctx, &gameboy.mmu);
                 self.timer_widget.show(ctx, &gameboy.mmu);
                 self.interrupts_widget.show(ctx, &gameboy.mmu);
-                self.background_map_widget.show(ctx, &gameboy.mmu);
-                self.tile_data_widget.show(ctx, &gameboy.mmu);
+                self.background_map_widget
+                    .show(ctx, &gameboy.mmu, &gameboy.palette);
+                self.tile_data_widget
+                    .show(ctx, &gameboy.mmu, &gameboy.palette);
+                self.tas_editor_window.show(ctx, gameboy, frame);
             });
     }
 
@@ -1,14 +1,39 @@
 use eframe::egui;
+use gameboy::io::joypad::Button;
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
+/// Size of the SGB output, border included
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+/// Buttons of the input display: position of the top left corner and size, in units of `overlay_unit`.
+const INPUT_DISPLAY: [(Button, &str, [f32; 2], [f32; 2]); 8] = [
+    (Button::Up, "", [1., 0.], [1., 1.]),
+    (Button::Left, "", [0., 1.], [1., 1.]),
+    (Button::Right, "", [2., 1.], [1., 1.]),
+    (Button::Down, "", [1., 2.], [1., 1.]),
+    (Button::Select, "SEL", [3.5, 2.2], [1.6, 0.8]),
+    (Button::Start, "STA", [5.3, 2.2], [1.6, 0.8]),
+    (Button::B, "B", [7.2, 1.], [1.2, 1.2]),
+    (Button::A, "A", [8.6, 0.4], [1.2, 1.2]),
+];
+const OVERLAY_BACKGROUND: egui::Color32 = egui::Color32::from_rgba_premultiplied(0, 0, 0, 160);
 
 pub struct GameboyScreen {
     visible: bool,
     size: [usize; 2],
     pub pixels: [u8; WIDTH * HEIGHT * 4],
     pub scaled_size: [f32; 2],
+    scale: f32,
+    /// Shows the SGB output instead of the game screen
+    sgb: bool,
     frame: egui::Frame,
+    /// Draws the buttons held on the first joypad over the screen
+    pub show_input: bool,
+    pub show_frame_counter: bool,
+    /// Counts the frames during which the game didn't read the joypad
+    pub show_lag_counter: bool,
 }
 
 impl Default for GameboyScreen {
@@ -28,7 +53,12 @@ impl Default for GameboyScreen {
             size: [WIDTH, HEIGHT],
             pixels: [0; WIDTH * HEIGHT * 4],
             scaled_size: [WIDTH as f32, HEIGHT as f32],
+            scale: 1.,
+            sgb: false,
             frame: gameboy_screen_frame,
+            show_input: false,
+            show_frame_counter: false,
+            show_lag_counter: false,
         }
     }
 }
@@ -37,16 +67,25 @@ impl GameboyScreen {
     pub fn new(scale: f32) -> Self {
         Self {
             scaled_size: [WIDTH as f32 * scale, HEIGHT as f32 * scale],
+            scale,
             ..Default::default()
         }
     }
 
-    pub fn show(&self, ctx: &egui::Context) {
+    /// Shows the last frame, or a blank screen while the LCD is off, with the enabled overlays on top.
+    /// In SGB mode, shows the output of the SGB instead, which handles the LCD being off itself.
+    pub fn show(&self, ctx: &egui::Context, gameboy: &gameboy::GameBoy) {
         if !self.visible {
             return;
         }
 
-        let image = egui::ColorImage::from_rgba_unmultiplied(self.size, &self.pixels);
+        let image = if let Some(sgb_frame) = gameboy.sgb_frame().filter(|_| self.sgb) {
+            egui::ColorImage::from_rgba_unmultiplied(self.size, sgb_frame)
+        } else if gameboy.is_screen_off() {
+            egui::ColorImage::new(self.size, egui::Color32::WHITE)
+        } else {
+            egui::ColorImage::from_rgba_unmultiplied(self.size, &self.pixels)
+        };
         let gameboy_screen_texture = ctx.load_texture(
             "gameboy_screen_texture",
             image,
@@ -57,10 +96,106 @@ impl GameboyScreen {
             .resizable(false)
             .frame(self.frame)
             .show(ctx, |ui| {
-                ui.image(&gameboy_screen_texture, self.scaled_size);
+                let rect = ui.image(&gameboy_screen_texture, self.scaled_size).rect;
+                let painter = ui.painter_at(rect);
+                self.draw_counters(&painter, gameboy);
+                if self.show_input {
+                    self.draw_input(&painter, gameboy);
+                }
             });
     }
 
+    /// Size of the overlays, which follow the scale of the screen.
+    fn overlay_unit(&self) -> f32 {
+        4. * self.scale
+    }
+
+    /// Frame and lag counters in the top left corner. The lag counter turns red on lag frames.
+    fn draw_counters(&self, painter: &egui::Painter, gameboy: &gameboy::GameBoy) {
+        let font = egui::FontId::monospace(3. * self.overlay_unit());
+        let margin = self.overlay_unit();
+        let mut pos = painter.clip_rect().left_top() + egui::vec2(margin, margin);
+
+        let mut lines = Vec::new();
+        if self.show_frame_counter {
+            lines.push((
+                format!("Frame {}", gameboy.get_frame_count()),
+                egui::Color32::WHITE,
+            ));
+        }
+        if self.show_lag_counter {
+            let color = if gameboy.is_lag_frame() {
+                egui::Color32::RED
+            } else {
+                egui::Color32::WHITE
+            };
+            lines.push((format!("Lag {}", gameboy.get_lag_count()), color));
+        }
+
+        for (text, color) in lines {
+            let galley = painter.layout_no_wrap(text, font.clone(), color);
+            let rect = egui::Rect::from_min_size(pos, galley.size()).expand(margin / 4.);
+            painter.rect_filled(rect, 0., OVERLAY_BACKGROUND);
+            pos.y += galley.size().y + margin / 2.;
+            painter.galley(rect.min + egui::vec2(margin / 4., margin / 4.), galley);
+        }
+    }
+
+    /// Buttons of the first joypad in the bottom left corner, filled while pressed.
+    fn draw_input(&self, painter: &egui::Painter, gameboy: &gameboy::GameBoy) {
+        let unit = self.overlay_unit();
+        let origin = painter.clip_rect().left_bottom() + egui::vec2(unit, -4. * unit);
+        let font = egui::FontId::monospace(0.6 * unit);
+        let joypad = gameboy.mmu.get_joypad();
+
+        let background =
+            egui::Rect::from_min_size(origin, egui::vec2(10., 3.) * unit).expand(unit / 2.);
+        painter.rect_filled(background, unit / 2., OVERLAY_BACKGROUND);
+
+        for (button, label, pos, size) in INPUT_DISPLAY {
+            let rect = egui::Rect::from_min_size(
+                origin + egui::Vec2::from(pos) * unit,
+                egui::Vec2::from(size) * unit,
+            );
+            let (fill, text) = if joypad.is_pressed(0, button) {
+                (egui::Color32::WHITE, egui::Color32::BLACK)
+            } else {
+                (egui::Color32::from_gray(0x50), egui::Color32::WHITE)
+            };
+            painter.rect_filled(rect, unit / 4., fill);
+            // Outlines the buttons held in turbo mode, which blink while pressed and released
+            if gameboy.get_turbo(0) & (1 << button as u8) != 0 {
+                let stroke = egui::Stroke::new(unit / 6., egui::Color32::YELLOW);
+                painter.rect_stroke(rect, unit / 4., stroke);
+            }
+            painter.text(
+                rect.center(),
+                egui::Align2::CENTER_CENTER,
+                label,
+                font.clone(),
+                text,
+            );
+        }
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        self.sgb
+    }
+
+    /// Switches between the game screen and the bordered SGB output, which is larger.
+    pub fn set_sgb(&mut self, sgb: bool) {
+        self.sgb = sgb;
+        self.size = if sgb {
+            [SGB_WIDTH, SGB_HEIGHT]
+        } else {
+            [WIDTH, HEIGHT]
+        };
+        self.scaled_size = [
+            self.size[0] as f32 * self.scale,
+            self.size[1] as f32 * self.scale,
+        ];
+    }
+
     pub fn is_visible(&self) -> bool {
         self.visible
     }
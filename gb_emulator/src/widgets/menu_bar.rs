@@ -1,13 +1,49 @@
 use std::path::PathBuf;
 
 use eframe::egui;
+use gameboy::colorization;
+use gameboy::io::joypad::Button;
+use gameboy::palette::{Palette, Preset};
+use gameboy::Model;
 
 use crate::widgets;
 
+/// When a trace starts, see `TraceStart`.
+#[derive(PartialEq)]
+enum TraceStartChoice {
+    Immediately,
+    AtPc,
+    AfterCycle,
+}
+
+/// Choices of `GameBoy::turbo_frames`: 30, 15, 10 and 7.5 presses per second.
+const TURBO_FRAMES: [u32; 4] = [1, 2, 3, 4];
+
+#[derive(PartialEq)]
+enum PaletteChoice {
+    Preset(Preset),
+    Custom,
+    /// CGB compatibility palette: one of the manual palettes, or None for the one picked from the title
+    Colorization(Option<usize>),
+}
+
 pub struct MenuBar {
     visible: bool,
     frame: egui::Frame,
     height: f32,
+    trace_start: TraceStartChoice,
+    trace_start_pc: u16,
+    trace_start_cycle: u64,
+    palette_choice: PaletteChoice,
+    custom_palette: Palette,
+    /// Model used for the next roms, None to pick it from the header of each rom
+    model_choice: Option<Model>,
+    /// Keys of the turbo buttons of the first joypad
+    pub turbo_key_bindings: [(egui::Key, Button); 2],
+    /// Index of the turbo binding waiting for a key press
+    turbo_rebinding: Option<usize>,
+    pub save_slots: widgets::save_slots::SaveSlots,
+    movies: widgets::movies::Movies,
 }
 
 impl Default for MenuBar {
@@ -26,6 +62,16 @@ impl Default for MenuBar {
             visible: true,
             frame: menu_bar_widget_frame,
             height: 10.,
+            trace_start: TraceStartChoice::AtPc,
+            trace_start_pc: 0x100,
+            trace_start_cycle: 0,
+            palette_choice: PaletteChoice::Preset(Preset::Grey),
+            custom_palette: Palette::default(),
+            model_choice: None,
+            turbo_key_bindings: crate::TURBO_KEY_BINDINGS,
+            turbo_rebinding: None,
+            save_slots: widgets::save_slots::SaveSlots::default(),
+            movies: widgets::movies::Movies::default(),
         }
     }
 }
@@ -61,8 +107,15 @@ impl MenuBar {
 
                 egui::menu::bar(ui, |ui| {
                     ui.add_visible_ui(self.visible, |ui| {
-                        self.show_file_button(ui, gameboy);
-                        self.show_view_button(ui, resize_requested, gameboy_screen, debug_widget);
+                        self.show_file_button(ui, gameboy, gameboy_screen);
+                        self.show_view_button(
+                            ui,
+                            resize_requested,
+                            gameboy_screen,
+                            debug_widget,
+                            gameboy,
+                        );
+                        self.show_debug_button(ui, gameboy);
                     });
 
                     ui.add_space(frame.info().window_info.size[0] / 2. - 252. / 2.); // magic
@@ -97,7 +150,12 @@ impl MenuBar {
             });
     }
 
-    fn show_file_button(&self, ui: &mut egui::Ui, gameboy: &mut gameboy::GameBoy) {
+    fn show_file_button(
+        &mut self,
+        ui: &mut egui::Ui,
+        gameboy: &mut gameboy::GameBoy,
+        gameboy_screen: &mut widgets::gameboy_screen::GameboyScreen,
+    ) {
         ui.menu_button("File", |ui| {
             if ui.button("Load Rom").clicked() {
                 let mut path_to_resources_folder = std::env::current_dir().unwrap();
@@ -114,20 +172,109 @@ impl MenuBar {
                     .unwrap();
 
                 match path {
-                    Some(path) => gameboy.load_roam(path),
+                    Some(path) => {
+                        let model =
+                            self.model_choice
+                                .unwrap_or_else(|| match std::fs::read(&path) {
+                                    Ok(rom) => Model::from_header(&rom),
+                                    Err(_) => Model::default(),
+                                });
+                        gameboy.set_model(model);
+                        gameboy.load_roam(path.clone());
+                        self.save_slots.set_rom(path.clone());
+                        self.movies.set_rom(path);
+                        // A CGB picks the palette of DMG games by itself, a manual palette stands for the button
+                        // combination which overrides it
+                        match self.palette_choice {
+                            PaletteChoice::Colorization(None) => {
+                                gameboy.palette = colorization::automatic_palette(&gameboy.mmu)
+                            }
+                            PaletteChoice::Colorization(Some(index)) => {
+                                gameboy.palette = colorization::MANUAL_PALETTES[index].palette
+                            }
+                            _ => (),
+                        }
+                    }
                     None => println!("Invalid path"),
                 };
                 ui.close_menu()
             }
+
+            self.save_slots
+                .show_menus(ui, gameboy, &mut gameboy_screen.pixels);
+            self.movies
+                .show_menu(ui, gameboy, &mut gameboy_screen.pixels);
+            ui.separator();
+
+            ui.menu_button("Model", |ui| {
+                if ui
+                    .selectable_label(self.model_choice.is_none(), "Automatic")
+                    .clicked()
+                {
+                    self.model_choice = None;
+                }
+
+                for model in Model::ALL {
+                    if ui
+                        .selectable_label(self.model_choice == Some(model), model.name())
+                        .clicked()
+                    {
+                        self.model_choice = Some(model);
+                        gameboy.set_model(model);
+                    }
+                }
+            });
+
+            ui.menu_button("Turbo buttons", |ui| {
+                self.show_turbo_key_bindings(ui);
+                ui.separator();
+                for frames in TURBO_FRAMES {
+                    let rate = format!("{} presses per second", 30. / frames as f32);
+                    ui.selectable_value(&mut gameboy.turbo_frames, frames, rate);
+                }
+            });
         });
     }
 
+    /// Keys of the turbo buttons: clicking one waits for the next key press and binds it.
+    fn show_turbo_key_bindings(&mut self, ui: &mut egui::Ui) {
+        let pressed_key = ui.input().events.iter().find_map(|event| match event {
+            egui::Event::Key {
+                key, pressed: true, ..
+            } => Some(*key),
+            _ => None,
+        });
+
+        for (index, (key, button)) in self.turbo_key_bindings.iter_mut().enumerate() {
+            if self.turbo_rebinding == Some(index) {
+                // Escape keeps the current key
+                if let Some(pressed_key) = pressed_key {
+                    if pressed_key != egui::Key::Escape {
+                        *key = pressed_key;
+                    }
+                    self.turbo_rebinding = None;
+                }
+            }
+
+            let rebinding = self.turbo_rebinding == Some(index);
+            let text = if rebinding {
+                format!("Turbo {:?}: press a key", button)
+            } else {
+                format!("Turbo {:?}: {}", button, key.symbol_or_name())
+            };
+            if ui.selectable_label(rebinding, text).clicked() {
+                self.turbo_rebinding = Some(index);
+            }
+        }
+    }
+
     fn show_view_button(
         &mut self,
         ui: &mut egui::Ui,
         resize_requested: &mut bool,
         gameboy_screen: &mut widgets::gameboy_screen::GameboyScreen,
         debug_widget: &mut widgets::debug_widget::DebugWidget,
+        gameboy: &mut gameboy::GameBoy,
     ) {
         ui.menu_button("View", |ui| {
             if ui.button("Show Menu bar (Alt)").clicked() {
@@ -145,6 +292,12 @@ impl MenuBar {
                 *resize_requested = true;
             }
 
+            ui.menu_button("Overlays", |ui| {
+                ui.checkbox(&mut gameboy_screen.show_input, "Input display");
+                ui.checkbox(&mut gameboy_screen.show_frame_counter, "Frame counter");
+                ui.checkbox(&mut gameboy_screen.show_lag_counter, "Lag counter");
+            });
+
             ui.menu_button("Debug widgets", |ui| {
                 if ui.button("Show CPU registers").clicked() {
                     debug_widget.register_window.update_visibility()
@@ -173,7 +326,168 @@ impl MenuBar {
                 if ui.button("Show Tile data").clicked() {
                     debug_widget.tile_data_widget.update_visibility()
                 }
+
+                if ui.button("Show TAS editor").clicked() {
+                    debug_widget.tas_editor_window.update_visibility()
+                }
             });
+
+            self.show_palette_menu(ui, gameboy);
+            ui.checkbox(&mut gameboy.color_correction, "CGB color correction");
+        });
+    }
+
+    fn show_palette_menu(&mut self, ui: &mut egui::Ui, gameboy: &mut gameboy::GameBoy) {
+        ui.menu_button("Palette", |ui| {
+            for preset in Preset::ALL {
+                if ui
+                    .selectable_label(
+                        self.palette_choice == PaletteChoice::Preset(preset),
+                        preset.name(),
+                    )
+                    .clicked()
+                {
+                    self.palette_choice = PaletteChoice::Preset(preset);
+                    gameboy.palette = preset.palette();
+                }
+            }
+
+            ui.menu_button("Game Boy Color", |ui| {
+                if ui
+                    .selectable_label(
+                        self.palette_choice == PaletteChoice::Colorization(None),
+                        "Automatic",
+                    )
+                    .clicked()
+                {
+                    self.palette_choice = PaletteChoice::Colorization(None);
+                    gameboy.palette = colorization::automatic_palette(&gameboy.mmu);
+                }
+
+                for (index, manual) in colorization::MANUAL_PALETTES.iter().enumerate() {
+                    if ui
+                        .selectable_label(
+                            self.palette_choice == PaletteChoice::Colorization(Some(index)),
+                            manual.buttons,
+                        )
+                        .clicked()
+                    {
+                        self.palette_choice = PaletteChoice::Colorization(Some(index));
+                        gameboy.palette = manual.palette;
+                    }
+                }
+            });
+
+            ui.separator();
+
+            if ui
+                .selectable_label(self.palette_choice == PaletteChoice::Custom, "Custom")
+                .clicked()
+            {
+                self.palette_choice = PaletteChoice::Custom;
+                gameboy.palette = self.custom_palette;
+            }
+
+            let mut changed = false;
+            ui.add_enabled_ui(self.palette_choice == PaletteChoice::Custom, |ui| {
+                egui::Grid::new("custom_palette").show(ui, |ui| {
+                    let custom_palette = &mut self.custom_palette;
+                    for (name, colors) in [
+                        ("BG", &mut custom_palette.bg),
+                        ("OBP0", &mut custom_palette.obp0),
+                        ("OBP1", &mut custom_palette.obp1),
+                    ] {
+                        ui.label(name);
+                        for color in colors.iter_mut() {
+                            let mut rgb = [color[0], color[1], color[2]];
+                            if ui.color_edit_button_srgb(&mut rgb).changed() {
+                                *color = [rgb[0], rgb[1], rgb[2], 0xFF];
+                                changed = true;
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+
+            if changed {
+                gameboy.palette = self.custom_palette;
+            }
+        });
+    }
+
+    fn show_debug_button(&mut self, ui: &mut egui::Ui, gameboy: &mut gameboy::GameBoy) {
+        ui.menu_button("Debug", |ui| {
+            let mut access_restrictions = gameboy.mmu.is_access_restricted();
+            if ui
+                .checkbox(&mut access_restrictions, "VRAM/OAM lockout")
+                .changed()
+            {
+                gameboy.mmu.set_access_restrictions(access_restrictions);
+            }
+            ui.separator();
+
+            if gameboy.is_tracing() {
+                if ui.button("Stop trace").clicked() {
+                    gameboy.stop_trace();
+                    ui.close_menu()
+                }
+                return;
+            }
+
+            ui.radio_value(
+                &mut self.trace_start,
+                TraceStartChoice::Immediately,
+                "Start immediately",
+            );
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.trace_start, TraceStartChoice::AtPc, "Start at PC");
+                ui.add_enabled(
+                    self.trace_start == TraceStartChoice::AtPc,
+                    egui::DragValue::new(&mut self.trace_start_pc)
+                        .prefix("0x")
+                        .hexadecimal(4, false, true),
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.radio_value(
+                    &mut self.trace_start,
+                    TraceStartChoice::AfterCycle,
+                    "Start after CPU cycle",
+                )
+                .on_hover_text("Counted since the rom was loaded or the gameboy was reset");
+                ui.add_enabled(
+                    self.trace_start == TraceStartChoice::AfterCycle,
+                    egui::DragValue::new(&mut self.trace_start_cycle).speed(1000.),
+                );
+            });
+
+            if ui.button("Start trace").clicked() {
+                let path = native_dialog::FileDialog::new()
+                    .add_filter("Trace files", &["log", "txt"])
+                    .show_save_single_file()
+                    .unwrap();
+
+                let start = match self.trace_start {
+                    TraceStartChoice::Immediately => gameboy::cpu::trace::TraceStart::Immediately,
+                    TraceStartChoice::AtPc => {
+                        gameboy::cpu::trace::TraceStart::AtPc(self.trace_start_pc)
+                    }
+                    TraceStartChoice::AfterCycle => {
+                        gameboy::cpu::trace::TraceStart::AfterCycle(self.trace_start_cycle)
+                    }
+                };
+
+                match path {
+                    Some(path) => {
+                        if let Err(e) = gameboy.start_trace(path, start) {
+                            println!("Error: {}", e);
+                        }
+                    }
+                    None => println!("Invalid path"),
+                };
+                ui.close_menu()
+            }
         });
     }
 
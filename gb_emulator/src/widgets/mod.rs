@@ -2,3 +2,5 @@ pub mod debug;
 pub mod debug_widget;
 pub mod gameboy_screen;
 pub mod menu_bar;
+pub mod movies;
+pub mod save_slots;
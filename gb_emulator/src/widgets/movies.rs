@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+
+use eframe::egui;
+use gameboy::movie::{Movie, MovieError, MovieMode};
+
+/// Records the joypads into movie files and plays them back.
+#[derive(Default)]
+pub struct Movies {
+    rom_path: Option<PathBuf>,
+    /// The running movie was recorded here, it is saved when stopped
+    recording: bool,
+    /// Desync of the running movie already reported
+    reported_desync: Option<usize>,
+}
+
+impl Movies {
+    pub fn set_rom(&mut self, rom_path: PathBuf) {
+        self.rom_path = Some(rom_path);
+        self.recording = false;
+    }
+
+    /// Shows the "Movie" menu, with the state of the running movie.
+    pub fn show_menu(
+        &mut self,
+        ui: &mut egui::Ui,
+        gameboy: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+    ) {
+        self.report_desync(gameboy);
+
+        ui.add_enabled_ui(self.rom_path.is_some(), |ui| {
+            ui.menu_button("Movie", |ui| {
+                if let Some(session) = gameboy.get_movie() {
+                    let frames = session.movie.frames.len();
+                    let current = gameboy.get_movie_frame().unwrap_or(0);
+                    ui.label(match session.get_mode() {
+                        MovieMode::Recording => format!("Recording: frame {}", current),
+                        MovieMode::Playing => format!("Playing: frame {} / {}", current, frames),
+                        MovieMode::Finished => format!("Finished: {} frames", frames),
+                    });
+                    if let Some(desync) = session.get_desync() {
+                        ui.colored_label(
+                            egui::Color32::RED,
+                            format!("Desynced at frame {}", desync),
+                        );
+                    }
+
+                    if ui.button("Stop").clicked() {
+                        self.stop(gameboy);
+                        ui.close_menu();
+                    }
+                    return;
+                }
+
+                if ui.button("Record from power on").clicked() {
+                    gameboy.record_movie(true, frame);
+                    self.recording = true;
+                    ui.close_menu();
+                }
+                if ui.button("Record from here").clicked() {
+                    gameboy.record_movie(false, frame);
+                    self.recording = true;
+                    ui.close_menu();
+                }
+                if ui.button("Play").clicked() {
+                    self.play(gameboy, frame);
+                    ui.close_menu();
+                }
+            });
+        });
+    }
+
+    /// Prints the first desync of the running movie, once.
+    fn report_desync(&mut self, gameboy: &gameboy::GameBoy) {
+        let desync = gameboy.get_movie().and_then(|session| session.get_desync());
+        if let Some(frame) = desync {
+            if self.reported_desync != desync {
+                println!("Error: the movie desynced at frame {}", frame);
+            }
+        }
+        self.reported_desync = desync;
+    }
+
+    fn play(&mut self, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        let path = native_dialog::FileDialog::new()
+            .set_location(&self.movie_dir())
+            .add_filter("Movie files", &["gbm"])
+            .show_open_single_file()
+            .unwrap();
+
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let movie = match std::fs::read(&path)
+            .map_err(MovieError::from)
+            .and_then(|data| Movie::from_bytes(&data))
+        {
+            Ok(movie) => movie,
+            Err(e) => {
+                println!("Error: {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        if let Err(e) = gameboy.play_movie(movie, frame) {
+            println!("Error: {}: {}", path.display(), e);
+        }
+        self.recording = false;
+    }
+
+    /// Stops the movie, and saves it if it was recorded.
+    fn stop(&mut self, gameboy: &mut gameboy::GameBoy) {
+        let movie = match gameboy.stop_movie() {
+            Some(movie) => movie,
+            None => return,
+        };
+        if !std::mem::take(&mut self.recording) {
+            return;
+        }
+
+        let filename = self
+            .rom_path
+            .as_ref()
+            .and_then(|path| path.file_stem())
+            .map(|stem| format!("{}.gbm", stem.to_string_lossy()))
+            .unwrap_or_default();
+        let path = native_dialog::FileDialog::new()
+            .set_location(&self.movie_dir())
+            .set_filename(&filename)
+            .add_filter("Movie files", &["gbm"])
+            .show_save_single_file()
+            .unwrap();
+
+        match path {
+            Some(path) => {
+                if let Err(e) = std::fs::write(&path, movie.to_bytes()) {
+                    println!("Error: {}: {}", path.display(), e);
+                }
+            }
+            None => println!("Invalid path"),
+        }
+    }
+
+    /// Movies are stored next to the rom.
+    fn movie_dir(&self) -> PathBuf {
+        self.rom_path
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::PathBuf;
+
+use eframe::egui;
+use gameboy::state::StateReader;
+
+use crate::widgets::gameboy_screen::{HEIGHT, WIDTH};
+
+pub const SLOTS: usize = 8;
+const THUMBNAIL_SIZE: [f32; 2] = [WIDTH as f32 / 2., HEIGHT as f32 / 2.];
+
+/// Hotkeys of the slots: F1-F8 load a state, Shift + F1-F8 save it.
+const SLOT_KEYS: [egui::Key; SLOTS] = [
+    egui::Key::F1,
+    egui::Key::F2,
+    egui::Key::F3,
+    egui::Key::F4,
+    egui::Key::F5,
+    egui::Key::F6,
+    egui::Key::F7,
+    egui::Key::F8,
+];
+
+/// Numbered save states of the loaded rom, stored next to it as `<rom>.ss1` to `<rom>.ss8`.
+pub struct SaveSlots {
+    rom_path: Option<PathBuf>,
+    /// Thumbnail of each slot, None until it has been read from disk
+    thumbnails: Vec<Option<Option<egui::TextureHandle>>>,
+}
+
+impl Default for SaveSlots {
+    fn default() -> Self {
+        Self {
+            rom_path: None,
+            thumbnails: vec![None; SLOTS],
+        }
+    }
+}
+
+impl SaveSlots {
+    /// Switches to the slots of another rom.
+    pub fn set_rom(&mut self, rom_path: PathBuf) {
+        self.rom_path = Some(rom_path);
+        self.thumbnails = vec![None; SLOTS];
+    }
+
+    fn slot_path(&self, slot: usize) -> Option<PathBuf> {
+        let rom_path = self.rom_path.as_ref()?;
+        Some(rom_path.with_extension(format!("ss{}", slot + 1)))
+    }
+
+    pub fn save(&mut self, slot: usize, gameboy: &gameboy::GameBoy, frame: &[u8]) {
+        let path = match self.slot_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+
+        match gameboy.save_state_file(&path, frame) {
+            Ok(()) => self.thumbnails[slot] = None,
+            Err(e) => println!("Error: {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn load(&mut self, slot: usize, gameboy: &mut gameboy::GameBoy, frame: &mut [u8]) {
+        let path = match self.slot_path(slot) {
+            Some(path) => path,
+            None => return,
+        };
+
+        if let Err(e) = gameboy.load_state_file(&path, frame) {
+            println!("Error: {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn handle_hotkeys(
+        &mut self,
+        ctx: &egui::Context,
+        gameboy: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+    ) {
+        for (slot, key) in SLOT_KEYS.into_iter().enumerate() {
+            if !ctx.input().key_pressed(key) {
+                continue;
+            }
+
+            if ctx.input().modifiers.shift {
+                self.save(slot, gameboy, frame);
+            } else {
+                self.load(slot, gameboy, frame);
+            }
+        }
+    }
+
+    /// Shows the "Save state" and "Load state" menus, with the thumbnail of each slot.
+    pub fn show_menus(
+        &mut self,
+        ui: &mut egui::Ui,
+        gameboy: &mut gameboy::GameBoy,
+        frame: &mut [u8],
+    ) {
+        ui.add_enabled_ui(self.rom_path.is_some(), |ui| {
+            ui.menu_button("Save state", |ui| {
+                for slot in 0..SLOTS {
+                    if self.show_slot(ui, slot, "Shift+") {
+                        self.save(slot, gameboy, frame);
+                        ui.close_menu();
+                    }
+                }
+            });
+
+            ui.menu_button("Load state", |ui| {
+                for slot in 0..SLOTS {
+                    if self.show_slot(ui, slot, "") {
+                        self.load(slot, gameboy, frame);
+                        ui.close_menu();
+                    }
+                }
+            });
+        });
+    }
+
+    /// Shows a slot with its thumbnail. Returns true if it was clicked.
+    fn show_slot(&mut self, ui: &mut egui::Ui, slot: usize, modifier: &str) -> bool {
+        let label = format!("Slot {} ({}F{})", slot + 1, modifier, slot + 1);
+
+        match self.thumbnail(ui.ctx(), slot) {
+            Some(thumbnail) => ui
+                .add(egui::ImageButton::new(thumbnail, THUMBNAIL_SIZE))
+                .on_hover_text(label)
+                .clicked(),
+            None => ui.button(format!("{} - Empty", label)).clicked(),
+        }
+    }
+
+    fn thumbnail(&mut self, ctx: &egui::Context, slot: usize) -> Option<&egui::TextureHandle> {
+        if self.thumbnails[slot].is_none() {
+            let texture = self
+                .slot_path(slot)
+                .and_then(|path| std::fs::read(path).ok())
+                .and_then(|data| {
+                    let thumbnail = StateReader::thumbnail(&data).ok()?;
+                    if thumbnail.len() != WIDTH * HEIGHT * 4 {
+                        return None;
+                    }
+                    let image =
+                        egui::ColorImage::from_rgba_unmultiplied([WIDTH, HEIGHT], thumbnail);
+                    Some(ctx.load_texture(
+                        format!("save_slot_{}", slot),
+                        image,
+                        egui::TextureOptions::NEAREST,
+                    ))
+                });
+            self.thumbnails[slot] = Some(texture);
+        }
+
+        self.thumbnails[slot].as_ref()?.as_ref()
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use gameboy::cpu::{disassembler, trace};
+use gameboy::{GameBoy, Model};
+
+const FRAME_SIZE: usize = 160 * 144 * 4;
+const DEFAULT_CONTEXT: usize = 8;
+const USAGE: &str = "Usage: trace_diff <rom> <reference trace> [--context N] [--no-ly-stub]
+
+Runs <rom> from 0x0100 and compares every instruction with the gameboy-doctor
+formatted <reference trace>. Stops at the first line that differs.
+
+Options:
+    --context N     Number of instructions shown before the divergence (default 8)
+    --no-ly-stub    Do not force LY to read 0x90 (gameboy-doctor logs expect it)";
+
+struct Args {
+    rom: PathBuf,
+    reference: PathBuf,
+    context: usize,
+    ly_stub: bool,
+}
+
+/// An instruction already executed, kept to show what led to the divergence.
+struct Step {
+    line_number: usize,
+    line: String,
+    disassembly: String,
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let reference = match File::open(&args.reference) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Error: {}: {}", args.reference.display(), e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut gameboy = match GameBoy::without_bios(args.rom.clone(), Model::Dmg) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Error: {}: {}", args.rom.display(), e);
+            std::process::exit(2);
+        }
+    };
+    if args.ly_stub {
+        gameboy.mmu.set_ly_override(Some(0x90));
+    }
+
+    let mut frame = vec![0; FRAME_SIZE];
+    let mut history: VecDeque<Step> = VecDeque::with_capacity(args.context + 1);
+
+    for (index, expected) in reference.lines().enumerate() {
+        let line_number = index + 1;
+        let expected = match expected {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Error: {}: {}", args.reference.display(), e);
+                std::process::exit(2);
+            }
+        };
+
+        let actual = trace::format_line(&gameboy.cpu, &gameboy.mmu);
+        if actual != expected.trim() {
+            print_divergence(&gameboy, &history, line_number, &expected, &actual);
+            std::process::exit(1);
+        }
+
+        history.push_back(Step {
+            line_number,
+            line: actual,
+            disassembly: disassembler::disassemble(&gameboy.mmu, gameboy.cpu.pc).0,
+        });
+        if history.len() > args.context {
+            history.pop_front();
+        }
+
+        gameboy.step_instruction(&mut frame);
+    }
+
+    println!("No divergence found");
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut positional = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+    let mut ly_stub = true;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => {
+                let value = args.next().ok_or("--context expects a value")?;
+                context = value
+                    .parse()
+                    .map_err(|_| format!("invalid context: {}", value))?;
+            }
+            "--no-ly-stub" => ly_stub = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => positional.push(PathBuf::from(arg)),
+        }
+    }
+
+    match <[PathBuf; 2]>::try_from(positional) {
+        Ok([rom, reference]) => Ok(Args {
+            rom,
+            reference,
+            context,
+            ly_stub,
+        }),
+        Err(_) => Err("expected a rom and a reference trace".to_string()),
+    }
+}
+
+fn print_divergence(
+    gameboy: &GameBoy,
+    history: &VecDeque<Step>,
+    line_number: usize,
+    expected: &str,
+    actual: &str,
+) {
+    println!("Divergence at line {}", line_number);
+    println!();
+
+    for step in history {
+        println!(
+            "{:>8}  {:<20} {}",
+            step.line_number, step.disassembly, step.line
+        );
+    }
+
+    let (disassembly, _) = disassembler::disassemble(&gameboy.mmu, gameboy.cpu.pc);
+    println!("{:>8}  {:<20} {}", line_number, disassembly, actual);
+    println!();
+
+    println!("Expected: {}", expected);
+    println!("Actual:   {}", actual);
+    println!();
+
+    let expected_fields = parse_fields(expected);
+    for (name, value) in parse_fields(actual) {
+        match expected_fields.iter().find(|(n, _)| *n == name) {
+            Some((_, expected_value)) if *expected_value != value => {
+                println!("{:<6} expected {:<12} got {}", name, expected_value, value)
+            }
+            None => println!("{:<6} missing in reference", name),
+            _ => (),
+        }
+    }
+}
+
+/// Splits a trace line into its `NAME:VALUE` fields.
+fn parse_fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .collect()
+}